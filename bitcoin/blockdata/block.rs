@@ -20,12 +20,20 @@
 //! these blocks and the blockchain.
 //!
 
-use std::io::IoResult;
+use std::io::{IoResult, IoError, InvalidInput, OtherIoError};
 
-use util::hash::Sha256dHash;
+use util::hash::{Sha256dHash, merkle_root};
 use util::uint256::Uint256;
-use network::serialize::{Serializable, SerializeIter, VarInt};
-use blockdata::transaction::Transaction;
+use network::serialize::{Serializable, SerializeIter, VarInt, deserialize_bounded_vec};
+use blockdata::constants::{MAX_BLOCK_WEIGHT, MAX_BLOCK_SIGOPS, MAX_BLOCK_SIZE};
+#[cfg(test)]
+use blockdata::constants::{genesis_block, genesis_tx, regtest_genesis_block, MAX_TX_WEIGHT};
+#[cfg(test)]
+use blockdata::opcodes;
+#[cfg(test)]
+use blockdata::script::Script;
+use blockdata::transaction::{Transaction, ValidationFlags, MIN_TX_SIZE};
+use util::misc::prepend_err;
 #[cfg(test)]
 use serialize::hex::FromHex;
 
@@ -114,15 +122,219 @@ impl BlockHeader {
     ret.increment();
     ret
   }
+
+  /// Returns the header's 80-byte wire encoding
+  pub fn to_bytes(&self) -> [u8, ..80] {
+    let mut ret = [0u8, ..80];
+    ret.copy_from(self.serialize().as_slice());
+    ret
+  }
+
+  /// Decodes a header from its 80-byte wire encoding
+  pub fn from_bytes(data: &[u8]) -> IoResult<BlockHeader> {
+    if data.len() != 80 {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "wrong header length",
+        detail: Some(format!("headers are 80 bytes, not {:u}", data.len()))
+      });
+    }
+    Serializable::deserialize(data.iter().map(|n| *n))
+  }
+
+  /// Searches for a nonce which meets the header's own target, trying at
+  /// most `max_iterations` nonces upward from the current one. On success
+  /// the header is left with the nonce, which is also returned. Only
+  /// sensible for easy targets, such as regtest's.
+  pub fn solve_nonce(&mut self, max_iterations: u32) -> IoResult<u32> {
+    let target = self.target();
+    for _ in range(0, max_iterations) {
+      if self.spv_validate(&target) {
+        return Ok(self.nonce);
+      }
+      if self.nonce == 0xFFFFFFFF {
+        break;
+      }
+      self.nonce += 1;
+    }
+    Err(nonces_exhausted(max_iterations))
+  }
+
+  /// As `solve_nonce`, but when every nonce has been tried, moves the
+  /// time on by a second and carries on from nonce zero
+  pub fn solve_nonce_with_timestamp(&mut self, max_iterations: u32) -> IoResult<u32> {
+    let target = self.target();
+    for _ in range(0, max_iterations) {
+      if self.spv_validate(&target) {
+        return Ok(self.nonce);
+      }
+      if self.nonce == 0xFFFFFFFF {
+        self.nonce = 0;
+        self.time += 1;
+      } else {
+        self.nonce += 1;
+      }
+    }
+    Err(nonces_exhausted(max_iterations))
+  }
+}
+
+/// Builds the error returned when a nonce search gives up
+fn nonces_exhausted(iterations: u32) -> IoError {
+  IoError {
+    kind: OtherIoError,
+    desc: "nonces exhausted",
+    detail: Some(format!("no header met the target after {:u} tries", iterations))
+  }
+}
+
+/// Builds the error returned for a block which fails validation
+fn validation_error(desc: &'static str, detail: String) -> IoError {
+  IoError {
+    kind: OtherIoError,
+    desc: desc,
+    detail: Some(detail)
+  }
+}
+
+impl Block {
+  /// Counts the signature operations in the block's scripts, using the
+  /// inaccurate legacy count which MAX_BLOCK_SIGOPS is defined in terms of
+  pub fn sigop_count(&self) -> uint {
+    let mut count = 0;
+    for tx in self.txdata.iter() {
+      for txin in tx.input.iter() {
+        count += txin.script_sig.sigop_count(false);
+      }
+      for txout in tx.output.iter() {
+        count += txout.script_pubkey.sigop_count(false);
+      }
+    }
+    count
+  }
+
+  /// Checks that the first transaction, and only the first, is a coinbase,
+  /// and that its scriptSig is between 2 and 100 bytes long
+  pub fn validate_coinbase_position(&self) -> IoResult<()> {
+    let hash = self.header.hash();
+    let coinbase = match self.txdata.as_slice().head() {
+      Some(tx) => tx,
+      None => { return Err(validation_error("block has no coinbase", format!("{:x}", hash))); }
+    };
+    if coinbase.input.len() != 1 {
+      return Err(validation_error("coinbase does not have exactly one input",
+                                  format!("{:x} has a coinbase with {:} inputs", hash, coinbase.input.len())));
+    }
+    if !coinbase.is_coinbase() {
+      return Err(validation_error("coinbase outpoint is not null",
+                                  format!("{:x} has a first transaction which is not a coinbase", hash)));
+    }
+    let script_len = coinbase.input.get(0).script_sig.as_slice().len();
+    if script_len < 2 || script_len > 100 {
+      return Err(validation_error("coinbase scriptSig has bad length",
+                                  format!("{:x} has a {:}-byte coinbase scriptSig", hash, script_len)));
+    }
+    for (n, tx) in self.txdata.iter().enumerate().skip(1) {
+      if tx.is_coinbase() {
+        return Err(validation_error("more than one coinbase",
+                                    format!("{:x} has a coinbase at position {:}", hash, n)));
+      }
+    }
+    Ok(())
+  }
+
+  /// Performs the consensus checks on a block which need neither the UTXO
+  /// set nor script execution: proof-of-work against `required_target`,
+  /// the merkle root, the weight and sigop limits, the position of the
+  /// coinbase, and the context-free checks on each transaction. Every
+  /// failure is returned, not just the first, so an empty vector means the
+  /// block passed.
+  pub fn validate(&self, required_target: &Uint256, height: u32) -> Vec<IoError> {
+    let mut errors = vec![];
+    let hash = self.header.hash();
+
+    if !self.header.spv_validate(required_target) {
+      errors.push(validation_error("bad proof of work", format!("{:x}", hash)));
+    }
+    if self.txdata.is_empty() {
+      errors.push(validation_error("block has no transactions", format!("{:x}", hash)));
+    } else {
+      match self.validate_coinbase_position() {
+        Err(e) => { errors.push(e); }
+        Ok(()) => {}
+      }
+    }
+    if merkle_root(self.txdata.as_slice()) != self.header.merkle_root {
+      errors.push(validation_error("merkle root does not match transactions", format!("{:x}", hash)));
+    }
+    // Without witness data, weight is four times the serialized size
+    let weight = 4 * self.serialized_len();
+    if weight > MAX_BLOCK_WEIGHT {
+      errors.push(validation_error("block too heavy",
+                                   format!("{:x} has weight {:}, more than {:}", hash, weight, MAX_BLOCK_WEIGHT)));
+    }
+    let sigops = self.sigop_count();
+    if sigops > MAX_BLOCK_SIGOPS {
+      errors.push(validation_error("too many sigops",
+                                   format!("{:x} has {:} sigops, more than {:}", hash, sigops, MAX_BLOCK_SIGOPS)));
+    }
+
+    // Input values need the UTXO set, so they cannot be checked here. The
+    // per-transaction weight limit is relay policy, not consensus; a block
+    // transaction need only fit in the block, which is checked above.
+    let flags = ValidationFlags { input_values: false, weight: false, .. ValidationFlags::all() };
+    for tx in self.txdata.iter() {
+      match tx.validate([].as_slice(), height, self.header.time, &flags) {
+        Err(e) => { errors.push(e); }
+        Ok(()) => {}
+      }
+    }
+    errors
+  }
 }
 
 impl_serializable!(BlockHeader, version, prev_blockhash, merkle_root, time, bits, nonce)
-impl_serializable!(Block, header, txdata)
+
+// Written out rather than with impl_serializable! so that the transaction
+// count is checked before any transactions are read
+impl Serializable for Block {
+  fn serialize(&self) -> Vec<u8> {
+    let mut ret = self.header.serialize();
+    ret.extend(self.txdata.serialize().move_iter());
+    ret
+  }
+
+  fn serialized_len(&self) -> uint {
+    self.header.serialized_len() + self.txdata.serialized_len()
+  }
+
+  fn serialize_iter<'a>(&'a self) -> SerializeIter<'a> {
+    SerializeIter {
+      data_iter: None,
+      sub_iter_iter: box vec![ &self.header as &Serializable, &self.txdata as &Serializable ].move_iter(),
+      sub_iter: None,
+      sub_started: false
+    }
+  }
+
+  fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<Block> {
+    Ok(Block {
+      header: try!(prepend_err("header", Serializable::deserialize(iter.by_ref()))),
+      txdata: try!(prepend_err("txdata", deserialize_bounded_vec(iter.by_ref(),
+                                                                 (MAX_BLOCK_SIZE / MIN_TX_SIZE) as u64, "transactions")))
+    })
+  }
+}
+
 impl_serializable!(LoneBlockHeader, header, tx_count)
 
+// Block 170, which has the first bitcoin transaction
+#[cfg(test)]
+static SOME_BLOCK: &'static str = "010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b0201000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0804ffff001d026e04ffffffff0100f2052a0100000043410446ef0102d1ec5240f0d061a4246c1bdef63fc3dbab7733052fbbf0ecd8f41fc26bf049ebb4f9527f374280259e7cfa99c48b0e3f39c51347a19a5819651503a5ac00000000010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac00000000";
+
 #[test]
 fn block_test() {
-  let some_block = "010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b0201000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0804ffff001d026e04ffffffff0100f2052a0100000043410446ef0102d1ec5240f0d061a4246c1bdef63fc3dbab7733052fbbf0ecd8f41fc26bf049ebb4f9527f374280259e7cfa99c48b0e3f39c51347a19a5819651503a5ac00000000010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac00000000".from_hex().unwrap();
+  let some_block = SOME_BLOCK.from_hex().unwrap();
   let cutoff_block = "010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b0201000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0804ffff001d026e04ffffffff0100f2052a0100000043410446ef0102d1ec5240f0d061a4246c1bdef63fc3dbab7733052fbbf0ecd8f41fc26bf049ebb4f9527f374280259e7cfa99c48b0e3f39c51347a19a5819651503a5ac00000000010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac".from_hex().unwrap();
 
   let prevhash = "4ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000".from_hex().unwrap();
@@ -145,6 +357,152 @@ fn block_test() {
   
   let reserialize = real_decode.serialize();
   assert_eq!(reserialize.as_slice(), some_block.as_slice());
+  assert_eq!(real_decode.serialized_len(), some_block.len());
+  assert_eq!(real_decode.header.serialized_len(), 80);
+
+  // This is a real block, so it should validate
+  let target = real_decode.header.target();
+  assert_eq!(real_decode.validate(&target, 170).len(), 0);
+  // Changing the merkle root breaks both it and the proof of work
+  let mut bad_block = real_decode;
+  bad_block.header.merkle_root = Sha256dHash::from_data([].as_slice());
+  assert_eq!(bad_block.validate(&target, 170).len(), 2);
+  // Changing a transaction breaks only the merkle root
+  let redecode: IoResult<Block> = Serializable::deserialize(some_block.iter().map(|n| *n));
+  let mut bad_block = redecode.unwrap();
+  bad_block.txdata.get_mut(1).lock_time = 1;
+  assert_eq!(bad_block.validate(&target, 170).len(), 1);
+
+  // The block has two pay-to-pubkey outputs; replace one of them to land
+  // exactly on the sigop limit, then go one over
+  assert_eq!(bad_block.sigop_count(), 2);
+  let mut script = Script::new();
+  for _ in range(0, MAX_BLOCK_SIGOPS - 1) {
+    script.push_opcode(opcodes::CHECKSIG);
+  }
+  bad_block.txdata.get_mut(1).output.get_mut(0).script_pubkey = script.clone();
+  assert_eq!(bad_block.sigop_count(), MAX_BLOCK_SIGOPS);
+  assert!(!bad_block.validate(&target, 170).iter().any(|e| e.desc == "too many sigops"));
+  script.push_opcode(opcodes::CHECKSIGVERIFY);
+  bad_block.txdata.get_mut(1).output.get_mut(0).script_pubkey = script;
+  assert!(bad_block.validate(&target, 170).iter().any(|e| e.desc == "too many sigops"));
+
+  // A transaction over the relay weight limit is still fine in a block
+  let mut script = Script::new();
+  script.push_slice(Vec::from_elem(MAX_TX_WEIGHT / 4, 0u8).as_slice());
+  bad_block.txdata.get_mut(1).output.get_mut(0).script_pubkey = script;
+  assert!(4 * bad_block.txdata.get(1).serialize().len() > MAX_TX_WEIGHT);
+  assert!(!bad_block.validate(&target, 170).iter().any(|e| e.desc == "transaction too heavy"));
 }
 
+#[cfg(test)]
+fn decode_block(data: &[u8]) -> Block {
+  let decode: IoResult<Block> = Serializable::deserialize(data.iter().map(|n| *n));
+  decode.unwrap()
+}
+
+#[test]
+fn coinbase_position_test() {
+  let some_block = SOME_BLOCK.from_hex().unwrap();
+  assert!(decode_block(some_block.as_slice()).validate_coinbase_position().is_ok());
+
+  // A second coinbase
+  let mut block = decode_block(some_block.as_slice());
+  block.txdata.push(genesis_tx());
+  assert_eq!(block.validate_coinbase_position().unwrap_err().desc, "more than one coinbase");
+  // validate reports it, along with the now-wrong merkle root
+  let target = block.header.target();
+  assert!(block.validate(&target, 170).iter().any(|e| e.desc == "more than one coinbase"));
+
+  // A coinbase spending a real output
+  let mut block = decode_block(some_block.as_slice());
+  block.txdata.get_mut(0).input.get_mut(0).prev_index = 0;
+  assert_eq!(block.validate_coinbase_position().unwrap_err().desc, "coinbase outpoint is not null");
+  // No coinbase at all
+  let mut block = decode_block(some_block.as_slice());
+  block.txdata.remove(0);
+  assert_eq!(block.validate_coinbase_position().unwrap_err().desc, "coinbase does not have exactly one input");
+
+  // The coinbase scriptSig must be 2 to 100 bytes
+  let mut block = decode_block(some_block.as_slice());
+  block.txdata.get_mut(0).input.get_mut(0).script_sig = Script::from_vec(vec![0]);
+  assert_eq!(block.validate_coinbase_position().unwrap_err().desc, "coinbase scriptSig has bad length");
+  block.txdata.get_mut(0).input.get_mut(0).script_sig = Script::from_vec(vec![0, 0]);
+  assert!(block.validate_coinbase_position().is_ok());
+  block.txdata.get_mut(0).input.get_mut(0).script_sig = Script::from_vec(Vec::from_elem(100, 0u8));
+  assert!(block.validate_coinbase_position().is_ok());
+  block.txdata.get_mut(0).input.get_mut(0).script_sig = Script::from_vec(Vec::from_elem(101, 0u8));
+  assert_eq!(block.validate_coinbase_position().unwrap_err().desc, "coinbase scriptSig has bad length");
+}
+
+#[test]
+fn solve_nonce_test() {
+  // Most nonces meet the regtest target, including the first
+  let mut header = regtest_genesis_block().header;
+  header.nonce = 0;
+  assert_eq!(header.solve_nonce(10).unwrap(), 0);
+  assert_eq!(header.nonce, 0);
 
+  // The mainnet genesis target is not going to be met by chance
+  let mut header = genesis_block().header;
+  header.nonce = 0;
+  assert_eq!(header.solve_nonce(10).unwrap_err().desc, "nonces exhausted");
+  assert_eq!(header.nonce, 10);
+}
+
+#[test]
+fn solve_nonce_with_timestamp_test() {
+  // At this time the last nonce misses the target, but a second later
+  // nonce zero meets it
+  let mut header = regtest_genesis_block().header;
+  header.time = 1296688607;
+  header.nonce = 0xFFFFFFFF;
+  let mut without_timestamp = header;
+  assert_eq!(without_timestamp.solve_nonce(10).unwrap_err().desc, "nonces exhausted");
+  assert_eq!(header.solve_nonce_with_timestamp(10).unwrap(), 0);
+  assert_eq!(header.time, 1296688608);
+  let target = header.target();
+  assert!(header.spv_validate(&target));
+}
+
+#[test]
+fn header_bytes_test() {
+  let some_header = "010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b".from_hex().unwrap();
+  let header = BlockHeader::from_bytes(some_header.as_slice()).unwrap();
+  assert_eq!(header.nonce, 2067413810);
+  assert_eq!(header.to_bytes().as_slice(), some_header.as_slice());
+
+  for header in [genesis_block().header, regtest_genesis_block().header, header].iter() {
+    let bytes = header.to_bytes();
+    assert_eq!(bytes.as_slice(), header.serialize().as_slice());
+    let decoded = BlockHeader::from_bytes(bytes.as_slice()).unwrap();
+    assert!(decoded == *header);
+    assert_eq!(decoded.to_bytes().as_slice(), bytes.as_slice());
+  }
+
+  // Anything but exactly 80 bytes is rejected
+  assert_eq!(BlockHeader::from_bytes(some_header.slice_to(79)).err().unwrap().detail,
+             Some(String::from_str("headers are 80 bytes, not 79")));
+  let mut long = some_header.clone();
+  long.push(0);
+  assert_eq!(BlockHeader::from_bytes(long.as_slice()).err().unwrap().desc, "wrong header length");
+  assert!(BlockHeader::from_bytes(&[]).is_err());
+}
+
+#[test]
+fn block_tx_count_limit_test() {
+  // A transaction count no block could hold is rejected before any
+  // transaction is read
+  let mut raw = genesis_block().header.serialize();
+  raw.push_all([0xFEu8, 0xFF, 0xFF, 0xFF, 0xFF]);
+  let block: IoResult<Block> = Serializable::deserialize(raw.iter().map(|n| *n));
+  let err = block.err().unwrap();
+  assert_eq!(err.desc, "too many elements");
+  assert_eq!(err.detail, Some(String::from_str("txdata: 4294967295 transactions, more than the 16666 allowed")));
+
+  // Serialization is unchanged by writing the impl out
+  let block = genesis_block();
+  assert_eq!(block.serialize_iter().collect::<Vec<u8>>(), block.serialize());
+  let decoded: Block = Serializable::deserialize(block.serialize().move_iter()).unwrap();
+  assert_eq!(decoded.serialize(), block.serialize());
+}
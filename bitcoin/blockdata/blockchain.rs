@@ -20,6 +20,7 @@
 
 use alloc::rc::Rc;
 use std::cell::RefCell;
+use std::cmp::min;
 use std::io::{IoResult, IoError, OtherIoError};
 
 use blockdata::block::BlockHeader;
@@ -41,6 +42,8 @@ struct BlockchainNode {
   required_difficulty: Uint256,
   /// Height above genesis
   height: u32,
+  /// Hash of `header`, computed once when the node is created
+  block_hash: Sha256dHash,
   /// Pointer to block's parent
   prev: RefCell<Option<Rc<BlockchainNode>>>
 }
@@ -66,13 +69,15 @@ impl Serializable for Rc<BlockchainNode> {
     ret.extend(self.total_work.serialize().move_iter());
     ret.extend(self.required_difficulty.serialize().move_iter());
     ret.extend(self.height.serialize().move_iter());
-    // Don't serialize the prev pointer
+    // Don't serialize the cached hash or the prev pointer
     ret
   }
 
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<Rc<BlockchainNode>> {
+    let header: BlockHeader = try!(prepend_err("header", Serializable::deserialize(iter.by_ref())));
     Ok(Rc::new(BlockchainNode {
-      header: try!(prepend_err("header", Serializable::deserialize(iter.by_ref()))),
+      block_hash: header.hash(),
+      header: header,
       total_work: try!(prepend_err("total_work", Serializable::deserialize(iter.by_ref()))),
       required_difficulty: try!(prepend_err("req_difficulty", Serializable::deserialize(iter.by_ref()))),
       height: try!(prepend_err("height", Serializable::deserialize(iter.by_ref()))),
@@ -83,7 +88,7 @@ impl Serializable for Rc<BlockchainNode> {
   // Override Serialize::hash to return the blockheader hash, since the
   // hash of the node itself is pretty much meaningless.
   fn hash(&self) -> Sha256dHash {
-    self.header.hash()
+    self.block_hash.clone()
   }
 }
 
@@ -152,12 +157,20 @@ impl<'tree> Iterator<Sha256dHash> for LocatorHashIter<'tree> {
       None => None
     };
 
-    for _ in range(0, self.skip) {
-      self.index = match self.index {
-        Some(ref rc) => rc.prev(self.tree),
-        None => None
+    // Step back, stopping at genesis so that it is always the last hash
+    self.index = match self.index {
+      Some(ref rc) if rc.height > 0 => {
+        let mut scan = rc.clone();
+        for _ in range(0, self.skip) {
+          scan = match scan.prev(self.tree) {
+            Some(prev) => prev,
+            None => break
+          };
+        }
+        Some(scan)
       }
-    }
+      _ => None
+    };
 
     self.count += 1;
     if self.count > 10 {
@@ -167,6 +180,28 @@ impl<'tree> Iterator<Sha256dHash> for LocatorHashIter<'tree> {
   }
 }
 
+/// An iterator over a block header and its ancestors, back to genesis
+pub struct AncestorIter<'tree> {
+  index: Option<&'tree Rc<BlockchainNode>>,
+  tree: &'tree PatriciaTree<Rc<BlockchainNode>>
+}
+
+impl<'tree> Iterator<&'tree BlockHeader> for AncestorIter<'tree> {
+  fn next(&mut self) -> Option<&'tree BlockHeader> {
+    match self.index {
+      Some(node) => {
+        self.index = if node.height > 0 {
+          self.tree.lookup(&node.header.prev_blockhash.as_bitv())
+        } else {
+          None
+        };
+        Some(&node.header)
+      }
+      None => None
+    }
+  }
+}
+
 /// This function emulates the GetCompact(SetCompact(n)) in the satoshi code,
 /// which drops the precision to something that can be encoded precisely in
 /// the nBits block header field. Savour the perversity. This is in Bitcoin
@@ -182,21 +217,137 @@ fn satoshi_the_precision(n: &Uint256) -> Uint256 {
   ret.shl(bits)
 }
 
+/// Computes the target of the first block of a difficulty period, from the
+/// last block of the period before and the time of that period's first
+/// block. The target may not go above `max`.
+fn retarget(last: &BlockHeader, first_time: u32, max: Uint256) -> Uint256 {
+  // Get clamped timespan between first and last blocks
+  let timespan = match last.time - first_time {
+    n if n < DIFFCHANGE_TIMESPAN / 4 => DIFFCHANGE_TIMESPAN / 4,
+    n if n > DIFFCHANGE_TIMESPAN * 4 => DIFFCHANGE_TIMESPAN * 4,
+    n => n
+  };
+  // Compute new target
+  let mut target = last.target();
+  target = target.mul_u32(timespan);
+  target = target.div(&Uint256::from_u64(DIFFCHANGE_TIMESPAN as u64));
+  // Clamp below the maximum (difficulty 1 on the real network)
+  if target > max { target = max };
+  // Compactify (make expressible in the 8+24 nBits float format
+  satoshi_the_precision(&target)
+}
+
+/// Builds the error returned for a header which fails validation
+fn header_error(desc: &'static str, detail: String) -> IoError {
+  IoError {
+    kind: OtherIoError,
+    desc: desc,
+    detail: Some(detail)
+  }
+}
+
+/// Returns the header at `height` from a run of headers starting at
+/// `start_height`, or `prev` if it is the one just before the run
+fn run_header<'a>(headers: &'a [BlockHeader], start_height: u32, prev: &'a BlockHeader,
+                  height: u32) -> Option<&'a BlockHeader> {
+  if height + 1 == start_height {
+    Some(prev)
+  } else if height >= start_height && ((height - start_height) as uint) < headers.len() {
+    Some(&headers[(height - start_height) as uint])
+  } else {
+    None
+  }
+}
+
+/// Checks a run of headers without the rest of the chain, so that blocks
+/// can be fetched while the headers before them are still being checked.
+/// The run follows `prev_header` and starts at `start_height`. Each header
+/// must link to the one before, carry the difficulty the retargeting rules
+/// give (with targets capped at `max_target`), meet it, and be later than
+/// the median time of the eleven headers before it, as far as the run
+/// goes back. A retarget can only be checked if the first header of the
+/// period before it is in the run or is `prev_header`, so runs should
+/// start at a period boundary. Every failure is returned with the index
+/// of its header. The genesis header has no parent, so a run may not
+/// start at height 0.
+pub fn validate_header_chain(headers: &[BlockHeader], start_height: u32, prev_header: &BlockHeader,
+                             max_target: &Uint256) -> Result<(), Vec<(uint, IoError)>> {
+  if start_height == 0 {
+    return Err(vec![(0, header_error("run starts at genesis",
+                                     String::from_str("the genesis header has no parent to check it against")))]);
+  }
+  let mut errors = vec![];
+  for (index, header) in headers.iter().enumerate() {
+    let height = start_height + index as u32;
+    let parent = if index == 0 { prev_header } else { &headers[index - 1] };
+    let hash = header.hash();
+
+    if header.prev_blockhash != parent.hash() {
+      errors.push((index, header_error("header does not connect",
+                                       format!("{:x} does not follow {:x}", hash, parent.hash()))));
+    }
+
+    let required = if height % DIFFCHANGE_INTERVAL == 0 {
+      match run_header(headers, start_height, prev_header, height - DIFFCHANGE_INTERVAL) {
+        Some(first) => Some(retarget(parent, first.time, *max_target)),
+        None => None
+      }
+    } else {
+      Some(parent.target())
+    };
+    match required {
+      None => {
+        errors.push((index, header_error("retarget not checkable",
+                                         format!("{:x} retargets, but the start of its period is not in the run", hash))));
+      }
+      Some(ref target) if header.target() != *target => {
+        errors.push((index, header_error("wrong difficulty bits",
+                                         format!("{:x} has bits {:x}", hash, header.bits))));
+      }
+      Some(ref target) if !header.spv_validate(target) => {
+        errors.push((index, header_error("bad proof of work", format!("{:x}", hash))));
+      }
+      _ => {}
+    }
+
+    // Median time of the previous eleven headers, or as many as we have
+    let mut times = vec![];
+    for back in range(1u32, 12) {
+      if back > height { break; }
+      match run_header(headers, start_height, prev_header, height - back) {
+        Some(earlier) => { times.push(earlier.time); }
+        None => { break; }
+      }
+    }
+    if !times.is_empty() {
+      times.sort();
+      let median = *times.get(times.len() / 2);
+      if header.time <= median {
+        errors.push((index, header_error("time too early",
+                                         format!("{:x} has time {:u}, not after the median {:u}", hash, header.time, median))));
+      }
+    }
+  }
+
+  if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
 impl Blockchain {
   /// Constructs a new blockchain
   pub fn new(genesis: BlockHeader) -> Blockchain {
-    let genhash = genesis.hash().as_bitv();
+    let genhash = genesis.hash();
     let rc_gen = Rc::new(BlockchainNode {
       header: genesis,
       total_work: Uint256::from_u64(0),
       required_difficulty: genesis.target(),
       height: 0,
+      block_hash: genhash.clone(),
       prev: RefCell::new(None)
     });
     Blockchain {
       tree: {
         let mut pat = PatriciaTree::new();
-        pat.insert(&genhash, rc_gen.clone());
+        pat.insert(&genhash.as_bitv(), rc_gen.clone());
         pat
       },
       best_hash: rc_gen.hash(),
@@ -217,21 +368,7 @@ impl Blockchain {
             for _ in range(0, DIFFCHANGE_INTERVAL - 1) {
               scan = scan.prev(&self.tree).unwrap();
             }
-            // Get clamped timespan between first and last blocks
-            let timespan = match prev.header.time - scan.header.time {
-              n if n < DIFFCHANGE_TIMESPAN / 4 => DIFFCHANGE_TIMESPAN / 4,
-              n if n > DIFFCHANGE_TIMESPAN * 4 => DIFFCHANGE_TIMESPAN * 4,
-              n => n
-            };
-            // Compute new target
-            let mut target = prev.header.target();
-            target = target.mul_u32(timespan);
-            target = target.div(&Uint256::from_u64(DIFFCHANGE_TIMESPAN as u64));
-            // Clamp below MAX_TARGET (difficulty 1)
-            let max = max_target();
-            if target > max { target = max };
-            // Compactify (make expressible in the 8+24 nBits float format
-            satoshi_the_precision(&target)
+            retarget(&prev.header, scan.header.time, max_target())
           } else {
           // Otherwise just use the last block's difficulty
              prev.required_difficulty
@@ -242,6 +379,7 @@ impl Blockchain {
           total_work: header.work().add(&prev.total_work),
           required_difficulty: difficulty,
           height: prev.height + 1,
+          block_hash: header.hash(),
           prev: RefCell::new(Some(prev.clone()))
         })
       },
@@ -257,7 +395,7 @@ impl Blockchain {
     }
 
     // Insert the new block
-    self.tree.insert(&header.hash().as_bitv(), rc_header.clone());
+    self.tree.insert(&rc_header.block_hash.as_bitv(), rc_header.clone());
     // Replace the best tip if necessary
     if rc_header.total_work > self.best_tip.total_work {
       self.set_best_tip(rc_header);
@@ -276,20 +414,86 @@ impl Blockchain {
     &self.best_tip.header
   }
 
-  /// Returns an array of locator hashes used in `getheaders` messages
+  /// Returns an array of locator hashes used in `getheaders` messages. The
+  /// first ten step back one block at a time, after which the step doubles
+  /// each time; the genesis hash is always last.
   pub fn locator_hashes(&self) -> Vec<Sha256dHash> {
     LocatorHashIter::new(self.best_tip.clone(), &self.tree).collect()
   }
+
+  /// Returns an iterator over the header with the given hash and its
+  /// ancestors, newest first. The iterator is empty if the hash is unknown.
+  pub fn ancestor_iter<'a>(&'a self, start_hash: &Sha256dHash) -> AncestorIter<'a> {
+    AncestorIter { index: self.tree.lookup(&start_hash.as_bitv()), tree: &self.tree }
+  }
+
+  /// Returns up to `count` headers of the best chain, in order, starting
+  /// at height `start_height`
+  pub fn headers_from<'a>(&'a self, start_height: u32, count: uint) -> Vec<&'a BlockHeader> {
+    if start_height > self.best_tip.height {
+      return vec![];
+    }
+    // Walk back from the tip, then put the headers in forward order
+    let available = (self.best_tip.height - start_height) as uint + 1;
+    let n = min(count, available);
+    let iter = AncestorIter { index: Some(&self.best_tip), tree: &self.tree };
+    let backward: Vec<&'a BlockHeader> = iter.skip(available - n).take(n).collect();
+    backward.move_iter().rev().collect()
+  }
+
+  /// Returns the hash of the block at the given height on the best chain,
+  /// or `None` if the best chain is not that long. This walks back from
+  /// the tip, so is linear in the distance from the tip.
+  pub fn hash_at_height(&self, height: u32) -> Option<Sha256dHash> {
+    if height > self.best_tip.height {
+      return None;
+    }
+    let mut scan = self.best_tip.clone();
+    while scan.height > height {
+      scan = match scan.prev(&self.tree) {
+        Some(prev) => prev,
+        None => { return None; }
+      };
+    }
+    Some(scan.hash())
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use std::prelude::*;
-  use std::io::IoResult;
+  use std::io::{IoResult, IoError};
+  use alloc::rc::Rc;
+  use std::cell::RefCell;
 
-  use blockdata::blockchain::Blockchain;
-  use blockdata::constants::genesis_block;
+  use blockdata::block::BlockHeader;
+  use blockdata::blockchain::{Blockchain, BlockchainNode, validate_header_chain};
+  use blockdata::constants::{genesis_block, regtest_genesis_block};
   use network::serialize::Serializable;
+  use util::hash::{Sha256dHash, zero_hash};
+
+  // Build a chain of `length` blocks on top of genesis; these would not pass
+  // proof-of-work checks, so they are linked in by hand.
+  fn fake_chain(length: u32) -> Blockchain {
+    let mut chain = Blockchain::new(genesis_block().header);
+    for _ in range(0, length) {
+      let prev = chain.best_tip.clone();
+      let mut header = prev.header;
+      header.prev_blockhash = prev.hash();
+      header.nonce = prev.height + 1;
+      let node = Rc::new(BlockchainNode {
+        header: header,
+        total_work: prev.total_work,
+        required_difficulty: prev.required_difficulty,
+        height: prev.height + 1,
+        block_hash: header.hash(),
+        prev: RefCell::new(Some(prev.clone()))
+      });
+      chain.tree.insert(&node.block_hash.as_bitv(), node.clone());
+      chain.set_best_tip(node);
+    }
+    chain
+  }
 
   #[test]
   fn blockchain_serialize_test() {
@@ -304,7 +508,156 @@ mod tests {
     let read_chain = deserial.unwrap();
     assert_eq!(read_chain.best_tip.hash().serialize(), genesis_block().header.hash().serialize());
   }
-}
 
+  #[test]
+  fn blockchain_hash_at_height_test() {
+    let empty_chain = Blockchain::new(genesis_block().header);
+    assert_eq!(empty_chain.hash_at_height(0).unwrap().serialize(), genesis_block().header.hash().serialize());
+    assert!(empty_chain.hash_at_height(1).is_none());
+  }
+
+  #[test]
+  fn blockchain_locator_test() {
+    let chain = fake_chain(100);
+    // Heights given by Bitcoin Core's locator for a tip at height 100
+    let heights = [100u32, 99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 89, 87, 83, 75, 59, 27, 0];
+    let expected: Vec<Sha256dHash> = heights.iter().map(|h| chain.hash_at_height(*h).unwrap()).collect();
+    assert!(chain.locator_hashes() == expected);
+
+    let genesis_chain = Blockchain::new(genesis_block().header);
+    assert!(genesis_chain.locator_hashes() == vec![genesis_block().header.hash()]);
+  }
 
+  #[test]
+  fn blockchain_ancestor_test() {
+    let chain = fake_chain(100);
+    let tip_hash = chain.best_tip().hash();
+    let ancestors: Vec<Sha256dHash> = chain.ancestor_iter(&tip_hash).map(|h| h.hash()).collect();
+    assert_eq!(ancestors.len(), 101);
+    assert!(ancestors.get(0) == &tip_hash);
+    assert!(ancestors.get(100) == &genesis_block().header.hash());
+
+    let mid_hash = chain.hash_at_height(50).unwrap();
+    assert_eq!(chain.ancestor_iter(&mid_hash).count(), 51);
+
+    let headers = chain.headers_from(95, 10);
+    assert_eq!(headers.len(), 6);
+    assert!(headers.get(0).hash() == chain.hash_at_height(95).unwrap());
+    assert!(headers.get(5).hash() == tip_hash);
+    assert_eq!(chain.headers_from(0, 3).len(), 3);
+    assert!(chain.headers_from(0, 3).get(0).hash() == genesis_block().header.hash());
+    assert_eq!(chain.headers_from(101, 3).len(), 0);
+  }
 
+  // Mine `count` headers on `prev`, `spacing` seconds apart, with its bits
+  fn mine_headers(prev: &BlockHeader, count: uint, spacing: u32) -> Vec<BlockHeader> {
+    let mut ret = vec![];
+    let mut parent = *prev;
+    for _ in range(0, count) {
+      let mut header = BlockHeader {
+        version: 1,
+        prev_blockhash: parent.hash(),
+        merkle_root: zero_hash(),
+        time: parent.time + spacing,
+        bits: parent.bits,
+        nonce: 0
+      };
+      header.solve_nonce(1000).unwrap();
+      ret.push(header);
+      parent = header;
+    }
+    ret
+  }
+
+  // Change a header and mine it again
+  fn remine(header: &mut BlockHeader, bits: u32, time: u32) {
+    header.bits = bits;
+    header.time = time;
+    header.nonce = 0;
+    header.solve_nonce(10000).unwrap();
+  }
+
+  // BlockHeader is not Clone, but it is Copy
+  fn copy_headers(headers: &[BlockHeader]) -> Vec<BlockHeader> {
+    headers.iter().map(|h| *h).collect()
+  }
+
+  fn failures(result: Result<(), Vec<(uint, IoError)>>) -> Vec<(uint, &'static str)> {
+    match result {
+      Ok(()) => vec![],
+      Err(errors) => errors.iter().map(|&(index, ref e)| (index, e.desc)).collect()
+    }
+  }
+
+  #[test]
+  fn validate_header_chain_test() {
+    // Heights 1 to 2016 on regtest's easy target, with blocks every five
+    // minutes, so the retarget at 2016 doubles the difficulty (nearly;
+    // the first block's time is not counted)
+    let genesis = regtest_genesis_block().header;
+    let limit = genesis.target();
+    let mut headers = mine_headers(&genesis, 2015, 300);
+    let mut last = BlockHeader {
+      version: 1,
+      prev_blockhash: headers.last().unwrap().hash(),
+      merkle_root: zero_hash(),
+      time: genesis.time + 2016 * 300,
+      bits: 0,
+      nonce: 0
+    };
+    remine(&mut last, 0x203ff7de, genesis.time + 2016 * 300);
+    headers.push(last);
+    assert_eq!(failures(validate_header_chain(headers.as_slice(), 1, &genesis, &limit)), vec![]);
+
+    // Not retargeting at the boundary is caught at the right index
+    let mut bad = copy_headers(headers.as_slice());
+    remine(bad.get_mut(2015), 0x207fffff, genesis.time + 2016 * 300);
+    assert_eq!(failures(validate_header_chain(bad.as_slice(), 1, &genesis, &limit)),
+               vec![(2015, "wrong difficulty bits")]);
+
+    // As is a change of bits mid-period, which also breaks the next link,
+    // and the next header is then checked against the changed target
+    let mut bad = copy_headers(headers.as_slice());
+    remine(bad.get_mut(100), 0x207ffffe, genesis.time + 101 * 300);
+    assert_eq!(failures(validate_header_chain(bad.as_slice(), 1, &genesis, &limit)),
+               vec![(100, "wrong difficulty bits"), (101, "header does not connect"),
+                    (101, "wrong difficulty bits")]);
+
+    // A time no later than the median of the eleven before
+    let mut bad = copy_headers(headers.as_slice());
+    remine(bad.get_mut(20), 0x207fffff, genesis.time + 16 * 300);
+    assert_eq!(failures(validate_header_chain(bad.as_slice(), 1, &genesis, &limit)),
+               vec![(20, "time too early"), (21, "header does not connect")]);
+
+    // A run starting mid-period cannot check the retarget, but the rest is fine
+    assert_eq!(failures(validate_header_chain(headers.slice_from(1000), 1001, headers.get(999), &limit)),
+               vec![(1015, "retarget not checkable")]);
+    assert_eq!(failures(validate_header_chain(headers.slice(1000, 2015), 1001, headers.get(999), &limit)),
+               vec![]);
+    // And a run given the wrong parent does not connect
+    assert_eq!(failures(validate_header_chain(headers.slice(1000, 1010), 1001, &genesis, &limit)),
+               vec![(0, "header does not connect")]);
+  }
+
+  #[test]
+  fn validate_header_chain_edge_test() {
+    let genesis = regtest_genesis_block().header;
+    let limit = genesis.target();
+    let headers = mine_headers(&genesis, 1, 300);
+
+    // A run cannot start at the genesis height, even with no headers
+    assert_eq!(failures(validate_header_chain(headers.as_slice(), 0, &genesis, &limit)),
+               vec![(0, "run starts at genesis")]);
+    assert_eq!(failures(validate_header_chain(&[], 0, &genesis, &limit)),
+               vec![(0, "run starts at genesis")]);
+
+    // A run of one header at height 1 has only genesis for its median time
+    assert_eq!(failures(validate_header_chain(headers.as_slice(), 1, &genesis, &limit)), vec![]);
+    let mut early = copy_headers(headers.as_slice());
+    remine(early.get_mut(0), genesis.bits, genesis.time);
+    assert_eq!(failures(validate_header_chain(early.as_slice(), 1, &genesis, &limit)),
+               vec![(0, "time too early")]);
+    // And an empty run has nothing to check
+    assert_eq!(failures(validate_header_chain(&[], 1, &genesis, &limit)), vec![]);
+  }
+}
@@ -33,6 +33,36 @@ pub static MAX_SEQUENCE: u32 = 0xFFFFFFFF;
 pub static COIN_VALUE: u64 = 100000000;
 pub static DIFFCHANGE_INTERVAL: u32 = 2016;
 pub static DIFFCHANGE_TIMESPAN: u32 = 14 * 24 * 3600;
+/// The most satoshis which can ever exist, and so the most any
+/// transaction can move
+pub static MAX_MONEY: u64 = 21000000 * 100000000;
+/// Locktimes below this are block heights; others are unix times
+pub static LOCKTIME_THRESHOLD: u32 = 500000000;
+/// The most weight a single transaction may have
+pub static MAX_TX_WEIGHT: uint = 400000;
+/// The most weight a block may have
+pub static MAX_BLOCK_WEIGHT: uint = 4000000;
+/// The most bytes a block may have, not counting witness data
+pub static MAX_BLOCK_SIZE: uint = 1000000;
+/// The most signature operations a block's scripts may contain, which is
+/// MAX_BLOCK_SIZE / 50
+pub static MAX_BLOCK_SIGOPS: uint = 20000;
+/// The largest transaction which will be relayed
+pub static MAX_STANDARD_TX_SIZE: uint = 100000;
+/// The largest script which may be executed
+pub static MAX_SCRIPT_SIZE: uint = 10000;
+/// The largest element which may be pushed onto the script stack
+pub static MAX_SCRIPT_ELEMENT_SIZE: uint = 520;
+/// The most non-push opcodes a script may execute
+pub static MAX_OPS_PER_SCRIPT: uint = 201;
+/// The most public keys a CHECKMULTISIG may take
+pub static MAX_PUBKEYS_PER_MULTISIG: uint = 20;
+/// Number of blocks before a coinbase output may be spent. Testnet uses
+/// the same value.
+pub static COINBASE_MATURITY: u32 = 100;
+/// Number of blocks before a regtest coinbase output may be spent, which
+/// is currently the same as on the real network
+pub static REGTEST_COINBASE_MATURITY: u32 = 100;
 
 /// In Bitcoind this is insanely described as ~((u256)0 >> 32)
 pub fn max_target() -> Uint256 {
@@ -92,6 +122,16 @@ pub fn genesis_block() -> Block {
   }
 }
 
+/// Constructs and returns the genesis block of the regtest chain, which
+/// differs from the real one only in its header
+pub fn regtest_genesis_block() -> Block {
+  let mut ret = genesis_block();
+  ret.header.time = 1296688602;
+  ret.header.bits = 0x207fffff;
+  ret.header.nonce = 2;
+  ret
+}
+
 #[test]
 fn test_genesis_tx() {
   let gen = genesis_tx();
@@ -129,4 +169,15 @@ fn test_genesis_block() {
              hex_bytes("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap());
 }
 
+#[test]
+fn test_regtest_genesis_block() {
+  let gen = regtest_genesis_block();
+
+  assert!(gen.header.merkle_root == genesis_block().header.merkle_root);
+  assert_eq!(gen.header.hash().serialize().iter().rev().map(|n| *n).collect::<Vec<u8>>(),
+             hex_bytes("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap());
+  let target = gen.header.target();
+  assert!(gen.header.spv_validate(&target));
+}
+
 
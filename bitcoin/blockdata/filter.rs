@@ -0,0 +1,489 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Compact Block Filters
+//!
+//! This module provides the basic block filters of BIP 158, which let a
+//! client find blocks that may concern it without downloading every block,
+//! together with the filter header chain of BIP 157, which commits to a
+//! sequence of filters so that a client can check the filters a peer serves.
+//!
+
+use collections::treemap::TreeMap;
+use std::io::{IoResult, IoError, InvalidInput, OtherIoError};
+
+use blockdata::block::Block;
+use blockdata::opcodes;
+use blockdata::script::Script;
+use network::serialize::{Serializable, VarInt, u64_to_varint, varint_to_u64};
+use util::hash::Sha256dHash;
+
+/// Golomb-Rice coding parameter of the basic filter
+static FILTER_P: uint = 19;
+/// Inverse of the false-positive rate of the basic filter
+static FILTER_M: u64 = 784931;
+
+/// A serialized BIP 158 block filter
+#[deriving(PartialEq, Clone, Show)]
+pub struct GcsFilter {
+  /// The encoded filter, starting with the varint element count
+  pub content: Vec<u8>
+}
+
+impl GcsFilter {
+  /// Builds the BIP 158 basic filter for a block. This contains every
+  /// output script in the block except empty and OP_RETURN ones, along
+  /// with `prevout_scripts`, the scripts of the outputs the block spends,
+  /// except empty ones.
+  pub fn new_basic(block: &Block, prevout_scripts: &[Script]) -> GcsFilter {
+    let mut items = vec![];
+    for tx in block.txdata.iter() {
+      for output in tx.output.iter() {
+        let script = output.script_pubkey.as_slice();
+        if script.len() > 0 && script[0] != opcodes::RETURN {
+          items.push(Vec::from_slice(script));
+        }
+      }
+    }
+    for script in prevout_scripts.iter() {
+      if script.as_slice().len() > 0 {
+        items.push(Vec::from_slice(script.as_slice()));
+      }
+    }
+    GcsFilter::from_items(&block.header.hash(), items)
+  }
+
+  /// Builds a filter of arbitrary items for the given block. Duplicates
+  /// only appear in the filter once.
+  pub fn from_items(block_hash: &Sha256dHash, mut items: Vec<Vec<u8>>) -> GcsFilter {
+    items.sort();
+    items.dedup();
+
+    let n_items = items.len() as u64;
+    let mut hashes: Vec<u64> = items.iter().map(|item| hash_to_range(block_hash, n_items, item.as_slice())).collect();
+    hashes.sort();
+
+    let mut writer = BitWriter { data: vec![], used_bits: 0 };
+    let mut last = 0;
+    for hash in hashes.iter() {
+      let delta = *hash - last;
+      // Quotient in unary, then the remainder in FILTER_P bits
+      for _ in range(0, delta >> FILTER_P) {
+        writer.write_bit(true);
+      }
+      writer.write_bit(false);
+      writer.write_bits(delta, FILTER_P);
+      last = *hash;
+    }
+
+    let mut content = u64_to_varint(n_items).serialize();
+    content.push_all(writer.data.as_slice());
+    GcsFilter { content: content }
+  }
+
+  /// Checks whether any of `scripts` may be in the filter for the given
+  /// block. Each script not in the filter matches anyway with probability
+  /// 1/784931, but a script in the filter always matches. Fails if the
+  /// filter is malformed.
+  pub fn match_any(&self, block_hash: &Sha256dHash, scripts: &[Script]) -> IoResult<bool> {
+    let length: VarInt = try!(Serializable::deserialize(self.content.iter().map(|n| *n)));
+    let header_len = length.serialized_len();
+    let n_items = varint_to_u64(length);
+    if n_items == 0 || scripts.len() == 0 {
+      return Ok(false);
+    }
+
+    let mut queries: Vec<u64> = scripts.iter().map(|script| hash_to_range(block_hash, n_items, script.as_slice())).collect();
+    queries.sort();
+
+    let mut reader = BitReader { data: self.content.slice_from(header_len), pos: 0 };
+    let mut query_iter = queries.iter().peekable();
+    let mut value = 0;
+    for _ in range(0, n_items) {
+      value += try!(reader.read_delta());
+      loop {
+        match query_iter.peek() {
+          Some(query) if **query < value => {}
+          Some(query) if **query == value => { return Ok(true); }
+          Some(_) => { break; }
+          None => { return Ok(false); }
+        }
+        query_iter.next();
+      }
+    }
+    Ok(false)
+  }
+}
+
+/// The SipHash-2-4 function with the given 128-bit key
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+  fn rotate_left(word: u64, n: uint) -> u64 {
+    (word << n) | (word >> (64 - n))
+  }
+  fn sip_round(v: &mut [u64, ..4]) {
+    v[0] += v[1]; v[1] = rotate_left(v[1], 13); v[1] ^= v[0]; v[0] = rotate_left(v[0], 32);
+    v[2] += v[3]; v[3] = rotate_left(v[3], 16); v[3] ^= v[2];
+    v[0] += v[3]; v[3] = rotate_left(v[3], 21); v[3] ^= v[0];
+    v[2] += v[1]; v[1] = rotate_left(v[1], 17); v[1] ^= v[2]; v[2] = rotate_left(v[2], 32);
+  }
+
+  let mut v = [k0 ^ 0x736f6d6570736575, k1 ^ 0x646f72616e646f6d,
+               k0 ^ 0x6c7967656e657261, k1 ^ 0x7465646279746573];
+  for block in data.chunks(8) {
+    let mut m = 0u64;
+    for (i, ch) in block.iter().enumerate() {
+      m |= (*ch as u64) << (8 * i);
+    }
+    // The final word also carries the length in its top byte
+    if block.len() < 8 {
+      m |= (data.len() as u64) << 56;
+    }
+    v[3] ^= m;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= m;
+  }
+  // If the data fills its last word, the length gets a word of its own
+  if data.len() % 8 == 0 {
+    let m = (data.len() as u64) << 56;
+    v[3] ^= m;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= m;
+  }
+  v[2] ^= 0xff;
+  for _ in range(0u, 4) {
+    sip_round(&mut v);
+  }
+  v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Returns the high 64 bits of the 128-bit product of two words
+fn mul_high(a: u64, b: u64) -> u64 {
+  let (a_hi, a_lo) = (a >> 32, a & 0xFFFFFFFF);
+  let (b_hi, b_lo) = (b >> 32, b & 0xFFFFFFFF);
+  let cross = ((a_lo * b_lo) >> 32) + ((a_hi * b_lo) & 0xFFFFFFFF) + a_lo * b_hi;
+  a_hi * b_hi + ((a_hi * b_lo) >> 32) + (cross >> 32)
+}
+
+/// Hashes an item into [0, n_items * FILTER_M), keyed by the first 16
+/// bytes of the block hash
+fn hash_to_range(block_hash: &Sha256dHash, n_items: u64, item: &[u8]) -> u64 {
+  let key = block_hash.as_slice();
+  let k0 = key.slice(0, 8).iter().rev().fold(0u64, |acc, n| (acc << 8) | *n as u64);
+  let k1 = key.slice(8, 16).iter().rev().fold(0u64, |acc, n| (acc << 8) | *n as u64);
+  mul_high(siphash24(k0, k1, item), n_items * FILTER_M)
+}
+
+/// Writes bits most-significant first, as filters are encoded
+struct BitWriter {
+  data: Vec<u8>,
+  /// Number of bits of the last byte which have been written
+  used_bits: uint
+}
+
+impl BitWriter {
+  fn write_bit(&mut self, bit: bool) {
+    if self.used_bits == 0 {
+      self.data.push(0);
+    }
+    if bit {
+      let last = self.data.len() - 1;
+      *self.data.get_mut(last) |= 0x80 >> self.used_bits;
+    }
+    self.used_bits = (self.used_bits + 1) % 8;
+  }
+
+  fn write_bits(&mut self, value: u64, n_bits: uint) {
+    for i in range(0, n_bits).rev() {
+      self.write_bit((value >> i) & 1 == 1);
+    }
+  }
+}
+
+/// Reads back the bits written by a BitWriter
+struct BitReader<'a> {
+  data: &'a [u8],
+  /// Index of the next bit to read
+  pos: uint
+}
+
+impl<'a> BitReader<'a> {
+  fn read_bit(&mut self) -> IoResult<bool> {
+    if self.pos >= 8 * self.data.len() {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "truncated filter",
+        detail: None
+      });
+    }
+    let bit = (self.data[self.pos / 8] >> (7 - self.pos % 8)) & 1 == 1;
+    self.pos += 1;
+    Ok(bit)
+  }
+
+  /// Reads one Golomb-Rice coded difference between filter entries
+  fn read_delta(&mut self) -> IoResult<u64> {
+    let mut quotient = 0u64;
+    while try!(self.read_bit()) {
+      quotient += 1;
+    }
+    let mut remainder = 0u64;
+    for _ in range(0, FILTER_P) {
+      remainder = (remainder << 1) | if try!(self.read_bit()) { 1 } else { 0 };
+    }
+    Ok((quotient << FILTER_P) + remainder)
+  }
+}
+
+/// The filters of a range of blocks, kept so that a wallet can find the
+/// blocks which pay to or spend from its scripts
+pub struct FilterIndex {
+  /// Block hash and filter at each height
+  filters: TreeMap<u32, (Sha256dHash, GcsFilter)>
+}
+
+impl FilterIndex {
+  /// Constructs an empty index
+  pub fn new() -> FilterIndex {
+    FilterIndex { filters: TreeMap::new() }
+  }
+
+  /// Adds the filter of the block at the given height, replacing any
+  /// filter already there (e.g. after a reorg)
+  pub fn add_block_filter(&mut self, height: u32, hash: &Sha256dHash, filter: GcsFilter) {
+    self.filters.insert(height, (hash.clone(), filter));
+  }
+
+  /// Returns the filter of the block with the given hash, if we have it
+  pub fn filter_for<'a>(&'a self, hash: &Sha256dHash) -> Option<&'a GcsFilter> {
+    for (_, &(ref block_hash, ref filter)) in self.filters.iter() {
+      if block_hash == hash {
+        return Some(filter);
+      }
+    }
+    None
+  }
+
+  /// Returns the heights, between `start_height` and `end_height`
+  /// inclusive, of the blocks whose filters match any of `scripts`. A
+  /// malformed filter counts as a match, since the block itself has to be
+  /// checked to know whether it is relevant.
+  pub fn scan_for_scripts(&self, scripts: &[Script], start_height: u32, end_height: u32) -> Vec<u32> {
+    let mut ret = vec![];
+    for (height, &(ref hash, ref filter)) in self.filters.lower_bound(&start_height) {
+      if *height > end_height {
+        break;
+      }
+      match filter.match_any(hash, scripts) {
+        Ok(false) => {}
+        _ => { ret.push(*height); }
+      }
+    }
+    ret
+  }
+}
+
+/// Computes the hash of a filter, which is the double-SHA256 of its bytes
+pub fn compute_filter_hash(filter: &GcsFilter) -> Sha256dHash {
+  Sha256dHash::from_data(filter.content.as_slice())
+}
+
+/// Computes the filter header which commits to `filter_hash` on top of
+/// the previous filter header
+pub fn compute_filter_header(filter_hash: &Sha256dHash, prev_header: &Sha256dHash) -> Sha256dHash {
+  let mut data = Vec::with_capacity(64);
+  data.push_all(filter_hash.as_slice());
+  data.push_all(prev_header.as_slice());
+  Sha256dHash::from_data(data.as_slice())
+}
+
+/// Checks that `headers` is the chain obtained by committing to each of
+/// `filter_hashes` in turn, starting from `prev_header`
+pub fn verify_cfheader_chain(headers: &[Sha256dHash], prev_header: &Sha256dHash,
+                             filter_hashes: &[Sha256dHash]) -> IoResult<()> {
+  if headers.len() != filter_hashes.len() {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "filter header count does not match filter hash count",
+      detail: Some(format!("{:} headers, {:} filter hashes", headers.len(), filter_hashes.len()))
+    });
+  }
+
+  let mut prev = prev_header.clone();
+  for (n, (header, filter_hash)) in headers.iter().zip(filter_hashes.iter()).enumerate() {
+    let expected = compute_filter_header(filter_hash, &prev);
+    if expected != *header {
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "filter header does not extend the chain",
+        detail: Some(format!("header {:} is {:x}, expected {:x}", n, *header, expected))
+      });
+    }
+    prev = expected;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+  use std::io::IoResult;
+
+  use blockdata::block::Block;
+  use blockdata::constants::genesis_block;
+  use blockdata::filter::{GcsFilter, FilterIndex, compute_filter_hash, compute_filter_header, verify_cfheader_chain,
+                          siphash24};
+  use blockdata::script::Script;
+  use blockdata::transaction::TxOut;
+  use network::serialize::Serializable;
+  use util::hash::{Sha256dHash, zero_hash};
+  use util::misc::hex_bytes;
+
+  fn script(hex: &str) -> Script {
+    Script::from_vec(hex_bytes(hex).unwrap())
+  }
+
+  fn genesis_script() -> Script {
+    script("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac")
+  }
+
+  // A block whose coinbase pays to the given scripts. The nonce is only
+  // there to give each block a different hash.
+  fn block_paying_to(scripts: &[Script], nonce: u32) -> Block {
+    let mut block = genesis_block();
+    block.header.nonce = nonce;
+    block.txdata.get_mut(0).output = scripts.iter().map(|s| TxOut { value: 0, script_pubkey: s.clone() }).collect();
+    block
+  }
+
+  // Hashes are given in the usual byte-reversed display order
+  fn hash_from_hex(s: &str) -> Sha256dHash {
+    let bytes = hex_bytes(s).unwrap();
+    let hash: IoResult<Sha256dHash> = Serializable::deserialize(bytes.iter().rev().map(|n| *n));
+    hash.unwrap()
+  }
+
+  #[test]
+  fn filter_header_test() {
+    // Testnet genesis filter from the BIP 158 test vectors
+    let filter = GcsFilter { content: hex_bytes("019dfca8").unwrap() };
+    let filter_hash = compute_filter_hash(&filter);
+    assert!(filter_hash == hash_from_hex("c03705b2d6fb76a59664f1d63fe8fdbb2dc076d18175fdc51d11c43afaf78a4c"));
+    let header = compute_filter_header(&filter_hash, &zero_hash());
+    assert!(header == hash_from_hex("21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"));
+  }
+
+  #[test]
+  fn cfheader_chain_test() {
+    let filter_hashes = vec![
+      compute_filter_hash(&GcsFilter { content: hex_bytes("019dfca8").unwrap() }),
+      compute_filter_hash(&GcsFilter { content: hex_bytes("0174a170").unwrap() }),
+      compute_filter_hash(&GcsFilter { content: hex_bytes("00").unwrap() })
+    ];
+    let mut headers = vec![];
+    let mut prev = zero_hash();
+    for filter_hash in filter_hashes.iter() {
+      prev = compute_filter_header(filter_hash, &prev);
+      headers.push(prev.clone());
+    }
+
+    assert!(verify_cfheader_chain(headers.as_slice(), &zero_hash(), filter_hashes.as_slice()).is_ok());
+    // Wrong starting point
+    assert!(verify_cfheader_chain(headers.as_slice(), headers.get(0), filter_hashes.as_slice()).is_err());
+    // Truncated headers
+    assert!(verify_cfheader_chain(headers.slice_to(2), &zero_hash(), filter_hashes.as_slice()).is_err());
+    // Headers out of order
+    let swapped = vec![headers.get(0).clone(), headers.get(2).clone(), headers.get(1).clone()];
+    assert!(verify_cfheader_chain(swapped.as_slice(), &zero_hash(), filter_hashes.as_slice()).is_err());
+  }
+
+  #[test]
+  fn siphash_test() {
+    // Test vectors from the SipHash paper's reference implementation
+    let k0 = 0x0706050403020100;
+    let k1 = 0x0f0e0d0c0b0a0908;
+    assert_eq!(siphash24(k0, k1, &[]), 0x726fdb47dd0e0e31);
+    let data: Vec<u8> = range(0u8, 15).collect();
+    assert_eq!(siphash24(k0, k1, data.as_slice()), 0xa129ca6149be45e5);
+  }
+
+  #[test]
+  fn basic_filter_test() {
+    // Testnet genesis, from the BIP 158 test vectors
+    let mut block = genesis_block();
+    block.header.time = 1296688602;
+    block.header.nonce = 414098458;
+    assert!(block.header.hash() == hash_from_hex("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"));
+    let filter = GcsFilter::new_basic(&block, &[]);
+    assert_eq!(filter.content, hex_bytes("019dfca8").unwrap());
+    assert!(filter.match_any(&block.header.hash(), [genesis_script()]).unwrap());
+
+    // Several elements, with a duplicate
+    let hash = genesis_block().header.hash();
+    let p2pkh = script("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac");
+    let p2sh = script("a9140389035a9225b3839e2bbf32d826a1e222031fd887");
+    let items = vec![Vec::from_slice(genesis_script().as_slice()), Vec::from_slice(p2pkh.as_slice()),
+                     Vec::from_slice(p2sh.as_slice()), Vec::from_slice(p2pkh.as_slice())];
+    let filter = GcsFilter::from_items(&hash, items);
+    assert_eq!(filter.content, hex_bytes("0373d36831d0c13b04").unwrap());
+    assert!(filter.match_any(&hash, [p2sh.clone()]).unwrap());
+    assert!(filter.match_any(&hash, [script("51"), p2pkh.clone()]).unwrap());
+    assert!(!filter.match_any(&hash, [script("51")]).unwrap());
+    assert!(!filter.match_any(&hash, &[]).unwrap());
+
+    // Empty and OP_RETURN outputs are left out; spent scripts are put in
+    let block = block_paying_to([Script::new(), script("6a0401020304")], 1);
+    assert_eq!(GcsFilter::new_basic(&block, &[]).content, vec![0u8]);
+    let filter = GcsFilter::new_basic(&block, [p2pkh.clone()]);
+    assert!(filter.match_any(&block.header.hash(), [p2pkh.clone()]).unwrap());
+    assert!(!filter.match_any(&block.header.hash(), [script("6a0401020304")]).unwrap());
+
+    // As in the BIP 158 vector for a transaction spending from an empty
+    // output script, empty spent scripts are left out too
+    let block = block_paying_to([p2sh.clone()], 2);
+    assert_eq!(GcsFilter::new_basic(&block, [Script::new()]), GcsFilter::new_basic(&block, &[]));
+    assert_eq!(GcsFilter::new_basic(&block, [Script::new(), p2pkh.clone()]),
+               GcsFilter::new_basic(&block, [p2pkh.clone()]));
+
+    // Truncated filters are an error
+    let truncated = GcsFilter { content: hex_bytes("0373d368").unwrap() };
+    assert!(truncated.match_any(&hash, [p2sh.clone()]).is_err());
+  }
+
+  #[test]
+  fn filter_index_test() {
+    let p2pkh = script("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac");
+    let p2sh = script("a9140389035a9225b3839e2bbf32d826a1e222031fd887");
+    let blocks = vec![block_paying_to([genesis_script()], 1),
+                      block_paying_to([p2sh.clone(), genesis_script()], 2),
+                      block_paying_to([genesis_script()], 3)];
+
+    let mut index = FilterIndex::new();
+    for (height, block) in blocks.iter().enumerate() {
+      index.add_block_filter(height as u32 + 1, &block.header.hash(), GcsFilter::new_basic(block, &[]));
+    }
+
+    assert_eq!(index.scan_for_scripts([p2sh.clone()], 0, 10), vec![2]);
+    assert_eq!(index.scan_for_scripts([p2pkh.clone(), p2sh.clone()], 1, 3), vec![2]);
+    assert_eq!(index.scan_for_scripts([genesis_script()], 0, 10), vec![1, 2, 3]);
+    assert_eq!(index.scan_for_scripts([genesis_script()], 2, 2), vec![2]);
+    assert_eq!(index.scan_for_scripts([p2pkh.clone()], 0, 10), vec![]);
+    assert_eq!(index.scan_for_scripts([p2sh.clone()], 3, 10), vec![]);
+
+    let hash = blocks.get(1).header.hash();
+    assert_eq!(index.filter_for(&hash), Some(&GcsFilter::new_basic(blocks.get(1), &[])));
+    assert!(index.filter_for(&genesis_block().header.hash()).is_none());
+  }
+}
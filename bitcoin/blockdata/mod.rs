@@ -24,5 +24,7 @@ pub mod script;
 pub mod transaction;
 pub mod block;
 pub mod blockchain;
+pub mod filter;
+pub mod template;
 
 
@@ -18,11 +18,22 @@
 //! the mapping from assembler instructions to bytes.
 //!
 
-pub static FALSE:     u8 = 0x00;
-pub static TRUE:      u8 = 0x51;
-pub static PUSHDATA1: u8 = 0x4C;
-pub static PUSHDATA2: u8 = 0x4D;
-pub static PUSHDATA4: u8 = 0x4E;
-pub static CHECKSIG:  u8 = 0xAC;
+pub static FALSE:               u8 = 0x00;
+pub static RESERVED:            u8 = 0x50;
+pub static TRUE:                u8 = 0x51;
+pub static PUSHNUM_16:          u8 = 0x60;
+pub static PUSHDATA1:           u8 = 0x4C;
+pub static PUSHDATA2:           u8 = 0x4D;
+pub static PUSHDATA4:           u8 = 0x4E;
+pub static PUSHNUM_NEG1:        u8 = 0x4F;
+pub static RETURN:              u8 = 0x6A;
+pub static DUP:                 u8 = 0x76;
+pub static EQUAL:               u8 = 0x87;
+pub static EQUALVERIFY:         u8 = 0x88;
+pub static HASH160:             u8 = 0xA9;
+pub static CHECKSIG:            u8 = 0xAC;
+pub static CHECKSIGVERIFY:      u8 = 0xAD;
+pub static CHECKMULTISIG:       u8 = 0xAE;
+pub static CHECKMULTISIGVERIFY: u8 = 0xAF;
 
 
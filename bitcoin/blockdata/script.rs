@@ -24,9 +24,10 @@
 //! This module provides the structures and functions needed to support scripts.
 //!
 
-use std::io::IoResult;
+use std::io::{IoResult, IoError, InvalidInput};
 
-use network::serialize::Serializable;
+use network::serialize::{Serializable, deserialize_bounded_vec};
+use blockdata::constants::{MAX_PUBKEYS_PER_MULTISIG, MAX_BLOCK_SIZE};
 use blockdata::opcodes;
 #[cfg(test)]
 use util::misc::hex_bytes;
@@ -35,18 +36,60 @@ use util::misc::hex_bytes;
 /// A Bitcoin script
 pub struct Script(Vec<u8>);
 
+/// One step of a script template
+pub enum ScriptTemplateOp {
+  /// Exactly this opcode
+  Exact(u8),
+  /// A direct push (not PUSHDATA) of exactly this many bytes
+  AnyPush(uint),
+  /// A direct push of between this many and this many bytes, inclusive
+  AnyPushLen(uint, uint)
+}
+
+/// A fixed pattern of opcodes and pushes which a script must consist of
+/// exactly, for classifying outputs
+pub struct ScriptTemplate(pub &'static [ScriptTemplateOp]);
+
+/// OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+pub static P2PKH_TEMPLATE: ScriptTemplate =
+  ScriptTemplate(&[Exact(opcodes::DUP), Exact(opcodes::HASH160), AnyPush(20),
+                   Exact(opcodes::EQUALVERIFY), Exact(opcodes::CHECKSIG)]);
+/// OP_HASH160 <20 bytes> OP_EQUAL
+pub static P2SH_TEMPLATE: ScriptTemplate =
+  ScriptTemplate(&[Exact(opcodes::HASH160), AnyPush(20), Exact(opcodes::EQUAL)]);
+/// OP_0 <20 bytes>
+pub static P2WPKH_TEMPLATE: ScriptTemplate = ScriptTemplate(&[Exact(opcodes::FALSE), AnyPush(20)]);
+/// OP_0 <32 bytes>
+pub static P2WSH_TEMPLATE: ScriptTemplate = ScriptTemplate(&[Exact(opcodes::FALSE), AnyPush(32)]);
+/// OP_1 <32 bytes>
+pub static P2TR_TEMPLATE: ScriptTemplate = ScriptTemplate(&[Exact(opcodes::TRUE), AnyPush(32)]);
+/// <33 bytes> OP_CHECKSIG; whether the push is a compressed key is not checked
+pub static P2PK_COMPRESSED_TEMPLATE: ScriptTemplate = ScriptTemplate(&[AnyPush(33), Exact(opcodes::CHECKSIG)]);
+/// <65 bytes> OP_CHECKSIG; whether the push is an uncompressed key is not checked
+pub static P2PK_UNCOMPRESSED_TEMPLATE: ScriptTemplate = ScriptTemplate(&[AnyPush(65), Exact(opcodes::CHECKSIG)]);
+
 impl Script {
   /// Creates a new empty script
   pub fn new() -> Script { Script(vec![]) }
 
+  /// Creates a script from its raw bytes
+  pub fn from_vec(v: Vec<u8>) -> Script { Script(v) }
+
+  /// Returns the raw bytes of the script
+  pub fn as_slice<'a>(&'a self) -> &'a [u8] {
+    let &Script(ref raw) = self;
+    raw.as_slice()
+  }
+
   /// Adds instructions to push an integer onto the stack. Integers are
   /// encoded as little-endian signed-magnitude numbers, but there are
   /// dedicated opcodes to push some small integers.
   pub fn push_int(&mut self, data: int) {
-    // We can special-case -1, 1-16
+    // We can special-case -1, 1-16. OP_1 to OP_16 are consecutive, and
+    // OP_1NEGATE is two below OP_1, with OP_RESERVED in between.
     if data == -1 || (data >= 1 && data <=16) {
       let &Script(ref mut raw) = self;
-      raw.push(data as u8 + opcodes::TRUE);
+      raw.push((data - 1 + opcodes::TRUE as int) as u8);
       return;
     }
     // We can also special-case zero
@@ -113,14 +156,203 @@ impl Script {
     raw.extend(data.iter().map(|n| *n));
   }
 
+  /// Returns the shortest encoding of a push of `data`, as BIP62 requires:
+  /// OP_0 for nothing, OP_1NEGATE and OP_1 to OP_16 for the single bytes
+  /// they push, and otherwise the smallest push opcode the data fits in
+  pub fn normalize_push(data: &[u8]) -> Vec<u8> {
+    let mut ret = Script::new();
+    match data.len() {
+      1 if data[0] >= 1 && data[0] <= 16 => ret.push_int(data[0] as int),
+      1 if data[0] == 0x81 => ret.push_int(-1),
+      // This includes OP_0 for empty data
+      _ => ret.push_slice(data)
+    }
+    let Script(raw) = ret;
+    raw
+  }
+
+  /// Checks that every push in the script uses its shortest encoding, as
+  /// BIP62's MINIMALDATA rule requires. A push which runs off the end of
+  /// the script is an error as well.
+  pub fn verify_minimaldata(&self) -> IoResult<()> {
+    let &Script(ref raw) = self;
+    let mut index = 0;
+    while index < raw.len() {
+      let start = index;
+      let opcode = *raw.get(index);
+      index += 1;
+      if opcode > opcodes::PUSHDATA4 {
+        continue;
+      }
+      let end = match skip_push(raw.as_slice(), index, opcode) {
+        Some(next) => next,
+        None => {
+          return Err(IoError {
+            kind: InvalidInput,
+            desc: "truncated push",
+            detail: Some(format!("push at offset {:u} runs off the end of the script", start))
+          });
+        }
+      };
+      let len_size = match opcode {
+        n if n == opcodes::PUSHDATA1 => 1,
+        n if n == opcodes::PUSHDATA2 => 2,
+        n if n == opcodes::PUSHDATA4 => 4,
+        _ => 0
+      };
+      let data = raw.slice(index + len_size, end);
+      if Script::normalize_push(data).as_slice() != raw.slice(start, end) {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "non-minimal push",
+          detail: Some(format!("push at offset {:u} could be shorter", start))
+        });
+      }
+      index = end;
+    }
+    Ok(())
+  }
+
   /// Adds an individual opcode to the script
   pub fn push_opcode(&mut self, data: u8) {
     let &Script(ref mut raw) = self;
     raw.push(data);
   }
+
+  /// Counts the signature operations in the script, as bitcoind's
+  /// GetSigOpCount does. If `accurate` is set, a CHECKMULTISIG preceded by
+  /// a small-integer push counts as that many operations; otherwise it
+  /// counts as MAX_PUBKEYS_PER_MULTISIG. Counting stops at a truncated push.
+  pub fn sigop_count(&self, accurate: bool) -> uint {
+    let &Script(ref raw) = self;
+    let mut count = 0;
+    let mut index = 0;
+    // Start with something which is not a small-integer push
+    let mut last_opcode = 0xFFu8;
+    while index < raw.len() {
+      let opcode = *raw.get(index);
+      index += 1;
+      if opcode <= opcodes::PUSHDATA4 {
+        // Skip over the pushed data
+        match skip_push(raw.as_slice(), index, opcode) {
+          Some(next) => { index = next; }
+          None => { break; }
+        }
+      } else if opcode == opcodes::CHECKSIG || opcode == opcodes::CHECKSIGVERIFY {
+        count += 1;
+      } else if opcode == opcodes::CHECKMULTISIG || opcode == opcodes::CHECKMULTISIGVERIFY {
+        if accurate && last_opcode >= opcodes::TRUE && last_opcode <= opcodes::PUSHNUM_16 {
+          count += (last_opcode - opcodes::TRUE + 1) as uint;
+        } else {
+          count += MAX_PUBKEYS_PER_MULTISIG;
+        }
+      }
+      last_opcode = opcode;
+    }
+    count
+  }
+
+  /// Checks whether the script is empty
+  pub fn is_empty(&self) -> bool {
+    let &Script(ref raw) = self;
+    raw.len() == 0
+  }
+
+  /// Checks whether the script consists only of data pushes, as BIP16 and
+  /// BIP141 require of scriptSigs. Unlike bitcoind, which lets it through,
+  /// OP_RESERVED is not considered a push; a truncated push is not either.
+  pub fn is_push_only(&self) -> bool {
+    let &Script(ref raw) = self;
+    let mut index = 0;
+    while index < raw.len() {
+      let opcode = *raw.get(index);
+      index += 1;
+      if opcode <= opcodes::PUSHDATA4 {
+        match skip_push(raw.as_slice(), index, opcode) {
+          Some(next) => { index = next; }
+          None => { return false; }
+        }
+      } else if opcode == opcodes::RESERVED || opcode > opcodes::PUSHNUM_16 {
+        return false;
+      }
+    }
+    true
+  }
+
+  /// Checks whether the script is exactly the template, looking at each
+  /// opcode once and skipping over pushed data
+  pub fn matches_template(&self, template: &ScriptTemplate) -> bool {
+    let &Script(ref raw) = self;
+    let &ScriptTemplate(ops) = template;
+    let mut index = 0;
+    for op in ops.iter() {
+      if index >= raw.len() {
+        return false;
+      }
+      let opcode = *raw.get(index);
+      index += 1;
+      let (min, max) = match *op {
+        Exact(expected) => {
+          if opcode != expected { return false; }
+          continue;
+        }
+        AnyPush(len) => (len, len),
+        AnyPushLen(min, max) => (min, max)
+      };
+      // Direct pushes are opcodes 1 to 75, which push that many bytes
+      let len = opcode as uint;
+      if opcode == 0 || opcode >= opcodes::PUSHDATA1 || len < min || len > max {
+        return false;
+      }
+      index += len;
+    }
+    index == raw.len()
+  }
 }
 
-impl_serializable_newtype!(Script, Vec<u8>)
+/// Given the index just past a push opcode, returns the index just past the
+/// data it pushes, or None if the script ends before the push does
+fn skip_push(raw: &[u8], index: uint, opcode: u8) -> Option<uint> {
+  let (len_size, data_len) = match opcode {
+    n if n == opcodes::PUSHDATA1 => (1, read_push_len(raw, index, 1)),
+    n if n == opcodes::PUSHDATA2 => (2, read_push_len(raw, index, 2)),
+    n if n == opcodes::PUSHDATA4 => (4, read_push_len(raw, index, 4)),
+    n => (0, Some(n as uint))
+  };
+  match data_len {
+    Some(len) if index + len_size + len <= raw.len() => Some(index + len_size + len),
+    _ => None
+  }
+}
+
+/// Reads the little-endian length of a PUSHDATA1/2/4 push, if the script
+/// is long enough to contain it
+fn read_push_len(raw: &[u8], index: uint, size: uint) -> Option<uint> {
+  if index + size > raw.len() {
+    return None;
+  }
+  Some(raw.slice(index, index + size).iter().rev().fold(0u, |acc, n| acc * 0x100 + *n as uint))
+}
+
+// Written out rather than with impl_serializable_newtype! so that the
+// length is checked before anything is read for it. A script cannot be
+// longer than the block it is in.
+impl Serializable for Script {
+  fn serialize(&self) -> Vec<u8> {
+    let &Script(ref data) = self;
+    data.serialize()
+  }
+
+  fn serialized_len(&self) -> uint {
+    let &Script(ref data) = self;
+    data.serialized_len()
+  }
+
+  fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<Script> {
+    let raw = deserialize_bounded_vec(iter, MAX_BLOCK_SIZE as u64, "script bytes");
+    raw.map(|ok| Script(ok))
+  }
+}
 
 #[test]
 fn test_script() {
@@ -129,10 +361,11 @@ fn test_script() {
   assert_eq!(script, Script(vec![]));
 
   // small ints
-  script.push_int(1);  comp.push(82u8); assert_eq!(script, Script(comp.clone()));
+  script.push_int(1);  comp.push(81u8); assert_eq!(script, Script(comp.clone()));
   script.push_int(0);  comp.push(0u8);  assert_eq!(script, Script(comp.clone()));
-  script.push_int(4);  comp.push(85u8); assert_eq!(script, Script(comp.clone()));
-  script.push_int(-1); comp.push(80u8); assert_eq!(script, Script(comp.clone()));
+  script.push_int(4);  comp.push(84u8); assert_eq!(script, Script(comp.clone()));
+  script.push_int(-1); comp.push(79u8); assert_eq!(script, Script(comp.clone()));
+  script.push_int(16); comp.push(96u8); assert_eq!(script, Script(comp.clone()));
   // forced scriptint
   script.push_scriptint(4);  comp.push_all([1u8, 4]); assert_eq!(script, Script(comp.clone()));
   // big ints
@@ -156,6 +389,173 @@ fn test_script_serialize() {
   let script: IoResult<Script> = Serializable::deserialize(hex_script.iter().map(|n| *n));
   assert!(script.is_ok());
   assert_eq!(script.unwrap().serialize().as_slice(), hex_script.as_slice());
+
+  // A length longer than any block is rejected before reading the script
+  let script: IoResult<Script> = Serializable::deserialize(hex_bytes("fe41420f00").unwrap().iter().map(|n| *n));
+  let err = script.unwrap_err();
+  assert_eq!(err.desc, "too many elements");
+  assert_eq!(err.detail, Some(String::from_str("1000001 script bytes, more than the 1000000 allowed")));
+}
+
+#[test]
+fn test_sigop_count() {
+  // Pay-to-pubkeyhash and pay-to-scripthash outputs
+  let p2pkh = Script(hex_bytes("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac").unwrap());
+  assert_eq!(p2pkh.sigop_count(false), 1);
+  assert_eq!(p2pkh.sigop_count(true), 1);
+  let p2sh = Script(hex_bytes("a9140389035a9225b3839e2bbf32d826a1e222031fd887").unwrap());
+  assert_eq!(p2sh.sigop_count(true), 0);
+
+  // Bare 2-of-3 multisig
+  let mut multisig = Script::new();
+  multisig.push_int(2);
+  for _ in range(0u, 3) {
+    multisig.push_slice([2u8, ..33].as_slice());
+  }
+  multisig.push_int(3);
+  multisig.push_opcode(opcodes::CHECKMULTISIG);
+  assert_eq!(multisig.sigop_count(true), 3);
+  assert_eq!(multisig.sigop_count(false), 20);
+  // A CHECKMULTISIG without a count before it always counts as 20
+  let mut multisig = Script::new();
+  multisig.push_slice([3u8, ..33].as_slice());
+  multisig.push_opcode(opcodes::CHECKMULTISIGVERIFY);
+  assert_eq!(multisig.sigop_count(true), 20);
+
+  // Pushed data is not counted, including with PUSHDATA2
+  let mut pushes = Script::new();
+  pushes.push_slice([opcodes::CHECKSIG, ..10].as_slice());
+  pushes.push_slice([opcodes::CHECKSIG, ..300].as_slice());
+  pushes.push_opcode(opcodes::CHECKSIGVERIFY);
+  assert_eq!(pushes.sigop_count(true), 1);
+
+  // Counting stops at a truncated push
+  assert_eq!(Script(vec![opcodes::CHECKSIG, opcodes::PUSHDATA1, 5, opcodes::CHECKSIG]).sigop_count(true), 1);
+  assert_eq!(Script(vec![opcodes::CHECKSIG, opcodes::PUSHDATA2, 1]).sigop_count(true), 1);
 }
 
+#[test]
+fn test_is_push_only() {
+  assert!(Script::new().is_empty());
+  assert!(Script::new().is_push_only());
+
+  // A typical P2PKH scriptSig: signature and pubkey pushes
+  let mut script_sig = Script::new();
+  script_sig.push_slice([0x30u8, ..72].as_slice());
+  script_sig.push_slice([0x02u8, ..33].as_slice());
+  assert!(!script_sig.is_empty());
+  assert!(script_sig.is_push_only());
+  // Small integers and long pushes are pushes too
+  script_sig.push_int(0);
+  script_sig.push_opcode(opcodes::PUSHNUM_16);
+  script_sig.push_slice([0u8, ..300].as_slice());
+  assert!(script_sig.is_push_only());
+  assert!(Script(vec![0x4F]).is_push_only());
 
+  // Anything else is not
+  let p2pkh = Script(hex_bytes("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac").unwrap());
+  assert!(!p2pkh.is_push_only());
+  script_sig.push_opcode(opcodes::CHECKSIG);
+  assert!(!script_sig.is_push_only());
+  assert!(!Script(vec![opcodes::RESERVED]).is_push_only());
+  assert!(!Script(vec![0x61]).is_push_only());
+  // Nor is a push which runs off the end of the script
+  assert!(!Script(vec![5, 1, 2]).is_push_only());
+  assert!(!Script(vec![opcodes::PUSHDATA2, 1]).is_push_only());
+}
+
+#[test]
+fn test_verify_minimaldata() {
+  fn offset_err(raw: Vec<u8>) -> Option<String> {
+    match Script(raw).verify_minimaldata() {
+      Ok(()) => None,
+      Err(e) => { assert_eq!(e.desc, "non-minimal push"); e.detail }
+    }
+  }
+
+  // Data pushes from push_slice are minimal, as are opcodes and small integers
+  let p2pkh = Script(hex_bytes("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac").unwrap());
+  assert!(p2pkh.verify_minimaldata().is_ok());
+  let mut script = Script::new();
+  script.push_slice(&[]);
+  script.push_slice([0x80u8]);
+  script.push_slice([0x11u8]);
+  script.push_slice([0u8, ..75].as_slice());
+  script.push_slice([0u8, ..76].as_slice());
+  script.push_slice([0u8, ..256].as_slice());
+  script.push_opcode(opcodes::PUSHNUM_NEG1);
+  script.push_opcode(opcodes::PUSHNUM_16);
+  assert!(script.verify_minimaldata().is_ok());
+
+  // PUSHDATA1 for a byte which a direct push would do
+  assert_eq!(offset_err(vec![opcodes::PUSHDATA1, 0x01, 0x80]),
+             Some(String::from_str("push at offset 0 could be shorter")));
+  // Direct pushes of bytes with their own opcodes
+  assert_eq!(offset_err(vec![0x76, 0x01, 0x05]),
+             Some(String::from_str("push at offset 1 could be shorter")));
+  assert!(offset_err(vec![0x01, 0x10]).is_some());
+  assert!(offset_err(vec![0x01, 0x81]).is_some());
+  // Empty data pushed other than with OP_0
+  assert!(offset_err(vec![opcodes::PUSHDATA1, 0x00]).is_some());
+  // PUSHDATA2 and PUSHDATA4 for data PUSHDATA1 would do
+  let mut raw = vec![opcodes::PUSHDATA2, 0xFF, 0x00];
+  raw.push_all([0u8, ..255].as_slice());
+  assert!(offset_err(raw).is_some());
+  let mut raw = vec![opcodes::PUSHDATA4, 0x4C, 0x00, 0x00, 0x00];
+  raw.push_all([0u8, ..76].as_slice());
+  assert!(offset_err(raw).is_some());
+
+  // Truncated pushes are errors too
+  assert_eq!(Script(vec![0x05, 0x01]).verify_minimaldata().unwrap_err().desc, "truncated push");
+}
+
+#[test]
+fn test_normalize_push() {
+  assert_eq!(Script::normalize_push(&[]), vec![opcodes::FALSE]);
+  assert_eq!(Script::normalize_push([0x01]), vec![opcodes::TRUE]);
+  assert_eq!(Script::normalize_push([0x10]), vec![opcodes::PUSHNUM_16]);
+  assert_eq!(Script::normalize_push([0x81]), vec![opcodes::PUSHNUM_NEG1]);
+  assert_eq!(Script::normalize_push([0x00]), vec![0x01, 0x00]);
+  assert_eq!(Script::normalize_push([0x80]), vec![0x01, 0x80]);
+  assert_eq!(Script::normalize_push([0x42, 0x42]), vec![0x02, 0x42, 0x42]);
+  let long = Script::normalize_push([7u8, ..200].as_slice());
+  assert_eq!(long.slice_to(2), [opcodes::PUSHDATA1, 200].as_slice());
+  assert_eq!(long.len(), 202);
+}
+
+#[test]
+fn test_matches_template() {
+  let templates = [&P2PKH_TEMPLATE, &P2SH_TEMPLATE, &P2WPKH_TEMPLATE, &P2WSH_TEMPLATE, &P2TR_TEMPLATE,
+                   &P2PK_COMPRESSED_TEMPLATE, &P2PK_UNCOMPRESSED_TEMPLATE];
+  // Each script, with the indices of the templates it should match
+  let cases = [
+    ("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac", vec![0u]),
+    ("a9140389035a9225b3839e2bbf32d826a1e222031fd887", vec![1u]),
+    ("0014751e76e8199196d454941c45d1b3a323f1433bd6", vec![2u]),
+    ("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262", vec![3u]),
+    ("51201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262", vec![4u]),
+    ("210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac", vec![5u]),
+    ("410479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8ac", vec![6u]),
+    // Near misses: wrong final opcode, short push, truncated, trailing
+    // byte, PUSHDATA1 instead of a direct push, and the wrong witness version
+    ("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ad", vec![]),
+    ("a9130389035a9225b3839e2bbf32d826a1e222031f87", vec![]),
+    ("a9140389035a9225b3839e2bbf32d826a1e222031fd8", vec![]),
+    ("0014751e76e8199196d454941c45d1b3a323f1433bd600", vec![]),
+    ("004c14751e76e8199196d454941c45d1b3a323f1433bd6", vec![]),
+    ("52201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262", vec![]),
+    ("", vec![]),
+    ("6a0401020304", vec![])
+  ];
+  for &(hex, ref expected) in cases.iter() {
+    let script = Script(hex_bytes(hex).unwrap());
+    let matched: Vec<uint> = range(0, templates.len()).filter(|&i| script.matches_template(templates[i])).collect();
+    assert_eq!(matched, *expected);
+  }
+
+  // A range of push lengths
+  static ANY_HASH: ScriptTemplate = ScriptTemplate(&[AnyPushLen(20, 32)]);
+  assert!(Script(hex_bytes("14751e76e8199196d454941c45d1b3a323f1433bd6").unwrap()).matches_template(&ANY_HASH));
+  assert!(!Script(hex_bytes("0102").unwrap()).matches_template(&ANY_HASH));
+  assert!(!Script(vec![]).matches_template(&ANY_HASH));
+}
@@ -0,0 +1,262 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Block Templates
+//!
+//! Support for mining from a `getblocktemplate` response (BIP 22): parsing
+//! the template, building a coinbase which commits to the block height as
+//! BIP 34 requires, and assembling a candidate block for the miner to
+//! search for a nonce on.
+//!
+
+use std::io::{IoResult, IoError, InvalidInput};
+use std::num::from_str_radix;
+use serialize::json;
+
+use blockdata::block::{Block, BlockHeader};
+use blockdata::constants::MAX_SEQUENCE;
+use blockdata::script::Script;
+use blockdata::transaction::{Transaction, TxIn, TxOut};
+use network::serialize::Serializable;
+use util::hash::{Sha256dHash, merkle_root, zero_hash};
+use util::misc::{hex_bytes, prepend_err};
+
+/// The parts of a block template needed to build a block
+pub struct BlockTemplate {
+  /// Block version
+  pub version: u32,
+  /// Hash of the block to build on
+  pub prev_blockhash: Sha256dHash,
+  /// Transactions to include after the coinbase, in order
+  pub transactions: Vec<Transaction>,
+  /// Total value the coinbase may claim, subsidy plus fees
+  pub coinbase_value: u64,
+  /// Compact target the block must meet
+  pub bits: u32,
+  /// Time to put in the block header
+  pub time: u32,
+  /// Height of the block being built
+  pub height: u32
+}
+
+/// A transaction as listed in a template
+#[deriving(Decodable)]
+struct RawTemplateTransaction {
+  data: String
+}
+
+/// A template as it appears in JSON; unused fields are ignored
+#[deriving(Decodable)]
+struct RawBlockTemplate {
+  version: u32,
+  previousblockhash: String,
+  transactions: Vec<RawTemplateTransaction>,
+  coinbasevalue: u64,
+  bits: String,
+  curtime: u32,
+  height: u32
+}
+
+fn template_error(desc: &'static str, detail: String) -> IoError {
+  IoError {
+    kind: InvalidInput,
+    desc: desc,
+    detail: Some(detail)
+  }
+}
+
+/// Parses the result of a `getblocktemplate` call
+pub fn parse_block_template(json: &str) -> IoResult<BlockTemplate> {
+  let raw: RawBlockTemplate = match json::decode(json) {
+    Ok(raw) => raw,
+    Err(e) => { return Err(template_error("bad block template", format!("{:}", e))); }
+  };
+
+  // The hash is given in the usual byte-reversed display order
+  let hash_bytes = try!(prepend_err("previousblockhash", hex_bytes(raw.previousblockhash.as_slice())));
+  let prev_blockhash = try!(prepend_err("previousblockhash",
+                                        Serializable::deserialize(hash_bytes.iter().rev().map(|n| *n))));
+  let bits = match from_str_radix(raw.bits.as_slice(), 16) {
+    Some(bits) => bits,
+    None => { return Err(template_error("bad block template", format!("bits {:s} is not hex", raw.bits.as_slice()))); }
+  };
+
+  let mut transactions = vec![];
+  for tx in raw.transactions.iter() {
+    let data = try!(prepend_err("transaction", hex_bytes(tx.data.as_slice())));
+    transactions.push(try!(prepend_err("transaction", Serializable::deserialize(data.move_iter()))));
+  }
+
+  Ok(BlockTemplate {
+    version: raw.version,
+    prev_blockhash: prev_blockhash,
+    transactions: transactions,
+    coinbase_value: raw.coinbasevalue,
+    bits: bits,
+    time: raw.curtime,
+    height: raw.height
+  })
+}
+
+/// Builds a coinbase scriptSig which starts with the block height, as
+/// BIP 34 requires, followed by the extra nonce
+fn coinbase_script(height: u32, extra_nonce: u32) -> Script {
+  let mut script = Script::new();
+  // bitcoind uses the small-integer opcodes for small heights, as push_int
+  // does, and checks that the scriptSig begins exactly as it would have
+  // written it
+  script.push_int(height as int);
+  script.push_slice(extra_nonce.serialize().as_slice());
+  script
+}
+
+/// Builds a coinbase for the template which pays its whole value to
+/// `script_pubkey`
+pub fn coinbase_from_template(template: &BlockTemplate, script_pubkey: &Script, extra_nonce: u32) -> Transaction {
+  Transaction {
+    version: 1,
+    lock_time: 0,
+    input: vec![TxIn {
+      prev_hash: zero_hash(),
+      prev_index: 0xFFFFFFFF,
+      script_sig: coinbase_script(template.height, extra_nonce),
+      sequence: MAX_SEQUENCE
+    }],
+    output: vec![TxOut {
+      value: template.coinbase_value,
+      script_pubkey: script_pubkey.clone()
+    }]
+  }
+}
+
+/// Assembles a candidate block from the template and a coinbase, with
+/// `extra_nonce` written into the coinbase and the merkle root computed.
+/// The header nonce is left at zero for the miner to fill in.
+pub fn build_candidate_block(template: &BlockTemplate, coinbase: Transaction, extra_nonce: u32) -> Block {
+  let mut coinbase = coinbase;
+  coinbase.input.get_mut(0).script_sig = coinbase_script(template.height, extra_nonce);
+
+  let mut txdata = vec![coinbase];
+  for tx in template.transactions.iter() {
+    // Transactions are not Clone, but they do round-trip
+    txdata.push(Serializable::deserialize(tx.serialize().move_iter()).unwrap());
+  }
+
+  Block {
+    header: BlockHeader {
+      version: template.version,
+      prev_blockhash: template.prev_blockhash,
+      merkle_root: merkle_root(txdata.as_slice()),
+      time: template.time,
+      bits: template.bits,
+      nonce: 0
+    },
+    txdata: txdata
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use blockdata::opcodes;
+  use blockdata::script::Script;
+  use blockdata::template::{parse_block_template, coinbase_from_template, build_candidate_block};
+  use network::serialize::Serializable;
+  use util::hash::merkle_root;
+  use util::misc::hex_bytes;
+
+  static TEMPLATE: &'static str = "{
+    \"capabilities\": [\"proposal\"],
+    \"version\": 2,
+    \"previousblockhash\": \"00000000e47349de5a0193abc5a2fe0be81cb1d1987e45ab85f3289d54cddc4d\",
+    \"transactions\": [{
+      \"data\": \"010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac00000000\",
+      \"fee\": 0,
+      \"sigops\": 1
+    }],
+    \"coinbasevalue\": 5000000000,
+    \"target\": \"00000000ffff0000000000000000000000000000000000000000000000000000\",
+    \"bits\": \"1d00ffff\",
+    \"curtime\": 1231965700,
+    \"height\": 300
+  }";
+
+  fn pay_script() -> Script {
+    Script::from_vec(hex_bytes("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac").unwrap())
+  }
+
+  #[test]
+  fn parse_block_template_test() {
+    let template = parse_block_template(TEMPLATE).unwrap();
+    assert_eq!(template.version, 2);
+    assert_eq!(template.prev_blockhash.as_slice(),
+               hex_bytes("4ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000").unwrap().as_slice());
+    assert_eq!(template.transactions.len(), 1);
+    assert_eq!(template.transactions.get(0).input.len(), 3);
+    assert_eq!(template.coinbase_value, 5000000000);
+    assert_eq!(template.bits, 0x1d00ffff);
+    assert_eq!(template.time, 1231965700);
+    assert_eq!(template.height, 300);
+
+    assert!(parse_block_template("{}").is_err());
+    assert!(parse_block_template(TEMPLATE.replace("1d00ffff", "1d00fffz").as_slice()).is_err());
+    assert!(parse_block_template(TEMPLATE.replace("\"data\": \"01", "\"data\": \"").as_slice()).is_err());
+  }
+
+  #[test]
+  fn coinbase_from_template_test() {
+    let mut template = parse_block_template(TEMPLATE).unwrap();
+    let coinbase = coinbase_from_template(&template, &pay_script(), 7);
+    assert!(coinbase.is_coinbase());
+    assert_eq!(coinbase.output.len(), 1);
+    assert_eq!(coinbase.output.get(0).value, 5000000000);
+    assert_eq!(coinbase.output.get(0).script_pubkey, pay_script());
+    // Height 300 as a two-byte push, then the extra nonce
+    assert_eq!(coinbase.input.get(0).script_sig.as_slice(), [2u8, 0x2c, 0x01, 4, 7, 0, 0, 0].as_slice());
+
+    // Heights which need a sign byte, and small heights
+    template.height = 128;
+    let coinbase = coinbase_from_template(&template, &pay_script(), 0);
+    assert_eq!(coinbase.input.get(0).script_sig.as_slice().slice_to(3), [2u8, 0x80, 0x00].as_slice());
+    template.height = 16;
+    let coinbase = coinbase_from_template(&template, &pay_script(), 0);
+    assert_eq!(coinbase.input.get(0).script_sig.as_slice()[0], opcodes::PUSHNUM_16);
+    template.height = 1;
+    let coinbase = coinbase_from_template(&template, &pay_script(), 0);
+    assert_eq!(coinbase.input.get(0).script_sig.as_slice()[0], opcodes::TRUE);
+  }
+
+  #[test]
+  fn build_candidate_block_test() {
+    let template = parse_block_template(TEMPLATE).unwrap();
+    let block = build_candidate_block(&template, coinbase_from_template(&template, &pay_script(), 0), 1);
+    assert_eq!(block.header.version, 2);
+    assert!(block.header.prev_blockhash == template.prev_blockhash);
+    assert_eq!(block.header.time, 1231965700);
+    assert_eq!(block.header.bits, 0x1d00ffff);
+    assert_eq!(block.header.nonce, 0);
+    assert_eq!(block.txdata.len(), 2);
+    assert_eq!(block.txdata.get(1).serialize(), template.transactions.get(0).serialize());
+    assert!(block.header.merkle_root == merkle_root(block.txdata.as_slice()));
+    // The extra nonce replaces the one the coinbase was built with
+    assert_eq!(block.txdata.get(0).input.get(0).script_sig.as_slice(), [2u8, 0x2c, 0x01, 4, 1, 0, 0, 0].as_slice());
+    assert!(block.validate_coinbase_position().is_ok());
+
+    // Changing the extra nonce changes the merkle root
+    let other = build_candidate_block(&template, coinbase_from_template(&template, &pay_script(), 0), 2);
+    assert!(other.header.merkle_root != block.header.merkle_root);
+  }
+}
+
@@ -23,13 +23,28 @@
 //! This module provides the structures and functions needed to support transactions.
 //!
 
-use std::io::IoResult;
+use std::io::{IoResult, IoError, OtherIoError};
+use std::slice;
 use util::hash::Sha256dHash;
-use network::serialize::{Serializable, SerializeIter};
+use network::serialize::{Serializable, SerializeIter, deserialize_bounded_vec};
+use blockdata::constants::{MAX_SEQUENCE, MAX_MONEY, LOCKTIME_THRESHOLD, MAX_TX_WEIGHT, MAX_BLOCK_SIZE};
+use util::misc::prepend_err;
 use blockdata::script::Script;
 #[cfg(test)]
+use blockdata::constants::{COIN_VALUE, genesis_tx};
+#[cfg(test)]
 use util::misc::hex_bytes;
 
+/// Size of the smallest transaction input: a hash, an index, an empty
+/// script and a sequence number
+static MIN_TXIN_SIZE: uint = 32 + 4 + 1 + 4;
+/// Size of the smallest transaction output: a value and an empty script
+static MIN_TXOUT_SIZE: uint = 8 + 1;
+/// Size of the smallest transaction which can appear in a block: a version,
+/// one input, one output and a lock time. Nothing larger than a block can be
+/// valid, so this bounds how many transactions a block can hold.
+pub static MIN_TX_SIZE: uint = 4 + 1 + MIN_TXIN_SIZE + 1 + MIN_TXOUT_SIZE + 4;
+
 /// A transaction input, which defines old coins to be consumed
 pub struct TxIn {
   /// The hash of the transaction whose output is being used an an input
@@ -54,6 +69,56 @@ pub struct TxOut {
   pub script_pubkey: Script
 }
 
+/// The witness of a segwit input: a stack of byte strings, such as a
+/// signature and a public key, which takes the place of the scriptSig
+#[deriving(PartialEq, Clone, Show)]
+pub struct Witness(Vec<Vec<u8>>);
+
+/// An iterator over the items of a witness
+pub struct WitnessItems<'a> {
+  iter: slice::Items<'a, Vec<u8>>
+}
+
+impl<'a> Iterator<&'a [u8]> for WitnessItems<'a> {
+  fn next(&mut self) -> Option<&'a [u8]> {
+    self.iter.next().map(|item| item.as_slice())
+  }
+}
+
+impl Witness {
+  /// Creates a new empty witness
+  pub fn new() -> Witness { Witness(vec![]) }
+
+  /// Adds an item to the top of the witness stack
+  pub fn push(&mut self, item: Vec<u8>) {
+    let &Witness(ref mut items) = self;
+    items.push(item);
+  }
+
+  /// Returns the number of items in the witness
+  pub fn len(&self) -> uint {
+    let &Witness(ref items) = self;
+    items.len()
+  }
+
+  /// Checks whether the witness has no items
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Returns the `i`th item, counting from the bottom of the stack
+  pub fn get<'a>(&'a self, i: uint) -> Option<&'a [u8]> {
+    let &Witness(ref items) = self;
+    if i < items.len() { Some(items.get(i).as_slice()) } else { None }
+  }
+
+  /// Returns an iterator over the items, bottom of the stack first
+  pub fn iter<'a>(&'a self) -> WitnessItems<'a> {
+    let &Witness(ref items) = self;
+    WitnessItems { iter: items.iter() }
+  }
+}
+
 /// A Bitcoin transaction, which describes an authenticated movement of coins
 pub struct Transaction {
   /// The protocol version, should always be 1.
@@ -67,9 +132,194 @@ pub struct Transaction {
   pub output: Vec<TxOut>
 }
 
+/// Which of the checks in `Transaction::validate` to perform. Everything
+/// is checked by default, but tests may want to exercise rules one at a time.
+#[deriving(PartialEq, Clone, Show)]
+pub struct ValidationFlags {
+  /// Require at least one input and at least one output
+  pub nonempty: bool,
+  /// Reject transactions which spend the same output twice
+  pub duplicate_inputs: bool,
+  /// Require output values to lie in [0, MAX_MONEY]
+  pub amounts: bool,
+  /// Require the outputs not to exceed the values of the spent outputs
+  /// given in `prevouts`
+  pub input_values: bool,
+  /// Reject transactions which are not final at the given height and time
+  pub finality: bool,
+  /// Reject transactions heavier than MAX_TX_WEIGHT
+  pub weight: bool
+}
+
+impl ValidationFlags {
+  /// Returns flags which enable every check
+  pub fn all() -> ValidationFlags {
+    ValidationFlags {
+      nonempty: true,
+      duplicate_inputs: true,
+      amounts: true,
+      input_values: true,
+      finality: true,
+      weight: true
+    }
+  }
+}
+
+/// Builds the error returned for a transaction which fails validation
+fn validation_error(desc: &'static str, detail: String) -> IoError {
+  IoError {
+    kind: OtherIoError,
+    desc: desc,
+    detail: Some(detail)
+  }
+}
+
+impl Transaction {
+  /// Checks whether this is a coinbase transaction, which has a single
+  /// input spending the null outpoint
+  pub fn is_coinbase(&self) -> bool {
+    self.input.len() == 1 &&
+      self.input.get(0).prev_index == 0xFFFFFFFF &&
+      self.input.get(0).prev_hash.as_slice().iter().all(|n| *n == 0)
+  }
+
+  /// Checks whether the transaction may be included in a block at the given
+  /// height and time
+  pub fn is_final(&self, height: u32, time: u32) -> bool {
+    if self.lock_time == 0 {
+      return true;
+    }
+    let limit = if self.lock_time < LOCKTIME_THRESHOLD { height } else { time };
+    if self.lock_time < limit {
+      return true;
+    }
+    // A lock time in the future is ignored if every sequence number is final
+    self.input.iter().all(|txin| txin.sequence == MAX_SEQUENCE)
+  }
+
+  /// Performs the consensus checks on a transaction which can be done
+  /// without executing its scripts. `prevouts` holds the outputs spent by
+  /// each input, in order, and is ignored for coinbase transactions.
+  pub fn validate(&self, prevouts: &[TxOut], height: u32, time: u32,
+                  flags: &ValidationFlags) -> IoResult<()> {
+    if flags.nonempty {
+      if self.input.is_empty() {
+        return Err(validation_error("transaction has no inputs", format!("{:x}", self.hash())));
+      }
+      if self.output.is_empty() {
+        return Err(validation_error("transaction has no outputs", format!("{:x}", self.hash())));
+      }
+    }
+
+    if flags.duplicate_inputs {
+      // Sort the serialized outpoints so that any duplicates are adjacent
+      let mut outpoints: Vec<Vec<u8>> = self.input.iter().map(|txin| {
+          let mut outpoint = txin.prev_hash.serialize();
+          outpoint.extend(txin.prev_index.serialize().move_iter());
+          outpoint
+        }).collect();
+      outpoints.sort();
+      for pair in outpoints.as_slice().windows(2) {
+        if pair[0] == pair[1] {
+          return Err(validation_error("transaction spends an output twice", format!("{:x}", self.hash())));
+        }
+      }
+    }
+
+    if flags.amounts {
+      let mut total_out = 0u64;
+      for (n, txout) in self.output.iter().enumerate() {
+        if txout.value > MAX_MONEY {
+          return Err(validation_error("output value out of range",
+                                      format!("output {:} of {:x} has value {:}", n, self.hash(), txout.value)));
+        }
+        total_out += txout.value;
+        if total_out > MAX_MONEY {
+          return Err(validation_error("total output value out of range",
+                                      format!("outputs of {:x} sum to more than {:}", self.hash(), MAX_MONEY)));
+        }
+      }
+    }
+
+    if flags.input_values && !self.is_coinbase() {
+      let total_out = self.output.iter().fold(0u64, |sum, txout| sum + txout.value);
+      if prevouts.len() != self.input.len() {
+        return Err(validation_error("wrong number of previous outputs",
+                                    format!("{:x} has {:} inputs but {:} previous outputs were given",
+                                            self.hash(), self.input.len(), prevouts.len())));
+      }
+      let mut total_in = 0u64;
+      for prevout in prevouts.iter() {
+        if prevout.value > MAX_MONEY || total_in + prevout.value > MAX_MONEY {
+          return Err(validation_error("input value out of range",
+                                      format!("inputs of {:x} sum to more than {:}", self.hash(), MAX_MONEY)));
+        }
+        total_in += prevout.value;
+      }
+      if total_out > total_in {
+        return Err(validation_error("transaction spends more than its inputs",
+                                    format!("{:x} spends {:} from inputs worth {:}", self.hash(), total_out, total_in)));
+      }
+    }
+
+    if flags.finality && !self.is_final(height, time) {
+      return Err(validation_error("transaction is not final",
+                                  format!("{:x} is locked until {:}", self.hash(), self.lock_time)));
+    }
+
+    if flags.weight {
+      // Without witness data, weight is four times the serialized size
+      let weight = 4 * self.serialized_len();
+      if weight > MAX_TX_WEIGHT {
+        return Err(validation_error("transaction too heavy",
+                                    format!("{:x} has weight {:}, more than {:}", self.hash(), weight, MAX_TX_WEIGHT)));
+      }
+    }
+    Ok(())
+  }
+}
+
 impl_serializable!(TxIn, prev_hash, prev_index, script_sig, sequence)
 impl_serializable!(TxOut, value, script_pubkey)
-impl_serializable!(Transaction, version, input, output, lock_time)
+impl_serializable_newtype!(Witness, Vec<Vec<u8>>)
+
+// Written out rather than with impl_serializable! so that the input and
+// output counts are checked before anything is read for them
+impl Serializable for Transaction {
+  fn serialize(&self) -> Vec<u8> {
+    let mut ret = self.version.serialize();
+    ret.extend(self.input.serialize().move_iter());
+    ret.extend(self.output.serialize().move_iter());
+    ret.extend(self.lock_time.serialize().move_iter());
+    ret
+  }
+
+  fn serialized_len(&self) -> uint {
+    self.version.serialized_len() + self.input.serialized_len() +
+      self.output.serialized_len() + self.lock_time.serialized_len()
+  }
+
+  fn serialize_iter<'a>(&'a self) -> SerializeIter<'a> {
+    SerializeIter {
+      data_iter: None,
+      sub_iter_iter: box vec![ &self.version as &Serializable, &self.input as &Serializable,
+                               &self.output as &Serializable, &self.lock_time as &Serializable ].move_iter(),
+      sub_iter: None,
+      sub_started: false
+    }
+  }
+
+  fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<Transaction> {
+    Ok(Transaction {
+      version: try!(prepend_err("version", Serializable::deserialize(iter.by_ref()))),
+      input: try!(prepend_err("input", deserialize_bounded_vec(iter.by_ref(),
+                                                               (MAX_BLOCK_SIZE / MIN_TXIN_SIZE) as u64, "inputs"))),
+      output: try!(prepend_err("output", deserialize_bounded_vec(iter.by_ref(),
+                                                                 (MAX_BLOCK_SIZE / MIN_TXOUT_SIZE) as u64, "outputs"))),
+      lock_time: try!(prepend_err("lock_time", Serializable::deserialize(iter.by_ref())))
+    })
+  }
+}
 
 #[test]
 fn test_txin() {
@@ -77,6 +327,37 @@ fn test_txin() {
   assert!(txin.is_ok());
 }
 
+#[test]
+fn test_witness() {
+  // A P2WPKH witness: a signature and a compressed public key
+  let sig = hex_bytes("3044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb01").unwrap();
+  let pubkey = hex_bytes("038262a6c6cec93c2d3ecd6c6072efea86d02ff8e3328bbd0242b20af3425990ac").unwrap();
+  let mut witness = Witness::new();
+  assert!(witness.is_empty());
+  witness.push(sig.clone());
+  witness.push(pubkey.clone());
+  assert!(!witness.is_empty());
+  assert_eq!(witness.len(), 2);
+  assert_eq!(witness.get(0), Some(sig.as_slice()));
+  assert_eq!(witness.get(1), Some(pubkey.as_slice()));
+  assert_eq!(witness.get(2), None);
+  assert_eq!(witness.iter().collect::<Vec<&[u8]>>(), vec![sig.as_slice(), pubkey.as_slice()]);
+
+  // An item count, then each item with its length
+  let mut expected = vec![2u8, 71];
+  expected.push_all(sig.as_slice());
+  expected.push(33);
+  expected.push_all(pubkey.as_slice());
+  assert_eq!(witness.serialize(), expected);
+
+  let decode: IoResult<Witness> = Serializable::deserialize(expected.iter().map(|n| *n));
+  assert_eq!(decode.unwrap(), witness);
+  let decode: IoResult<Witness> = Serializable::deserialize(expected.slice_to(50).iter().map(|n| *n));
+  assert!(decode.is_err());
+  let decode: IoResult<Witness> = Serializable::deserialize(Some(0u8).move_iter());
+  assert!(decode.unwrap().is_empty());
+}
+
 #[test]
 fn test_transaction() {
   let hex_tx = hex_bytes("0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000").unwrap();
@@ -100,5 +381,99 @@ fn test_transaction() {
              hex_bytes("a6eab3c14ab5272a58a5ba91505ba1a4b6d7a3a9fcbd187b6cd99a7b6d548cb7").unwrap());
 }
 
+#[test]
+fn test_transaction_count_limits() {
+  // An input count no block could hold is rejected before any input is read
+  let tx: IoResult<Transaction> = Serializable::deserialize(hex_bytes("01000000fe00000100").unwrap().iter().map(|n| *n));
+  let err = tx.err().unwrap();
+  assert_eq!(err.desc, "too many elements");
+  assert_eq!(err.detail, Some(String::from_str("input: 65536 inputs, more than the 24390 allowed")));
+
+  // Likewise outputs
+  let tx: IoResult<Transaction> = Serializable::deserialize(hex_bytes("0100000000feffffffff").unwrap().iter().map(|n| *n));
+  let err = tx.err().unwrap();
+  assert_eq!(err.desc, "too many elements");
+  assert_eq!(err.detail, Some(String::from_str("output: 4294967295 outputs, more than the 111111 allowed")));
 
+  // Serialization is unchanged by writing the impl out
+  let tx = decode();
+  assert_eq!(tx.serialize(), hex_bytes(TEST_TX).unwrap());
+  assert_eq!(tx.serialize_iter().collect::<Vec<u8>>(), tx.serialize());
+  assert_eq!(tx.serialized_len(), tx.serialize().len());
+}
+
+#[cfg(test)]
+static TEST_TX: &'static str = "0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000";
 
+#[cfg(test)]
+fn decode() -> Transaction {
+  let tx: IoResult<Transaction> = Serializable::deserialize(hex_bytes(TEST_TX).unwrap().iter().map(|n| *n));
+  tx.unwrap()
+}
+
+#[cfg(test)]
+fn prevouts() -> Vec<TxOut> {
+  vec![TxOut { value: 2 * COIN_VALUE, script_pubkey: Script::new() }]
+}
+
+#[test]
+fn test_transaction_validate() {
+  let all = ValidationFlags::all();
+
+  // The transaction pays 1 BTC out of a 2 BTC input
+  let tx = decode();
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_ok());
+  assert!(!tx.is_coinbase());
+  assert!(genesis_tx().is_coinbase());
+  assert!(genesis_tx().validate([].as_slice(), 0, 0, &all).is_ok());
+
+  // No inputs or outputs
+  let mut tx = decode();
+  tx.input.clear();
+  assert!(tx.validate([].as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate([].as_slice(), 300000, 0, &ValidationFlags { nonempty: false, input_values: false, .. all.clone() }).is_ok());
+  let mut tx = decode();
+  tx.output.clear();
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &ValidationFlags { nonempty: false, .. all.clone() }).is_ok());
+
+  // Duplicate inputs
+  let mut tx = decode();
+  let dup = decode().input.pop().unwrap();
+  tx.input.push(dup);
+  let mut two_prevouts = prevouts();
+  two_prevouts.push_all_move(prevouts());
+  assert!(tx.validate(two_prevouts.as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate(two_prevouts.as_slice(), 300000, 0, &ValidationFlags { duplicate_inputs: false, .. all.clone() }).is_ok());
+
+  // Output values out of range or more than the inputs
+  let mut tx = decode();
+  tx.output.get_mut(0).value = MAX_MONEY + 1;
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &ValidationFlags { amounts: false, input_values: false, .. all.clone() }).is_ok());
+  let mut tx = decode();
+  tx.output.get_mut(0).value = 3 * COIN_VALUE;
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &ValidationFlags { input_values: false, .. all.clone() }).is_ok());
+  // ...or with the wrong number of previous outputs
+  assert!(decode().validate(two_prevouts.as_slice(), 300000, 0, &all).is_err());
+
+  // Heightlocked, timelocked, and locked but with final sequence numbers
+  let mut tx = decode();
+  tx.lock_time = 300000;
+  tx.input.get_mut(0).sequence = 0;
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate(prevouts().as_slice(), 300001, 0, &all).is_ok());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &ValidationFlags { finality: false, .. all.clone() }).is_ok());
+  tx.lock_time = 1400000000;
+  assert!(tx.validate(prevouts().as_slice(), 300000, 1400000000, &all).is_err());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 1400000001, &all).is_ok());
+  tx.input.get_mut(0).sequence = MAX_SEQUENCE;
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_ok());
+
+  // Too heavy
+  let mut tx = decode();
+  tx.input.get_mut(0).script_sig.push_slice([0u8, ..100000].as_slice());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &all).is_err());
+  assert!(tx.validate(prevouts().as_slice(), 300000, 0, &ValidationFlags { weight: false, .. all.clone() }).is_ok());
+}
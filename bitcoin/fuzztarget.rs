@@ -0,0 +1,197 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Fuzzing Entry Points
+//!
+//! Functions which feed arbitrary bytes to the library's decoders, for use
+//! by an external fuzzer; build with `--cfg fuzztarget` to export them.
+//! Each one checks that anything which decodes successfully re-encodes to
+//! bytes which decode again, and to the same bytes a second time. Decoding
+//! is lenient in places (non-minimal varints, any nonzero byte as `true`),
+//! so the first re-encoding need not match the input byte for byte.
+//!
+
+use std::io::IoResult;
+
+use blockdata::block::{Block, BlockHeader};
+use blockdata::script::Script;
+use blockdata::transaction::Transaction;
+use network::constants::MAGIC_BITCOIN;
+use network::message_blockdata::{GetBlocksMessage, GetHeadersMessage, InventoryMessage};
+use network::message_blockdata::{GetDataMessage, NotFoundMessage, HeadersMessage, SendHeadersMessage};
+use network::message_network::{VersionMessage, VersionAckMessage, GetAddrMessage, MempoolMessage};
+use network::message_network::{PingMessage, PongMessage};
+use network::serialize::{Serializable, VarInt};
+use network::socket::decode_message;
+
+/// Decodes `data` as a `T`, and if that succeeds checks that the value's
+/// serialization decodes as well, and serializes the same way again
+fn roundtrip<T: Serializable>(data: &[u8]) {
+  let decode: IoResult<T> = Serializable::deserialize(data.iter().map(|n| *n));
+  match decode {
+    Ok(value) => {
+      let reserialized = value.serialize();
+      let redecode: IoResult<T> = Serializable::deserialize(reserialized.iter().map(|n| *n));
+      assert!(redecode.is_ok());
+      assert!(redecode.unwrap().serialize() == reserialized);
+    }
+    Err(_) => {}
+  }
+}
+
+/// Decodes `data` as the payload of a message with the given command
+fn payload(command: &str, data: &[u8]) {
+  match command {
+    "version" => roundtrip::<VersionMessage>(data),
+    "verack" => roundtrip::<VersionAckMessage>(data),
+    "getaddr" => roundtrip::<GetAddrMessage>(data),
+    "mempool" => roundtrip::<MempoolMessage>(data),
+    "ping" => roundtrip::<PingMessage>(data),
+    "pong" => roundtrip::<PongMessage>(data),
+    "inv" => roundtrip::<InventoryMessage>(data),
+    "getdata" => roundtrip::<GetDataMessage>(data),
+    "notfound" => roundtrip::<NotFoundMessage>(data),
+    "getblocks" => roundtrip::<GetBlocksMessage>(data),
+    "getheaders" => roundtrip::<GetHeadersMessage>(data),
+    "headers" => roundtrip::<HeadersMessage>(data),
+    "sendheaders" => roundtrip::<SendHeadersMessage>(data),
+    "block" => roundtrip::<Block>(data),
+    "tx" => roundtrip::<Transaction>(data),
+    _ => {}
+  }
+}
+
+/// Decodes `data` as a network message, including its header, and then
+/// decodes the payload of any message with a known command
+pub fn message(data: &[u8]) {
+  match decode_message(data.iter().map(|n| *n), MAGIC_BITCOIN) {
+    Ok(msg) => payload(msg.command.as_slice(), msg.data.as_slice()),
+    Err(_) => {}
+  }
+}
+
+/// Decodes `data` as the payload of every known message type
+pub fn message_payload(data: &[u8]) {
+  for command in ["version", "verack", "getaddr", "mempool", "ping", "pong", "inv", "getdata",
+                  "notfound", "getblocks", "getheaders", "headers", "sendheaders", "block", "tx"].iter() {
+    payload(*command, data);
+  }
+}
+
+/// Decodes `data` as a transaction
+pub fn transaction(data: &[u8]) {
+  roundtrip::<Transaction>(data);
+}
+
+/// Decodes `data` as a block and as a lone block header
+pub fn block(data: &[u8]) {
+  roundtrip::<Block>(data);
+  roundtrip::<BlockHeader>(data);
+}
+
+/// Decodes `data` as a script
+pub fn script(data: &[u8]) {
+  roundtrip::<Script>(data);
+}
+
+/// Decodes `data` as a varint
+pub fn varint(data: &[u8]) {
+  roundtrip::<VarInt>(data);
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use blockdata::constants::{genesis_block, genesis_tx};
+  use fuzztarget::{message, message_payload, transaction, block, script, varint};
+  use network::constants::MAGIC_BITCOIN;
+  use network::serialize::{Serializable, CommandString, CheckedData};
+  use util::misc::hex_bytes;
+
+  /// Xorshift generator, so that any failing input can be reproduced
+  struct XorShift {
+    state: u32
+  }
+
+  impl XorShift {
+    fn next(&mut self, bound: uint) -> uint {
+      self.state ^= self.state << 13;
+      self.state ^= self.state >> 17;
+      self.state ^= self.state << 5;
+      self.state as uint % bound
+    }
+  }
+
+  /// Runs `data` through every entry point
+  fn run_all(data: &[u8]) {
+    message(data);
+    message_payload(data);
+    transaction(data);
+    block(data);
+    script(data);
+    varint(data);
+  }
+
+  /// Test vectors from elsewhere in the library to start mutating from
+  fn seed_corpus() -> Vec<Vec<u8>> {
+    let version = hex_bytes("721101000100000000000000e6e0845300000000010000000000000000000000000000000000ffff0000000000000100000000000000fd87d87eeb4364f22cf54dca59412db7208d47d920cffce83ee8102f5361746f7368693a302e392e39392f2c9f040001").unwrap();
+    let mut envelope = MAGIC_BITCOIN.serialize();
+    envelope.extend(CommandString(String::from_str("version")).serialize().move_iter());
+    envelope.extend(CheckedData(version.clone()).serialize().move_iter());
+
+    vec![version,
+         envelope,
+         genesis_block().serialize(),
+         genesis_tx().serialize(),
+         vec![0xfd, 0x01, 0x02],
+         vec![]]
+  }
+
+  #[test]
+  fn seed_corpus_test() {
+    for seed in seed_corpus().iter() {
+      run_all(seed.as_slice());
+    }
+  }
+
+  #[test]
+  fn mini_fuzz_test() {
+    let mut rng = XorShift { state: 0x2014abcd };
+    for seed in seed_corpus().iter() {
+      for _ in range(0u, 500) {
+        let mut data = seed.clone();
+        // Apply a few random byte changes, truncations and insertions
+        for _ in range(0u, 1 + rng.next(4)) {
+          match rng.next(3) {
+            0 if data.len() > 0 => {
+              let pos = rng.next(data.len());
+              *data.get_mut(pos) ^= 1 + rng.next(255) as u8;
+            }
+            1 if data.len() > 0 => {
+              let len = rng.next(data.len());
+              data.truncate(len);
+            }
+            _ => {
+              let pos = rng.next(data.len() + 1);
+              data.insert(pos, rng.next(256) as u8);
+            }
+          }
+        }
+        run_all(data.as_slice());
+      }
+    }
+  }
+}
+
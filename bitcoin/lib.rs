@@ -56,4 +56,10 @@ mod macros;
 pub mod network;
 pub mod blockdata;
 pub mod util;
+pub mod testing;
+
+// Multiple cfg attributes are or'd, so the mini fuzz runs with the tests
+#[cfg(fuzztarget)]
+#[cfg(test)]
+pub mod fuzztarget;
 
@@ -24,6 +24,10 @@ macro_rules! impl_serializable(
         ret
       }
 
+      fn serialized_len(&self) -> uint {
+        0 $( + self.$field.serialized_len() )+
+      }
+
       fn serialize_iter<'a>(&'a self) -> SerializeIter<'a> {
         SerializeIter {
           data_iter: None,
@@ -52,6 +56,11 @@ macro_rules! impl_serializable_newtype(
         data.serialize()
       }
 
+      fn serialized_len(&self) -> uint {
+        let &$thing(ref data) = self;
+        data.serialized_len()
+      }
+
       fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<$thing> {
         let raw = Serializable::deserialize(iter);
         raw.map(|ok| $thing(ok))
@@ -74,5 +83,23 @@ macro_rules! impl_message(
       }
     }
   );
+  ($thing:ident, $name:expr, $min_version:expr) => (
+    impl $thing {
+      /// Returns a human-readable description of the message
+      fn command() -> String { String::from_str($name) }
+      /// Returns whether a peer speaking protocol version `version`
+      /// understands the message
+      fn is_supported_by_version(version: u32) -> bool { version >= $min_version }
+    }
+
+    impl Message for $thing {
+      fn command(&self) -> String {
+        $thing::command()
+      }
+      fn is_supported_by_version(&self, version: u32) -> bool {
+        $thing::is_supported_by_version(version)
+      }
+    }
+  );
 )
 
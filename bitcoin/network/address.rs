@@ -18,9 +18,23 @@
 //! network addresses in Bitcoin messages.
 //!
 
-use std::io::{IoResult, standard_error, InvalidInput};
+use std::ascii::StrAsciiExt;
+use std::fmt;
+use std::from_str::FromStr;
+use std::io::{IoError, IoResult, standard_error, InvalidInput};
+use std::io::net::ip::IpAddr;
 
+use network::constants::DEFAULT_PORT;
 use network::serialize::Serializable;
+use util::hash::sha3_256;
+use util::misc::{base32_encode, base32_decode};
+#[cfg(test)]
+use util::misc::hex_bytes;
+
+/// Number of base32 characters in a v3 onion hostname, excluding `.onion`
+static ONION_V3_LEN: uint = 56;
+/// Version byte encoded in v3 onion hostnames
+static ONION_V3_VERSION: u8 = 3;
 
 /// A message which can be sent on the Bitcoin network
 pub struct Address {
@@ -60,6 +74,179 @@ impl Serializable for Address {
   }
 }
 
+/// A peer endpoint as written in configuration and logs: an IPv4 address,
+/// bracketed IPv6 address or hostname, optionally followed by `:port`.
+/// A bare IPv6 address is also accepted, but cannot be given a port.
+#[deriving(PartialEq, Clone)]
+pub struct PeerAddr {
+  /// IP address or hostname, without brackets
+  pub host: String,
+  /// Network port
+  pub port: u16
+}
+
+impl PeerAddr {
+  /// Parses a peer endpoint, using `default_port` if none is given
+  pub fn parse(s: &str, default_port: u16) -> IoResult<PeerAddr> {
+    let (host, port_str) =
+      if s.starts_with("[") {
+        let close = match s.find(']') {
+          Some(n) => n,
+          None => { return Err(peer_addr_error(s, String::from_str("missing closing `]`"))); }
+        };
+        let host = s.slice(1, close);
+        if from_str::<IpAddr>(host).is_none() {
+          return Err(peer_addr_error(s, format!("`{:s}` is not an IPv6 address", host)));
+        }
+        let rest = s.slice_from(close + 1);
+        if rest.is_empty() {
+          (host, None)
+        } else if rest.starts_with(":") {
+          (host, Some(rest.slice_from(1)))
+        } else {
+          return Err(peer_addr_error(s, format!("unexpected `{:s}` after `]`", rest)));
+        }
+      } else {
+        match s.find(':') {
+          // Several colons can only be a bare IPv6 address
+          Some(n) if s.slice_from(n + 1).contains_char(':') => {
+            if from_str::<IpAddr>(s).is_none() {
+              return Err(peer_addr_error(s, String::from_str("not an IPv6 address, and a port needs brackets around the address")));
+            }
+            (s, None)
+          }
+          Some(n) => (s.slice_to(n), Some(s.slice_from(n + 1))),
+          None => (s, None)
+        }
+      };
+
+    if host.is_empty() {
+      return Err(peer_addr_error(s, String::from_str("empty host")));
+    }
+    if !host.contains_char(':') && !host.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.') {
+      return Err(peer_addr_error(s, format!("host `{:s}` contains invalid characters", host)));
+    }
+
+    let port = match port_str {
+      None => default_port,
+      Some(p) => match from_str::<u16>(p) {
+        Some(n) => n,
+        None => { return Err(peer_addr_error(s, format!("port `{:s}` is not a number from 0 to 65535", p))); }
+      }
+    };
+    Ok(PeerAddr { host: String::from_str(host), port: port })
+  }
+}
+
+/// Builds the error for an unparseable peer endpoint
+fn peer_addr_error(s: &str, what: String) -> IoError {
+  IoError {
+    kind: InvalidInput,
+    desc: "invalid peer address",
+    detail: Some(format!("{:s}: {:s}", s, what.as_slice()))
+  }
+}
+
+impl FromStr for PeerAddr {
+  fn from_str(s: &str) -> Option<PeerAddr> {
+    PeerAddr::parse(s, DEFAULT_PORT).ok()
+  }
+}
+
+impl fmt::Show for PeerAddr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.host.as_slice().contains_char(':') {
+      write!(f, "[{:s}]:{:}", self.host.as_slice(), self.port)
+    } else {
+      write!(f, "{:s}:{:}", self.host.as_slice(), self.port)
+    }
+  }
+}
+
+/// The address of a Tor v3 hidden service. These do not fit into the 16-byte
+/// encoding used by `Address`, and can only be reached through a SOCKS5 proxy.
+pub struct OnionV3Address {
+  /// The ed25519 public key identifying the service
+  pub pubkey: [u8, ..32],
+  /// Network port
+  pub port: u16
+}
+
+impl OnionV3Address {
+  /// Constructs an address from a `.onion` hostname
+  pub fn from_hostname(host: &str, port: u16) -> IoResult<OnionV3Address> {
+    let pubkey = try!(parse_onion_v3(host));
+    Ok(OnionV3Address { pubkey: pubkey, port: port })
+  }
+
+  /// Returns the `.onion` hostname of the service
+  pub fn hostname(&self) -> String {
+    onion_v3_hostname(&self.pubkey)
+  }
+}
+
+impl fmt::Show for OnionV3Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:s}:{:}", self.hostname().as_slice(), self.port)
+  }
+}
+
+/// Computes the two-byte checksum of a v3 onion public key
+fn onion_v3_checksum(pubkey: &[u8]) -> [u8, ..2] {
+  let mut data = Vec::from_slice(b".onion checksum");
+  data.push_all(pubkey);
+  data.push(ONION_V3_VERSION);
+  let hash = sha3_256(data.as_slice());
+  [hash[0], hash[1]]
+}
+
+/// Checks whether a hostname is a well-formed Tor v3 `.onion` address
+pub fn is_onion_v3(host: &str) -> bool {
+  parse_onion_v3(host).is_ok()
+}
+
+/// Extracts the ed25519 public key from a Tor v3 `.onion` hostname,
+/// checking its version byte and checksum
+pub fn parse_onion_v3(host: &str) -> IoResult<[u8, ..32]> {
+  let lower = host.to_ascii_lower();
+  if !lower.as_slice().ends_with(".onion") || lower.len() != ONION_V3_LEN + 6 {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "not a v3 onion address",
+      detail: Some(format!("{:s} is not {:} base32 characters followed by .onion", host, ONION_V3_LEN))
+    });
+  }
+  // This is 32 bytes of pubkey, 2 bytes of checksum and a version byte
+  let raw = try!(base32_decode(lower.as_slice().slice_to(ONION_V3_LEN)));
+  if *raw.get(34) != ONION_V3_VERSION {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "bad onion address version",
+      detail: Some(format!("version {:} is not {:}", *raw.get(34), ONION_V3_VERSION))
+    });
+  }
+  let mut pubkey = [0u8, ..32];
+  pubkey.copy_from(raw.as_slice().slice_to(32));
+  if onion_v3_checksum(pubkey.as_slice()).as_slice() != raw.as_slice().slice(32, 34) {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "bad onion address checksum",
+      detail: Some(format!("checksum of {:s} did not match", host))
+    });
+  }
+  Ok(pubkey)
+}
+
+/// Encodes an ed25519 public key as a Tor v3 `.onion` hostname
+pub fn onion_v3_hostname(pubkey: &[u8, ..32]) -> String {
+  let mut raw = Vec::from_slice(pubkey.as_slice());
+  raw.push_all(onion_v3_checksum(pubkey.as_slice()).as_slice());
+  raw.push(ONION_V3_VERSION);
+  let mut ret = base32_encode(raw.as_slice());
+  ret.push_str(".onion");
+  ret
+}
+
 #[test]
 fn serialize_address_test() {
   assert!(Address {
@@ -87,5 +274,65 @@ fn deserialize_address_test() {
   assert!(addr.is_err());
 }
 
+#[test]
+fn onion_v3_test() {
+  let tor = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+  let ddg = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+  let pubkey = parse_onion_v3(tor);
+  assert!(pubkey.is_ok());
+  assert_eq!(pubkey.unwrap().as_slice(),
+             hex_bytes("d1b38b83a83b3ed918c5bb69dd444ad56bc8d5835a914de73447474e5f02591b").unwrap().as_slice());
+
+  // Round trip through the address type
+  let addr = OnionV3Address::from_hostname(tor, 8333);
+  assert!(addr.is_ok());
+  let real_addr = addr.unwrap();
+  assert_eq!(real_addr.hostname(), String::from_str(tor));
+  assert_eq!(format!("{}", real_addr), format!("{:s}:8333", tor));
+  assert_eq!(OnionV3Address::from_hostname(ddg, 1).unwrap().hostname(), String::from_str(ddg));
+
+  // Hostnames are case-insensitive
+  assert!(is_onion_v3("DUCKDUCKGOGG42XJOC72X3SJASOWOARFBGCMVFIMAFTT6TWAGSWZCZAD.ONION"));
+  // Corrupted checksum
+  assert!(!is_onion_v3("2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen5awid.onion"));
+  // Not v3 onions
+  assert!(!is_onion_v3("expyuzz4wqqyqhjn.onion"));
+  assert!(!is_onion_v3("2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid"));
+  assert!(!is_onion_v3("bitcoin.org"));
+}
+
+#[test]
+fn peer_addr_test() {
+  let v4 = PeerAddr::parse("1.2.3.4:8333", 18333).unwrap();
+  assert_eq!(v4.host, String::from_str("1.2.3.4"));
+  assert_eq!(v4.port, 8333);
+  assert_eq!(format!("{}", v4), String::from_str("1.2.3.4:8333"));
 
+  let v6 = PeerAddr::parse("[2001:db8::1]:18333", 8333).unwrap();
+  assert_eq!(v6.host, String::from_str("2001:db8::1"));
+  assert_eq!(v6.port, 18333);
+  assert_eq!(format!("{}", v6), String::from_str("[2001:db8::1]:18333"));
+  assert!(PeerAddr::parse("[2001:db8::1]", 8333).unwrap() == PeerAddr { host: String::from_str("2001:db8::1"), port: 8333 });
+  assert!(PeerAddr::parse("2001:db8::1", 8333).unwrap() == PeerAddr { host: String::from_str("2001:db8::1"), port: 8333 });
 
+  // Missing port takes the default
+  let host = PeerAddr::parse("node.example.com", 18333).unwrap();
+  assert_eq!(host.port, 18333);
+  let parsed: Option<PeerAddr> = from_str("node.example.com");
+  assert_eq!(parsed.unwrap().port, DEFAULT_PORT);
+
+  // Out-of-range port
+  let err = PeerAddr::parse("node.example.com:65536", 8333);
+  assert!(err.is_err());
+  assert!(err.unwrap_err().detail.unwrap().as_slice().contains("port `65536`"));
+  assert!(PeerAddr::parse("node.example.com:", 8333).is_err());
+
+  // Malformed hosts
+  assert!(PeerAddr::parse(":8333", 8333).is_err());
+  assert!(PeerAddr::parse("[2001:db8::1:8333", 8333).is_err());
+  assert!(PeerAddr::parse("[node.example.com]:8333", 8333).is_err());
+  assert!(PeerAddr::parse("[2001:db8::1]8333", 8333).is_err());
+  assert!(PeerAddr::parse("2001:db8::1:zz:8333", 8333).is_err());
+  assert!(PeerAddr::parse("node example.com", 8333).is_err());
+}
@@ -18,9 +18,123 @@
 //! protocol, such as protocol versioning and magic header bytes.
 //!
 
+use std::fmt;
+
+/// The networks which this library knows about
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Network {
+  /// The main Bitcoin network
+  Bitcoin,
+  /// Bitcoin's testnet
+  BitcoinTestnet
+}
+
 pub static MAGIC_BITCOIN: u32       = 0xD9B4BEF9;
+pub static DEFAULT_PORT: u16        = 8333;
 
 pub static PROTOCOL_VERSION: u32    = 70001;
 pub static SERVICES: u64            = 0;
 pub static USER_AGENT: &'static str = "bitcoin-rust v0.1";
+/// Longest user agent bitcoind accepts in a `version` message
+pub static MAX_USER_AGENT_LENGTH: uint = 256;
+
+/// Service bit: the node can serve the full block chain. We only store
+/// headers, so `SERVICES` does not include this.
+pub static NODE_NETWORK: u64         = 1 << 0;
+/// Service bit: the node answers `getutxo` requests (BIP 64)
+pub static NODE_GETUTXO: u64         = 1 << 1;
+/// Service bit: the node supports bloom-filtered connections (BIP 111)
+pub static NODE_BLOOM: u64           = 1 << 2;
+/// Service bit: the node can serve witness data (BIP 144)
+pub static NODE_WITNESS: u64         = 1 << 3;
+/// Service bit: the node serves only the last 288 blocks (BIP 159)
+pub static NODE_NETWORK_LIMITED: u64 = 1 << 10;
+
+/// A set of service bits, as given in `version` and `addr` messages
+#[deriving(PartialEq, Eq, Clone)]
+pub struct Services(pub u64);
+
+impl Services {
+  /// Whether every bit of `flag` is set
+  pub fn has(&self, flag: u64) -> bool {
+    let &Services(bits) = self;
+    bits & flag == flag
+  }
+}
+
+impl fmt::Show for Services {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let &Services(bits) = self;
+    if bits == 0 {
+      return write!(f, "NONE");
+    }
+    let names = [(NODE_NETWORK, "NODE_NETWORK"), (NODE_GETUTXO, "NODE_GETUTXO"),
+                 (NODE_BLOOM, "NODE_BLOOM"), (NODE_WITNESS, "NODE_WITNESS"),
+                 (NODE_NETWORK_LIMITED, "NODE_NETWORK_LIMITED")];
+    let mut rest = bits;
+    for &(flag, name) in names.iter() {
+      if rest & flag != 0 {
+        rest &= !flag;
+        try!(write!(f, "{:s}{:s}", name, if rest == 0 { "" } else { "|" }));
+      }
+    }
+    // Bits with no name are shown as a number
+    if rest != 0 {
+      try!(write!(f, "0x{:x}", rest));
+    }
+    Ok(())
+  }
+}
+
+/// Largest payload accepted for a command not listed in `max_payload_size`
+pub static DEFAULT_MAX_PAYLOAD_SIZE: u32 = 65536;
+
+/// Returns the largest payload a well-behaved peer could send with the
+/// given command. Anything bigger is rejected before it is read, so every
+/// new message type should get an entry here.
+pub fn max_payload_size(command: &str) -> u32 {
+  match command {
+    // Empty messages
+    "verack" | "getaddr" | "sendheaders" | "mempool" => 0,
+    // A nonce
+    "ping" | "pong" => 8,
+    // Fixed fields plus a user agent of up to 256 bytes come to about 350
+    "version" => 1024,
+    // 1000 timestamped addresses
+    "addr" => 3 + 1000 * 30,
+    // 50000 inventory vectors
+    "inv" | "getdata" | "notfound" => 3 + 50000 * 36,
+    // Version, a locator of up to 101 hashes and a stop hash
+    "getblocks" | "getheaders" => 4 + 1 + 101 * 32 + 32,
+    // 2000 headers, each with a zero transaction count
+    "headers" => 3 + 2000 * 81,
+    // A block or transaction can be as big as the block weight limit
+    "block" | "tx" => 4000000,
+    _ => DEFAULT_MAX_PAYLOAD_SIZE
+  }
+}
+
+#[test]
+fn max_payload_size_test() {
+  assert_eq!(max_payload_size("verack"), 0);
+  assert_eq!(max_payload_size("ping"), 8);
+  assert_eq!(max_payload_size("inv"), 1800003);
+  assert_eq!(max_payload_size("headers"), 162003);
+  assert_eq!(max_payload_size("block"), 4000000);
+  assert_eq!(max_payload_size("nonsense"), DEFAULT_MAX_PAYLOAD_SIZE);
+}
+
+#[test]
+fn services_test() {
+  let services = Services(NODE_NETWORK | NODE_WITNESS);
+  assert!(services.has(NODE_NETWORK));
+  assert!(services.has(NODE_NETWORK | NODE_WITNESS));
+  assert!(!services.has(NODE_BLOOM));
+  assert!(!services.has(NODE_NETWORK | NODE_BLOOM));
+  assert!(!Services(SERVICES).has(NODE_NETWORK));
 
+  assert_eq!(format!("{:}", services), String::from_str("NODE_NETWORK|NODE_WITNESS"));
+  assert_eq!(format!("{:}", Services(NODE_NETWORK_LIMITED)), String::from_str("NODE_NETWORK_LIMITED"));
+  assert_eq!(format!("{:}", Services(0)), String::from_str("NONE"));
+  assert_eq!(format!("{:}", Services(NODE_BLOOM | 1 << 24)), String::from_str("NODE_BLOOM|0x1000000"));
+}
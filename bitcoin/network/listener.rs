@@ -18,15 +18,35 @@
 //! to connect to a peer, send network messages, and receive Bitcoin data.
 //!
 
-use std::io::{IoResult, standard_error, ConnectionFailed};
+use std::io::{IoResult, standard_error, ConnectionFailed, ConnectionAborted, TimedOut};
 use std::io::timer;
+use std::rand::task_rng;
+use rand::Rng;
+use time::precise_time_ns;
 
 use blockdata::block::{Block, BlockHeader};
-use network::serialize::{Serializable, Message};
-use network::message_network::{VersionAckMessage, PingMessage, PongMessage};
-use network::message_blockdata::{InventoryMessage, Inventory, HeadersMessage};
-use network::socket::Socket;
 use network::constants;
+use network::serialize::Message;
+use network::message_network::{VersionMessage, VersionAckMessage, PingMessage, PongMessage};
+use network::message_blockdata::{InventoryMessage, NotFoundMessage, Inventory, HeadersMessage};
+use network::ratelimit::RateLimiter;
+use network::socket::{Socket, MessageData};
+use network::trace::{Handshake, Misbehaved, ReceiveError, Disconnected};
+use util::timer::{TimerQueue, TimerHandle};
+
+/// How long the peer has to answer our `version` with a `verack`
+static HANDSHAKE_TIMEOUT_NS: u64 = 60 * 1000000000;
+/// How often to ping the peer to check it is still there
+static PING_INTERVAL_NS: u64 = 120 * 1000000000;
+
+/// Things the listener has to do at a set time
+#[deriving(Clone)]
+enum ListenerTimer {
+  /// The handshake has not finished in time
+  HandshakeTimeout,
+  /// It is time to ping the peer
+  SendPing
+}
 
 /// Container for communication channels with the listening thread
 pub struct ListenerChannels {
@@ -36,6 +56,293 @@ pub struct ListenerChannels {
   pub header_rx: Receiver<Option<Box<BlockHeader>>>,
   /// Receiver for new inv messages received by peer
   pub inv_rx: Receiver<Vec<Inventory>>,
+  /// Receiver for inventories which the peer told us it does not have
+  pub notfound_rx: Receiver<Vec<Inventory>>,
+  /// Receiver for the reason the listener gave up on the peer, after
+  /// which it has closed the connection
+  pub disconnect_rx: Receiver<&'static str>,
+}
+
+/// The listening thread's side of the connection
+struct ListenerTask {
+  sock: Socket,
+  block_tx: Sender<Box<Block>>,
+  header_tx: Sender<Option<Box<BlockHeader>>>,
+  inv_tx: Sender<Vec<Inventory>>,
+  notfound_tx: Sender<Vec<Inventory>>,
+  disconnect_tx: Sender<&'static str>,
+  limiter: RateLimiter,
+  handshake_complete: bool,
+  handshake_timer: TimerHandle,
+  /// Nonce and send time of the ping the peer has yet to answer
+  ping_sent: Option<(u64, u64)>,
+  timers: TimerQueue<ListenerTimer>
+}
+
+impl ListenerTask {
+  /// Sets up the task for a socket whose `version` was sent at `now_ns`,
+  /// limiting the peer's messages with `limiter`, along with the channels
+  /// on which the application hears from it
+  fn new(sock: Socket, limiter: RateLimiter, now_ns: u64) -> (ListenerTask, ListenerChannels) {
+    let (block_tx, block_rx) = channel();
+    let (header_tx, header_rx) = channel();
+    let (inv_tx, inv_rx) = channel();
+    let (notfound_tx, notfound_rx) = channel();
+    let (disconnect_tx, disconnect_rx) = channel();
+
+    let mut timers = TimerQueue::new();
+    let handshake_timer = timers.schedule(now_ns, HANDSHAKE_TIMEOUT_NS, HandshakeTimeout);
+    timers.schedule_periodic(now_ns, PING_INTERVAL_NS, SendPing);
+
+    (ListenerTask {
+      sock: sock,
+      block_tx: block_tx,
+      header_tx: header_tx,
+      inv_tx: inv_tx,
+      notfound_tx: notfound_tx,
+      disconnect_tx: disconnect_tx,
+      limiter: limiter,
+      handshake_complete: false,
+      handshake_timer: handshake_timer,
+      ping_sent: None,
+      timers: timers
+    }, ListenerChannels {
+      block_rx: block_rx,
+      header_rx: header_rx,
+      inv_rx: inv_rx,
+      notfound_rx: notfound_rx,
+      disconnect_rx: disconnect_rx,
+    })
+  }
+
+  /// Message loop
+  fn run(&mut self) {
+    loop {
+      let now = precise_time_ns();
+      match self.poll_timers(now) {
+        Some(reason) => {
+          self.disconnect(reason);
+          break;
+        }
+        None => {}
+      }
+      // Receive new message, waking up in time for the next timer
+      let received = match self.timers.next_deadline() {
+        Some(deadline) => self.sock.receive_message_timeout((deadline - now) / 1000000 + 1),
+        None => self.sock.receive_message()
+      };
+      match received {
+        Ok(msg) => {
+          // Drop messages the peer is sending too fast
+          if !self.allow_message(msg.command.as_slice(), precise_time_ns()) {
+            continue;
+          }
+          self.handle_message(&msg);
+        }
+        // A timer is due
+        Err(ref e) if e.kind == TimedOut => {}
+        Err(ref e) if e.kind == ConnectionAborted => {
+          println!("Received error {:} when decoding message, giving up on peer.", e);
+          self.sock.trace_event(ReceiveError(format!("{:}", e)));
+          self.disconnect(e.desc);
+          break;
+        }
+        Err(e) => {
+          println!("Received error {:} when decoding message.", e);
+          self.sock.trace_event(ReceiveError(format!("{:}", e)));
+          timer::sleep(1000);
+        }
+      }
+    }
+  }
+
+  /// Handles the timers due by `now_ns`. Returns the reason to give up
+  /// on the peer, if there is one.
+  fn poll_timers(&mut self, now_ns: u64) -> Option<&'static str> {
+    let mut ret = None;
+    for event in self.timers.poll(now_ns).move_iter() {
+      match event {
+        HandshakeTimeout => {
+          println!("Peer did not complete the handshake in time, giving up on it");
+          self.sock.trace_event(Misbehaved("handshake timed out"));
+          ret = Some("handshake timed out");
+        }
+        SendPing => {
+          let nonce: u64 = task_rng().gen();
+          match self.sock.send_message(&PingMessage { nonce: nonce }) {
+            Err(e) => {
+              println!("Warning: error sending ping: {:}", e);
+            },
+            _ => {
+              self.ping_sent = Some((nonce, precise_time_ns()));
+            }
+          }
+        }
+      }
+    }
+    ret
+  }
+
+  /// Checks a message against the rate limits, noting in the trace if
+  /// the peer is sending it too fast and it should be dropped
+  fn allow_message(&mut self, command: &str, now_ns: u64) -> bool {
+    let allowed = self.limiter.allow(command, now_ns);
+    if !allowed {
+      self.sock.trace_event(Misbehaved("rate limited"));
+    }
+    allowed
+  }
+
+  /// Closes the connection, which the application's socket shares, and
+  /// tells the application why. The trace so far is printed, since it is
+  /// what a bug report about the disconnection would need.
+  fn disconnect(&mut self, reason: &'static str) {
+    self.sock.trace_event(Disconnected(reason));
+    println!("Disconnected from peer: {:s}. Recent protocol events:\n{:s}", reason, self.sock.trace_dump().as_slice());
+    match self.sock.close() {
+      Err(e) => {
+        println!("Warning: error closing connection: {:}", e);
+      },
+      _ => {}
+    }
+    self.disconnect_tx.send(reason);
+  }
+
+  /// Acts on a message received from the peer
+  fn handle_message(&mut self, msg: &MessageData) {
+    let sock = &mut self.sock;
+    match msg.command.as_slice() {
+      "verack" => {
+        if self.handshake_complete {
+          println!("Received second verack (peer is misbehaving)");
+          sock.trace_event(Misbehaved("second verack"));
+        } else {
+          self.handshake_complete = true;
+          self.timers.cancel(self.handshake_timer.clone());
+          sock.trace_event(Handshake("complete"));
+        }
+      }
+      "version" => {
+        // TODO: we should react to the rest of the version data
+        let msg_decode: IoResult<VersionMessage> = msg.decode_typed();
+        match msg_decode {
+          Ok(version) => {
+            sock.set_peer_version(version.version);
+          }
+          Err(e) => {
+            println!("Warning: received error decoding version: {:}", e);
+          }
+        }
+        match sock.send_message(&VersionAckMessage::new()) {
+          Err(e) => {
+            println!("Warning: error sending verack: {:}", e);
+          },
+          _ => {}
+        }
+      }
+      "sendheaders" => {
+        sock.set_prefers_headers(true);
+      }
+      "getaddr" => {
+        // We do not keep an address book, so there is nothing to
+        // offer; not answering is allowed by the protocol.
+      }
+      "inv" => {
+        // TDOO: we should filter the inv message instead of just requesting all the data
+        let msg_decode: IoResult<InventoryMessage> = msg.decode_typed();
+        match msg_decode {
+          Ok(msg) => {
+            // Tranlate inv to getdata
+            let InventoryMessage(data) = msg;
+            self.inv_tx.send(data);
+          }
+          Err(e) => {
+            println!("Warning: received error decoding inv: {:}", e);
+          }
+        }
+      }
+      "notfound" => {
+        let msg_decode: IoResult<NotFoundMessage> = msg.decode_typed();
+        match msg_decode {
+          Ok(msg) => {
+            let NotFoundMessage(data) = msg;
+            self.notfound_tx.send(data);
+          }
+          Err(e) => {
+            println!("Warning: received error decoding notfound: {:}", e);
+          }
+        }
+      }
+      "block" => {
+        let block_decode: IoResult<Block> = msg.decode_typed();
+        match block_decode {
+          Ok(block) => {
+            self.block_tx.send(box block);
+          }
+          Err(e) => {
+            println!("Warning: received error decoding block: {:}", e);
+          }
+        }
+      }
+      "headers" => {
+        let msg_decode: IoResult<HeadersMessage> = msg.decode_typed();
+        match msg_decode {
+          Ok(headers) => {
+            let HeadersMessage(data) = headers;
+            for header in data.move_iter() {
+              // header will be a LoneBlockHeader, which has an extraneous tx_count
+              // field (which is zero anyway). header.header is the actual BlockHeader.
+              self.header_tx.send(Some(box header.header));
+            }
+            self.header_tx.send(None);
+          }
+          Err(e) => {
+            println!("Warning: received error decoding headers: {:}", e);
+          }
+        }
+      }
+      // Ping
+      "ping" => {
+        let msg_decode: IoResult<PingMessage> = msg.decode_typed();
+        match msg_decode {
+          Ok(ping) => {
+            let PingMessage { nonce: nonce } = ping;
+            match sock.send_message(&PongMessage { nonce: nonce }) {
+               Err(e) => {
+                println!("Warning: error sending pong: {:}", e);
+              },
+              _ => {}
+            }
+          }
+          Err(e) => {
+            println!("Warning: received error decoding ping: {:}", e);
+          }
+        }
+      }
+      "pong" => {
+        let msg_decode: IoResult<PongMessage> = msg.decode_typed();
+        match msg_decode {
+          Ok(pong) => {
+            // Only an answer to our latest ping tells us the round-trip time
+            match self.ping_sent {
+              Some((nonce, sent_ns)) if nonce == pong.nonce => {
+                sock.record_ping_latency(precise_time_ns() - sent_ns);
+                self.ping_sent = None;
+              }
+              _ => {}
+            }
+          }
+          Err(e) => {
+            println!("Warning: received error decoding pong: {:}", e);
+          }
+        }
+      }
+      // Unknown message
+      s => {
+        println!("Received unknown message type {:s}", s);
+      }
+    }
+  }
 }
 
 /// A message which can be sent on the Bitcoin network
@@ -44,6 +351,11 @@ pub trait Listener {
   fn peer<'a>(&'a self) -> &'a str;
   /// Return the port we have connected to the peer on
   fn port(&self) -> u16;
+  /// Return the rate limits for the peer's messages, starting at `now_ns`.
+  /// Implementations can change the defaults with `RateLimiter::set_limit`.
+  fn rate_limiter(&self, now_ns: u64) -> RateLimiter {
+    RateLimiter::new(now_ns)
+  }
   /// Main listen loop
   fn start(&self) -> IoResult<(ListenerChannels, Socket)> {
     // Open socket
@@ -54,121 +366,161 @@ pub trait Listener {
     }
     let mut sock = ret_sock.clone();
 
-    let (block_tx, block_rx) = channel();
-    let (header_tx, header_rx) = channel();
-    let (inv_tx, inv_rx) = channel();
-
     // Send version message to peer
     let version_message = try!(sock.version_message(0));
     try!(sock.send_message(&version_message));
 
-    // Message loop
+    let now = precise_time_ns();
+    let (task, channels) = ListenerTask::new(sock, self.rate_limiter(now), now);
     spawn(proc() {
-      let mut handshake_complete = false;
-      let mut sock = sock;
-      loop {
-        // Receive new message
-        match sock.receive_message() {
-          Ok(msg) => {
-            match msg.command.as_slice() {
-              "verack" => {
-                // TODO: when the timeout stuff in std::io::net::tcp is sorted out we should
-                // actually time out if the verack doesn't come in in time
-                if handshake_complete {
-                  println!("Received second verack (peer is misbehaving)");
-                } else {
-                  handshake_complete = true;
-                }
-              }
-              "version" => {
-                // TODO: we should react to the version data
-                match sock.send_message(&VersionAckMessage::new()) {
-                  Err(e) => {
-                    println!("Warning: error sending verack: {:}", e);
-                  },
-                  _ => {}
-                }
-              }
-              "inv" => {
-                // TDOO: we should filter the inv message instead of just requesting all the data
-                let msg_decode: IoResult<InventoryMessage> = Serializable::deserialize(msg.data.iter().map(|n| *n));
-                match msg_decode {
-                  Ok(msg) => {
-                    // Tranlate inv to getdata
-                    let InventoryMessage(data) = msg;
-                    inv_tx.send(data);
-                  }
-                  Err(e) => {
-                    println!("Warning: received error decoding inv: {:}", e);
-                  }
-                }
-              }
-              "block" => {
-                let block_decode: IoResult<Block> = Serializable::deserialize(msg.data.iter().map(|n| *n));
-                match block_decode {
-                  Ok(block) => {
-                    block_tx.send(box block);
-                  }
-                  Err(e) => {
-                    println!("Warning: received error decoding block: {:}", e);
-                  }
-                }
-              }
-              "headers" => {
-                let msg_decode: IoResult<HeadersMessage> = Serializable::deserialize(msg.data.iter().map(|n| *n));
-                match msg_decode {
-                  Ok(headers) => {
-                    let HeadersMessage(data) = headers;
-                    for header in data.move_iter() {
-                      // header will be a LoneBlockHeader, which has an extraneous tx_count
-                      // field (which is zero anyway). header.header is the actual BlockHeader.
-                      header_tx.send(Some(box header.header));
-                    }
-                    header_tx.send(None);
-                  }
-                  Err(e) => {
-                    println!("Warning: received error decoding headers: {:}", e);
-                  }
-                }
-              }
-              // Ping
-              "ping" => {
-                let msg_decode: IoResult<PingMessage> = Serializable::deserialize(msg.data.iter().map(|n| *n));
-                match msg_decode {
-                  Ok(ping) => {
-                    let PingMessage { nonce: nonce } = ping;
-                    match sock.send_message(&PongMessage { nonce: nonce }) {
-                       Err(e) => {
-                        println!("Warning: error sending pong: {:}", e);
-                      },
-                      _ => {}
-                    }
-                  }
-                  Err(e) => {
-                    println!("Warning: received error decoding ping: {:}", e);
-                  }
-                }
-              }
-              // Unknown message
-              s => {
-                println!("Received unknown message type {:s}", s);
-              }
-            }
-          }
-          Err(e) => {
-            println!("Received error {:} when decoding message.", e);
-            timer::sleep(1000);
-          }
-        }
-      }
+      let mut task = task;
+      task.run();
     });
-    Ok((ListenerChannels {
-      block_rx: block_rx,
-      header_rx: header_rx,
-      inv_rx: inv_rx,
-    }, ret_sock))
+    Ok((channels, ret_sock))
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+  use std::io::{Acceptor, Listener};
+  use std::io::net::tcp::TcpListener;
+  use time::precise_time_ns;
+
+  use network::constants;
+  use network::message_network::{MempoolMessage, PongMessage};
+  use network::message_blockdata::{NotFoundMessage, Inventory, InvBlock};
+  use network::listener::{ListenerTask, HANDSHAKE_TIMEOUT_NS, PING_INTERVAL_NS};
+  use network::ratelimit::RateLimiter;
+  use network::serialize::{Serializable, CommandString, CheckedData};
+  use network::socket::{Socket, MessageData};
+  use util::hash::Sha256dHash;
+  use util::misc::hex_bytes;
+
+  // A `version` message from a peer speaking protocol version 60001
+  static OLD_VERSION_HEX: &'static str = "61ea00000100000000000000e6e0845300000000010000000000000000000000000000000000ffff0000000000000100000000000000fd87d87eeb4364f22cf54dca59412db7208d47d920cffce83ee8102f5361746f7368693a302e392e39392f2c9f040001";
+
+  #[test]
+  fn version_negotiation_test() {
+    // The application keeps the original socket and the listener a clone
+    let mut app_sock = Socket::new(constants::MAGIC_BITCOIN);
+    let (mut task, _channels) = ListenerTask::new(app_sock.clone(), RateLimiter::new(0), 0);
+
+    let version = MessageData {
+      command: String::from_str("version"),
+      data: hex_bytes(OLD_VERSION_HEX).unwrap()
+    };
+    task.handle_message(&version);
+    assert_eq!(task.sock.negotiated_version(), 60001);
+    assert_eq!(app_sock.negotiated_version(), 60001);
+
+    // So the application no longer sends the peer messages it is too old for
+    let err = app_sock.send_message(&MempoolMessage::new()).unwrap_err();
+    assert_eq!(err.desc, "peer protocol version too low");
+  }
+
+  #[test]
+  fn notfound_test() {
+    let (mut task, channels) = ListenerTask::new(Socket::new(constants::MAGIC_BITCOIN), RateLimiter::new(0), 0);
+    let hash = Sha256dHash::from_data(b"missing block");
+    let notfound = NotFoundMessage(vec![Inventory { inv_type: InvBlock, hash: hash.clone() }]);
+    task.handle_message(&MessageData::from_typed(&notfound));
 
+    let data = channels.notfound_rx.recv();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data.get(0).inv_type, InvBlock);
+    assert!(data.get(0).hash == hash);
+    // Nothing goes to the other channels
+    assert!(channels.inv_rx.try_recv().is_err());
 
+    // A malformed notfound is dropped
+    task.handle_message(&MessageData { command: String::from_str("notfound"), data: vec![1, 2] });
+    assert!(channels.notfound_rx.try_recv().is_err());
+  }
+
+  #[test]
+  fn handshake_timeout_test() {
+    let (mut task, channels) = ListenerTask::new(Socket::new(constants::MAGIC_BITCOIN), RateLimiter::new(0), 0);
+    assert_eq!(task.poll_timers(HANDSHAKE_TIMEOUT_NS - 1), None);
+    assert_eq!(task.poll_timers(HANDSHAKE_TIMEOUT_NS), Some("handshake timed out"));
+    task.disconnect("handshake timed out");
+    assert_eq!(channels.disconnect_rx.recv(), "handshake timed out");
+
+    // Once the peer has sent its verack, the handshake cannot time out
+    let (mut task, _channels) = ListenerTask::new(Socket::new(constants::MAGIC_BITCOIN), RateLimiter::new(0), 0);
+    task.handle_message(&MessageData { command: String::from_str("verack"), data: vec![] });
+    assert_eq!(task.poll_timers(HANDSHAKE_TIMEOUT_NS), None);
+    // Only the ping timer is left, for the next ping
+    assert_eq!(task.timers.len(), 1);
+    assert_eq!(task.timers.next_deadline(), Some(PING_INTERVAL_NS));
+  }
+
+  #[test]
+  fn rate_limit_test() {
+    // The limiter is the caller's, so its limits can be changed
+    let mut limiter = RateLimiter::new(0);
+    limiter.set_limit("inv", 1.0, 0.0, 0);
+    let app_sock = Socket::new(constants::MAGIC_BITCOIN);
+    let (mut task, _channels) = ListenerTask::new(app_sock.clone(), limiter, 0);
+
+    assert!(task.allow_message("inv", 0));
+    assert!(!app_sock.trace_dump().as_slice().contains("rate limited"));
+    assert!(!task.allow_message("inv", 0));
+    assert_eq!(task.limiter.dropped, 1);
+    assert!(app_sock.trace_dump().as_slice().contains("misbehaved: rate limited"));
+    // Commands without a limit are not affected
+    assert!(task.allow_message("headers", 0));
+  }
+
+  #[test]
+  fn ping_latency_test() {
+    let app_sock = Socket::new(constants::MAGIC_BITCOIN);
+    let (mut task, _channels) = ListenerTask::new(app_sock.clone(), RateLimiter::new(0), 0);
+    // With no connection the keepalive ping is not sent, so not waited for
+    task.poll_timers(PING_INTERVAL_NS);
+    assert!(task.ping_sent.is_none());
+
+    task.ping_sent = Some((42, precise_time_ns()));
+    // A pong with the wrong nonce is not an answer
+    task.handle_message(&MessageData::from_typed(&PongMessage { nonce: 41 }));
+    assert!(app_sock.last_ping_latency().is_none());
+    assert!(task.ping_sent.is_some());
+    // The right one is, and the application's socket sees the latency
+    task.handle_message(&MessageData::from_typed(&PongMessage { nonce: 42 }));
+    assert!(app_sock.last_ping_latency().is_some());
+    assert!(app_sock.last_ping_latency().unwrap() < 1000000000);
+    assert!(task.ping_sent.is_none());
+  }
+
+  #[test]
+  fn oversized_payload_test() {
+    let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+    let port = listener.socket_name().unwrap().port;
+    let acceptor = listener.listen();
+    let (done_tx, done_rx) = channel();
+
+    // Fake peer which sends a ping with one byte too many, then stays
+    // connected until we are done
+    spawn(proc() {
+      let mut acceptor = acceptor;
+      let mut stream = acceptor.accept().unwrap();
+      let mut raw = constants::MAGIC_BITCOIN.serialize();
+      raw.extend(CommandString(String::from_str("ping")).serialize().move_iter());
+      raw.extend(CheckedData(vec![0u8, ..9]).serialize().move_iter());
+      stream.write(raw.as_slice()).unwrap();
+      done_rx.recv();
+    });
+
+    let mut app_sock = Socket::new(constants::MAGIC_BITCOIN);
+    app_sock.connect("127.0.0.1", port).unwrap();
+    let now = precise_time_ns();
+    let (mut task, channels) = ListenerTask::new(app_sock.clone(), RateLimiter::new(now), now);
+    // The rest of the ping is still on the stream, so the listener gives up
+    // rather than trying to read another message from it
+    task.run();
+    assert_eq!(channels.disconnect_rx.recv(), "oversized payload");
+    assert!(app_sock.trace_dump().as_slice().contains("misbehaved: oversized payload"));
+    assert!(app_sock.trace_dump().as_slice().contains("disconnected: oversized payload"));
+    done_tx.send(());
+  }
+}
@@ -19,6 +19,8 @@
 //!
 
 use std::io::{IoResult, IoError, InvalidInput};
+use std::mem;
+use collections::treemap::TreeSet;
 #[cfg(test)]
 use serialize::hex::FromHex;
 #[cfg(test)]
@@ -135,6 +137,8 @@ impl Serializable for Inventory {
     rv
   }
 
+  fn serialized_len(&self) -> uint { 36 }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<Inventory> {
     let int_type: u32 = try!(Serializable::deserialize(iter.by_ref()));
     Ok(Inventory {
@@ -153,6 +157,44 @@ impl Serializable for Inventory {
   }
 }
 
+/// Removes repeated inventories (same type and hash) from `items`,
+/// keeping the first of each in its place
+fn dedup_inventory(items: &mut Vec<Inventory>) {
+  let mut seen = TreeSet::new();
+  let old = mem::replace(items, vec![]);
+  for item in old.move_iter() {
+    // The encoding is exactly the type and hash
+    if seen.insert(item.serialize()) {
+      items.push(item);
+    }
+  }
+}
+
+impl InventoryMessage {
+  /// Removes repeated inventories, keeping the first of each
+  pub fn deduplicate(&mut self) {
+    let &InventoryMessage(ref mut items) = self;
+    dedup_inventory(items);
+  }
+
+  /// Splits the message into messages of at most `max_items` inventories
+  /// each, in the same order. `max_items` must not be zero.
+  pub fn split_chunks(&self, max_items: uint) -> Vec<InventoryMessage> {
+    let &InventoryMessage(ref items) = self;
+    items.as_slice().chunks(max_items).map(|chunk| {
+      InventoryMessage(chunk.iter().map(|item| Inventory { inv_type: item.inv_type, hash: item.hash }).collect())
+    }).collect()
+  }
+}
+
+impl GetDataMessage {
+  /// Removes repeated inventories, keeping the first of each
+  pub fn deduplicate(&mut self) {
+    let &GetDataMessage(ref mut items) = self;
+    dedup_inventory(items);
+  }
+}
+
 impl_serializable_newtype!(InventoryMessage, Vec<Inventory>)
 impl_message!(InventoryMessage, "inv")
 
@@ -160,11 +202,27 @@ impl_serializable_newtype!(GetDataMessage, Vec<Inventory>)
 impl_message!(GetDataMessage, "getdata")
 
 impl_serializable_newtype!(NotFoundMessage, Vec<Inventory>)
-impl_message!(NotFoundMessage, "notfound")
+impl_message!(NotFoundMessage, "notfound", 70001)
 
 impl_serializable_newtype!(HeadersMessage, Vec<LoneBlockHeader>)
 impl_message!(HeadersMessage, "headers")
 
+/// The `sendheaders` message, which asks that new blocks be announced
+/// with `headers` rather than `inv`
+pub struct SendHeadersMessage;
+
+impl SendHeadersMessage {
+  /// Constructs a new `sendheaders` message
+  pub fn new() -> SendHeadersMessage { SendHeadersMessage }
+}
+
+impl_message!(SendHeadersMessage, "sendheaders")
+
+impl Serializable for SendHeadersMessage {
+  fn serialize(&self) -> Vec<u8> { vec![] }
+  fn deserialize<I: Iterator<u8>>(_: I) -> IoResult<SendHeadersMessage> { Ok(SendHeadersMessage) }
+}
+
 #[test]
 fn getblocks_message_test() {
   let from_sat = "72110100014a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b0000000000000000000000000000000000000000000000000000000000000000".from_hex().unwrap();
@@ -242,3 +300,54 @@ fn inv_message_test() {
   assert_eq!(reserialize3.as_slice(), first_20.as_slice());
 }
 
+#[cfg(test)]
+fn inventory(inv_type: InvType, hash_byte: u8) -> Inventory {
+  let hash: IoResult<Sha256dHash> = Serializable::deserialize([hash_byte, ..32].iter().map(|n| *n));
+  Inventory { inv_type: inv_type, hash: hash.unwrap() }
+}
+
+#[cfg(test)]
+fn inventory_bytes(items: &Vec<Inventory>) -> Vec<(u8, u8)> {
+  items.iter().map(|item| {
+    let raw = item.serialize();
+    (*raw.get(0), *raw.get(4))
+  }).collect()
+}
+
+#[test]
+fn inventory_deduplicate_test() {
+  let mut inv = InventoryMessage(vec![inventory(InvBlock, 1), inventory(InvTransaction, 2),
+                                      inventory(InvBlock, 1), inventory(InvTransaction, 1),
+                                      inventory(InvTransaction, 2), inventory(InvBlock, 3)]);
+  inv.deduplicate();
+  // Exact repeats go, but the same hash with another type stays
+  let InventoryMessage(ref items) = inv;
+  assert_eq!(inventory_bytes(items), vec![(2, 1), (1, 2), (1, 1), (2, 3)]);
+
+  let mut getdata = GetDataMessage(vec![inventory(InvTransaction, 7), inventory(InvTransaction, 7)]);
+  getdata.deduplicate();
+  let GetDataMessage(ref items) = getdata;
+  assert_eq!(inventory_bytes(items), vec![(1, 7)]);
+
+  let mut empty = GetDataMessage(vec![]);
+  empty.deduplicate();
+  let GetDataMessage(ref items) = empty;
+  assert_eq!(items.len(), 0);
+}
+
+#[test]
+fn inventory_split_chunks_test() {
+  let inv = InventoryMessage(range(0u8, 5).map(|n| inventory(InvBlock, n)).collect());
+  let chunks = inv.split_chunks(2);
+  assert_eq!(chunks.len(), 3);
+  let contents: Vec<Vec<(u8, u8)>> = chunks.iter().map(|chunk| {
+    let &InventoryMessage(ref items) = chunk;
+    inventory_bytes(items)
+  }).collect();
+  assert_eq!(contents, vec![vec![(2, 0), (2, 1)], vec![(2, 2), (2, 3)], vec![(2, 4)]]);
+
+  // A limit at least the size of the message gives it back whole
+  assert_eq!(inv.split_chunks(5).len(), 1);
+  assert_eq!(inv.split_chunks(50000).len(), 1);
+  assert_eq!(InventoryMessage(vec![]).split_chunks(10).len(), 0);
+}
@@ -18,7 +18,7 @@
 //! capabilities
 //!
 
-use std::io::IoResult;
+use std::io::{IoResult, IoError, InvalidInput};
 #[cfg(test)]
 use serialize::hex::FromHex;
 
@@ -57,6 +57,13 @@ pub struct VersionMessage {
 /// The `verack` message
 pub struct VersionAckMessage;
 
+/// The `getaddr` message
+pub struct GetAddrMessage;
+
+/// The `mempool` message, asking the peer to announce the transactions
+/// in its memory pool
+pub struct MempoolMessage;
+
 impl VersionMessage {
   // TODO: we have fixed services and relay to 0
   /// Constructs a new `version` message
@@ -72,6 +79,7 @@ impl VersionMessage {
       Err(e) => { return Err(e); }
       _ => {}
     }
+    try!(validate_user_agent(socket.user_agent.as_slice()));
     Ok(VersionMessage {
       version: constants::PROTOCOL_VERSION,
       services: socket.services,
@@ -86,6 +94,28 @@ impl VersionMessage {
   }
 }
 
+/// Checks that a user agent is printable ASCII and no longer than bitcoind
+/// allows
+fn validate_user_agent(user_agent: &str) -> IoResult<()> {
+  if !user_agent.chars().all(|c| c >= ' ' && c <= '~') {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "user agent is not printable ASCII",
+      detail: Some(format!("user agent {:}", user_agent.escape_default()))
+    });
+  }
+  // Now that it is ASCII, its length in bytes is its length on the wire
+  if user_agent.len() > constants::MAX_USER_AGENT_LENGTH {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "oversized user agent",
+      detail: Some(format!("user agent is {:} bytes, more than {:} allowed",
+                           user_agent.len(), constants::MAX_USER_AGENT_LENGTH))
+    });
+  }
+  Ok(())
+}
+
 impl_message!(VersionMessage, "version")
 
 /// The `ping` message
@@ -102,7 +132,7 @@ pub struct PongMessage {
   pub nonce: u64
 }
 impl_serializable!(PongMessage, nonce)
-impl_message!(PongMessage, "pong")
+impl_message!(PongMessage, "pong", 60001)
 
 impl Serializable for VersionMessage {
   fn serialize(&self) -> Vec<u8> {
@@ -122,7 +152,7 @@ impl Serializable for VersionMessage {
   }
 
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<VersionMessage> {
-    Ok(VersionMessage {
+    let ret = VersionMessage {
       version: try!(Serializable::deserialize(iter.by_ref())),
       services: try!(Serializable::deserialize(iter.by_ref())),
       timestamp: try!(Serializable::deserialize(iter.by_ref())),
@@ -132,7 +162,9 @@ impl Serializable for VersionMessage {
       user_agent: try!(Serializable::deserialize(iter.by_ref())),
       start_height: try!(Serializable::deserialize(iter.by_ref())),
       relay: try!(Serializable::deserialize(iter.by_ref()))
-    })
+    };
+    try!(validate_user_agent(ret.user_agent.as_slice()));
+    Ok(ret)
   }
 }
 
@@ -148,6 +180,30 @@ impl Serializable for VersionAckMessage {
   fn deserialize<I: Iterator<u8>>(_: I) -> IoResult<VersionAckMessage> { Ok(VersionAckMessage) }
 }
 
+impl GetAddrMessage {
+  /// Constructs a new `getaddr` message
+  pub fn new() -> GetAddrMessage { GetAddrMessage }
+}
+
+impl_message!(GetAddrMessage, "getaddr")
+
+impl Serializable for GetAddrMessage {
+  fn serialize(&self) -> Vec<u8> { vec![] }
+  fn deserialize<I: Iterator<u8>>(_: I) -> IoResult<GetAddrMessage> { Ok(GetAddrMessage) }
+}
+
+impl MempoolMessage {
+  /// Constructs a new `mempool` message
+  pub fn new() -> MempoolMessage { MempoolMessage }
+}
+
+impl_message!(MempoolMessage, "mempool", 60002)
+
+impl Serializable for MempoolMessage {
+  fn serialize(&self) -> Vec<u8> { vec![] }
+  fn deserialize<I: Iterator<u8>>(_: I) -> IoResult<MempoolMessage> { Ok(MempoolMessage) }
+}
+
 #[test]
 fn version_message_test() {
   // This message is from my satoshi node, morning of May 27 2014
@@ -169,5 +225,40 @@ fn version_message_test() {
   assert_eq!(reserialize.as_slice(), from_sat.as_slice());
 }
 
+#[test]
+fn version_message_user_agent_test() {
+  let from_sat = "721101000100000000000000e6e0845300000000010000000000000000000000000000000000ffff0000000000000100000000000000fd87d87eeb4364f22cf54dca59412db7208d47d920cffce83ee8102f5361746f7368693a302e392e39392f2c9f040001".from_hex().unwrap();
+  let mut msg: VersionMessage = Serializable::deserialize(from_sat.iter().map(|n| *n)).unwrap();
+
+  // 256 bytes is fine, 257 is not
+  msg.user_agent = String::from_char(256, 'a');
+  let decode: IoResult<VersionMessage> = Serializable::deserialize(msg.serialize().move_iter());
+  assert_eq!(decode.unwrap().user_agent.len(), 256);
+  msg.user_agent = String::from_char(257, 'a');
+  let decode: IoResult<VersionMessage> = Serializable::deserialize(msg.serialize().move_iter());
+  let err = decode.err().unwrap();
+  assert_eq!(err.desc, "oversized user agent");
+  assert_eq!(err.detail, Some(String::from_str("user agent is 257 bytes, more than 256 allowed")));
+
+  // Control characters and non-ASCII bytes are rejected
+  msg.user_agent = String::from_str("/Satoshi:0.9.99/\n");
+  let decode: IoResult<VersionMessage> = Serializable::deserialize(msg.serialize().move_iter());
+  assert_eq!(decode.err().unwrap().desc, "user agent is not printable ASCII");
+  let mut raw = from_sat.clone();
+  *raw.get_mut(81) = 0xC3;
+  let decode: IoResult<VersionMessage> = Serializable::deserialize(raw.move_iter());
+  assert_eq!(decode.err().unwrap().desc, "user agent is not printable ASCII");
+}
+
+#[test]
+fn getaddr_message_test() {
+  assert_eq!(GetAddrMessage::new().serialize(), vec![]);
+  assert_eq!(GetAddrMessage::new().command(), String::from_str("getaddr"));
+
+  let empty: Vec<u8> = vec![];
+  let decode: IoResult<GetAddrMessage> = Serializable::deserialize(empty.iter().map(|n| *n));
+  assert!(decode.is_ok());
+}
+
 
 
@@ -24,6 +24,8 @@ pub mod serialize;
 
 pub mod address;
 pub mod listener;
+pub mod ratelimit;
+pub mod trace;
 pub mod message_blockdata;
 pub mod message_network;
 
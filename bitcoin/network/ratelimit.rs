@@ -0,0 +1,178 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Rate limiting
+//!
+//! Token buckets which limit how often a peer may send each kind of
+//! message, so that a flood of cheap messages cannot tie up the listener.
+//! Time is passed in explicitly, in nanoseconds, rather than read from a
+//! clock.
+//!
+
+/// A bucket which fills with tokens at a steady rate up to some capacity.
+/// Each allowed event takes one token.
+pub struct TokenBucket {
+  capacity: f64,
+  per_second: f64,
+  tokens: f64,
+  last_update_ns: u64
+}
+
+impl TokenBucket {
+  /// Constructs a full bucket holding up to `capacity` tokens, which
+  /// refills at `per_second` tokens per second
+  pub fn new(capacity: f64, per_second: f64, now_ns: u64) -> TokenBucket {
+    TokenBucket {
+      capacity: capacity,
+      per_second: per_second,
+      tokens: capacity,
+      last_update_ns: now_ns
+    }
+  }
+
+  /// Takes a token if one is available, returning whether it was
+  pub fn consume(&mut self, now_ns: u64) -> bool {
+    if now_ns > self.last_update_ns {
+      let elapsed = (now_ns - self.last_update_ns) as f64 / 1000000000.0;
+      self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+      self.last_update_ns = now_ns;
+    }
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+/// Per-command rate limits for a single peer. Commands without a limit
+/// are always allowed.
+pub struct RateLimiter {
+  commands: Vec<String>,
+  /// The bucket for each entry in `commands`
+  buckets: Vec<TokenBucket>,
+  /// Number of messages which have been refused
+  pub dropped: uint
+}
+
+impl RateLimiter {
+  /// Constructs a limiter with default limits, which are generous enough
+  /// that no well-behaved peer should hit them. `getaddr` is allowed only
+  /// once per connection.
+  pub fn new(now_ns: u64) -> RateLimiter {
+    let mut ret = RateLimiter { commands: vec![], buckets: vec![], dropped: 0 };
+    ret.set_limit("ping", 20.0, 1.0, now_ns);
+    ret.set_limit("inv", 1000.0, 100.0, now_ns);
+    ret.set_limit("getaddr", 1.0, 0.0, now_ns);
+    ret
+  }
+
+  /// Limits `command` to bursts of `capacity` messages, refilling at
+  /// `per_second` messages per second, replacing any existing limit
+  pub fn set_limit(&mut self, command: &str, capacity: f64, per_second: f64, now_ns: u64) {
+    let bucket = TokenBucket::new(capacity, per_second, now_ns);
+    match self.commands.iter().position(|name| name.as_slice() == command) {
+      Some(i) => { *self.buckets.get_mut(i) = bucket; }
+      None => {
+        self.commands.push(String::from_str(command));
+        self.buckets.push(bucket);
+      }
+    }
+  }
+
+  /// Checks whether a message with the given command should be processed,
+  /// counting it as dropped if not
+  pub fn allow(&mut self, command: &str, now_ns: u64) -> bool {
+    match self.commands.iter().position(|name| name.as_slice() == command) {
+      Some(i) => {
+        let allowed = self.buckets.get_mut(i).consume(now_ns);
+        if !allowed {
+          self.dropped += 1;
+        }
+        allowed
+      }
+      None => true
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use network::ratelimit::{TokenBucket, RateLimiter};
+
+  static SECOND: u64 = 1000000000;
+
+  #[test]
+  fn token_bucket_test() {
+    let mut bucket = TokenBucket::new(3.0, 2.0, 0);
+    assert!(bucket.consume(0));
+    assert!(bucket.consume(0));
+    assert!(bucket.consume(0));
+    assert!(!bucket.consume(0));
+    // Half a second buys one more token
+    assert!(bucket.consume(SECOND / 2));
+    assert!(!bucket.consume(SECOND / 2));
+    // A long wait only refills up to capacity
+    for _ in range(0u, 3) {
+      assert!(bucket.consume(100 * SECOND));
+    }
+    assert!(!bucket.consume(100 * SECOND));
+    // Time going backwards does not add tokens
+    assert!(!bucket.consume(50 * SECOND));
+  }
+
+  #[test]
+  fn rate_limiter_test() {
+    let mut limiter = RateLimiter::new(0);
+
+    // A burst of 100 pings: the first 20 get through
+    let mut allowed = 0u;
+    for _ in range(0u, 100) {
+      if limiter.allow("ping", 0) {
+        allowed += 1;
+      }
+    }
+    assert_eq!(allowed, 20);
+    assert_eq!(limiter.dropped, 80);
+    // After ten seconds another ten are allowed
+    let mut allowed = 0u;
+    for _ in range(0u, 100) {
+      if limiter.allow("ping", 10 * SECOND) {
+        allowed += 1;
+      }
+    }
+    assert_eq!(allowed, 10);
+    assert_eq!(limiter.dropped, 170);
+
+    // getaddr is answered once, however long we wait
+    assert!(limiter.allow("getaddr", 0));
+    assert!(!limiter.allow("getaddr", SECOND));
+    assert!(!limiter.allow("getaddr", 1000 * SECOND));
+
+    // Commands without limits always get through
+    for _ in range(0u, 1000) {
+      assert!(limiter.allow("headers", 0));
+    }
+    assert_eq!(limiter.dropped, 172);
+
+    // Limits can be changed
+    limiter.set_limit("headers", 1.0, 0.0, 0);
+    assert!(limiter.allow("headers", 0));
+    assert!(!limiter.allow("headers", 0));
+  }
+}
+
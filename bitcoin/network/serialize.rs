@@ -106,6 +106,11 @@ pub trait Serializable {
   }
   /// Read an object off the wire
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<Self>;
+  /// Returns the length of the serialization, which types override when
+  /// they can work it out without serializing
+  fn serialized_len(&self) -> uint {
+    self.serialize().len()
+  }
   /// Obtain a hash of the object
   fn hash(&self) -> Sha256dHash {
     Sha256dHash::from_data(self.serialize().as_slice())
@@ -149,6 +154,9 @@ pub trait Serializable {
 pub trait Message : Serializable {
   /// Returns the name of the message as encoded in the network header
   fn command(&self) -> String;
+  /// Returns whether a peer speaking protocol version `version` understands
+  /// the message. Messages which have always existed are understood by all.
+  fn is_supported_by_version(&self, _version: u32) -> bool { true }
 }
 
 /// A variable-length unsigned integer
@@ -184,6 +192,33 @@ pub fn varint_to_u64(n: VarInt) -> u64 {
   }
 }
 
+/// Reads `n_elems` elements of a vector whose count has already been read
+fn deserialize_elements<T: Serializable, I: Iterator<u8>>(mut iter: I, n_elems: u64) -> IoResult<Vec<T>> {
+  let mut n_elems = n_elems;
+  let mut v: Vec<T> = vec![];
+  while n_elems > 0 {
+    v.push(try!(Serializable::deserialize(iter.by_ref())));
+    n_elems -= 1;
+  }
+  Ok(v)
+}
+
+/// Deserializes a vector as `Vec<T>` does, but rejects a count of more than
+/// `max` before reading any elements, so that a crafted count fails at once
+/// rather than after reading everything that follows. `what` names the
+/// elements in the error.
+pub fn deserialize_bounded_vec<T: Serializable, I: Iterator<u8>>(mut iter: I, max: u64, what: &str) -> IoResult<Vec<T>> {
+  let n_elems = varint_to_u64(try!(Serializable::deserialize(iter.by_ref())));
+  if n_elems > max {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "too many elements",
+      detail: Some(format!("{:u} {:s}, more than the {:u} allowed", n_elems, what, max))
+    });
+  }
+  deserialize_elements(iter, n_elems)
+}
+
 fn read_uint_le<I: Iterator<u8>>(mut iter: FixedTake<I>) -> Option<u64> {
   let (rv, _) = iter.fold((0u64, 1u64), |(old, mult), next| (old + next as u64 * mult, mult * 0x100));
   match iter.is_err() {
@@ -204,6 +239,8 @@ impl Serializable for bool {
     if *self { Vec::from_slice(&[1u8]) } else { Vec::from_slice(&[0u8]) }
   }
 
+  fn serialized_len(&self) -> uint { 1 }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<bool> {
     match iter.next() {
       Some(u) => Ok(u != 0),
@@ -217,6 +254,8 @@ impl Serializable for u8 {
     Vec::from_slice(&[*self])
   }
 
+  fn serialized_len(&self) -> uint { 1 }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<u8> {
     match iter.next() {
       Some(u) => Ok(u as u8),
@@ -230,6 +269,8 @@ impl Serializable for u16 {
     unsafe { Vec::from_slice(transmute::<_, [u8, ..2]>(self.to_le())) }
   }
 
+  fn serialized_len(&self) -> uint { 2 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<u16> {
     match read_uint_le(iter.fixed_take(2)) {
       Some(u) => Ok(u as u16),
@@ -243,6 +284,8 @@ impl Serializable for u32 {
     unsafe { Vec::from_slice(transmute::<_, [u8, ..4]>(self.to_le())) }
   }
 
+  fn serialized_len(&self) -> uint { 4 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<u32> {
     match read_uint_le(iter.fixed_take(4)) {
       Some(u) => Ok(u as u32),
@@ -256,6 +299,8 @@ impl Serializable for i32 {
     unsafe { Vec::from_slice(transmute::<_, [u8, ..4]>(self.to_le())) }
   }
 
+  fn serialized_len(&self) -> uint { 4 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<i32> {
     match read_uint_le(iter.fixed_take(4)) {
       Some(u) => Ok(u as i32),
@@ -269,6 +314,8 @@ impl Serializable for u64 {
     unsafe { Vec::from_slice(transmute::<_, [u8, ..8]>(self.to_le())) }
   }
 
+  fn serialized_len(&self) -> uint { 8 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<u64> {
     match read_uint_le(iter.fixed_take(8)) {
       Some(u) => Ok(u as u64),
@@ -282,6 +329,8 @@ impl Serializable for i64 {
     unsafe { Vec::from_slice(transmute::<_, [u8, ..8]>(self.to_le())) }
   }
 
+  fn serialized_len(&self) -> uint { 8 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<i64> {
     match read_uint_le(iter.fixed_take(8)) {
       Some(u) => Ok(u as i64),
@@ -300,6 +349,15 @@ impl Serializable for VarInt {
     }
   }
 
+  fn serialized_len(&self) -> uint {
+    match *self {
+      VarU8(_)  => 1,
+      VarU16(_) => 3,
+      VarU32(_) => 5,
+      VarU64(_) => 9
+    }
+  }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<VarInt> {
     match iter.next() {
       Some(n) if n < 0xFD => Ok(VarU8(n)),
@@ -319,6 +377,8 @@ macro_rules! serialize_fixvec(
           Vec::from_slice(self.as_slice())
         }
 
+        fn serialized_len(&self) -> uint { $size }
+
         fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<[u8, ..$size]> {
           let mut v = [0u8, ..$size];
           let mut fixiter = iter.fixed_take($size);
@@ -341,6 +401,7 @@ macro_rules! serialize_fixvec(
         let vec = [5u8, ..$size];
         let short_vec = [5u8, ..($size - 1)];
         assert_eq!(vec.as_slice(), vec.serialize().as_slice());
+        assert_eq!(vec.serialized_len(), $size);
 
         let decode: IoResult<[u8, ..$size]> = Serializable::deserialize(vec.iter().map(|n| *n));
         let short_decode: IoResult<[u8, ..$size]> = Serializable::deserialize(short_vec.iter().map(|n| *n));
@@ -366,6 +427,11 @@ impl Serializable for CheckedData {
     ret
   }
 
+  fn serialized_len(&self) -> uint {
+    let &CheckedData(ref data) = self;
+    8 + data.len()
+  }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<CheckedData> {
     let length: u32 = try!(Serializable::deserialize(iter.by_ref()));
     let checksum: u32 = try!(Serializable::deserialize(iter.by_ref()));
@@ -400,6 +466,10 @@ impl Serializable for String {
     rv
   }
 
+  fn serialized_len(&self) -> uint {
+    u64_to_varint(self.len() as u64).serialized_len() + self.len()
+  }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<String> {
     let length: VarInt = try!(Serializable::deserialize(iter.by_ref()));
     let mut fixiter = iter.fixed_take(varint_to_u64(length) as uint);
@@ -419,6 +489,8 @@ impl Serializable for CommandString {
     Vec::from_slice(rawbytes.as_slice())
   }
 
+  fn serialized_len(&self) -> uint { 12 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<CommandString> {
     let mut fixiter = iter.fixed_take(12);
     let rv: String = FromIterator::from_iter(fixiter.by_ref().filter_map(|u| if u > 0 { Some(u as char) } else { None }));
@@ -441,14 +513,14 @@ impl<T: Serializable> Serializable for Vec<T> {
     rv
   }
 
+  fn serialized_len(&self) -> uint {
+    let n_elems = u64_to_varint(self.len() as u64);
+    self.iter().fold(n_elems.serialized_len(), |sum, elem| sum + elem.serialized_len())
+  }
+
   fn deserialize<I: Iterator<u8>>(mut iter: I) -> IoResult<Vec<T>> {
-    let mut n_elems = varint_to_u64(try!(Serializable::deserialize(iter.by_ref())));
-    let mut v: Vec<T> = vec![];
-    while n_elems > 0 {
-      v.push(try!(Serializable::deserialize(iter.by_ref())));
-      n_elems -= 1;
-    }
-    Ok(v)
+    let n_elems = varint_to_u64(try!(Serializable::deserialize(iter.by_ref())));
+    deserialize_elements(iter, n_elems)
   }
 }
 
@@ -464,6 +536,13 @@ impl<T:Serializable+'static> Serializable for Option<T> {
     }
   }
 
+  fn serialized_len(&self) -> uint {
+    match self {
+      &Some(ref dat) => 1 + dat.serialized_len(),
+      &None => 1
+    }
+  }
+
   fn serialize_iter<'a>(&'a self) -> SerializeIter<'a> {
     match self {
       &Some(ref dat) => SerializeIter {
@@ -499,6 +578,10 @@ impl <T:Serializable> Serializable for Box<T> {
     (**self).serialize_iter()
   }
 
+  fn serialized_len(&self) -> uint {
+    (**self).serialized_len()
+  }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<Box<T>> {
     let ret: T = try!(Serializable::deserialize(iter));
     Ok(box ret)
@@ -535,6 +618,34 @@ fn serialize_iter_test() {
   assert_eq!(20u64.serialize(), 20u64.serialize_iter().collect());
 }
 
+#[test]
+fn serialized_len_test() {
+  fn check<T: Serializable>(value: T) {
+    assert_eq!(value.serialized_len(), value.serialize().len());
+  }
+  check(true);
+  check(1u8);
+  check(300u16);
+  check(300u32);
+  check(-1i32);
+  check(20u64);
+  check(-20i64);
+  for &n in [0u64, 0xFC, 0xFD, 0xFFFF, 0x10000, 0xFFFFFFFF, 0x100000000].iter() {
+    check(u64_to_varint(n));
+  }
+  check(String::from_str("andrew"));
+  check(String::from_char(300, 'a'));
+  check(CommandString(String::from_str("ping")));
+  check(CheckedData(vec![1u8, 2, 3]));
+  check(vec![1u64, 2, 3]);
+  check(Vec::from_elem(0xFD, String::from_str("x")));
+  check(vec![vec![1u8], vec![], vec![2u8, 3]]);
+  check(Some(5u32));
+  check(None::<u32>);
+  check(box 7u64);
+  check([0u8, ..32]);
+}
+
 #[test]
 fn serialize_int_test() {
   // bool
@@ -683,6 +794,21 @@ fn deserialize_vec_test() {
   assert_eq!(Serializable::deserialize([4u8, 2, 3, 4, 5, 6].iter().map(|n| *n)), Ok(vec![2u8, 3, 4, 5]));
 }
 
+#[test]
+fn deserialize_bounded_vec_test() {
+  let ok: IoResult<Vec<u8>> = deserialize_bounded_vec([3u8, 2, 3, 4].iter().map(|n| *n), 3, "bytes");
+  assert_eq!(ok, Ok(vec![2u8, 3, 4]));
+  // One over the limit fails without reading on, so a truncated vector
+  // gives the limit error rather than running out of data
+  let over: IoResult<Vec<u8>> = deserialize_bounded_vec([4u8, 2, 3, 4].iter().map(|n| *n), 3, "bytes");
+  let err = over.unwrap_err();
+  assert_eq!(err.desc, "too many elements");
+  assert_eq!(err.detail, Some(String::from_str("4 bytes, more than the 3 allowed")));
+  // Including a count no input could back up
+  let huge: IoResult<Vec<u64>> = deserialize_bounded_vec([0xFFu8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF].iter().map(|n| *n), 1000, "things");
+  assert_eq!(huge.unwrap_err().detail, Some(String::from_str("18446744073709551615 things, more than the 1000 allowed")));
+}
+
 #[test]
 fn deserialize_strbuf_test() {
   assert_eq!(Serializable::deserialize([6u8, 0x41, 0x6e, 0x64, 0x72, 0x65, 0x77].iter().map(|n| *n)), Ok(String::from_str("Andrew")));
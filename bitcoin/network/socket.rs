@@ -17,21 +17,34 @@
 //! This module provides support for low-level network communication.
 //!
 
-use time::now;
+use time::{now, precise_time_ns};
 use std::rand::task_rng;
 use rand::Rng;
-use std::io::{IoError, IoResult, NotConnected, OtherIoError, standard_error};
+use std::io::{IoError, IoResult, ConnectionFailed, ConnectionAborted, InvalidInput, NotConnected, OtherIoError, TimedOut, standard_error};
 use std::io::net::{ip, tcp};
+use std::sync::{Arc, Mutex};
+use std::sync::atomics::{AtomicBool, AtomicUint, SeqCst};
 
+use blockdata::block::{BlockHeader, LoneBlockHeader};
 use network::constants;
-use network::address::Address;
+use network::address::{Address, is_onion_v3};
+use network::message_blockdata::{HeadersMessage, InventoryMessage, Inventory, InvBlock};
 use network::serialize::CheckedData;
 use network::serialize::CommandString;
 use network::serialize::Message;
-use network::serialize::Serializable;
-use network::message_network::VersionMessage;
+use network::serialize::{Serializable, VarU8};
+use network::message_network::{VersionMessage, PingMessage, PongMessage};
+use network::trace::{ProtocolTrace, TraceEvent, Sent, Received, Misbehaved, format_trace};
+use network::trace::{DEFAULT_TRACE_EVENTS, DEFAULT_TRACE_BYTES};
 use util::misc::prepend_err;
 
+/// Number of milliseconds `measure_ping` waits for a `pong`
+static PING_TIMEOUT_MS: u64 = 10000;
+/// Number of milliseconds `receive_message_timeout` allows for the rest of
+/// a message once it has started to arrive; long enough for a full block
+/// on a slow link
+static MESSAGE_TIMEOUT_MS: u64 = 120000;
+
 /// Network message with header removed
 pub struct MessageData {
   /// Raw message data
@@ -40,6 +53,49 @@ pub struct MessageData {
   pub command: String
 }
 
+impl MessageData {
+  /// Decodes the payload, treating any bytes left over as an error. Only
+  /// `version` may have extra bytes, since later protocol versions
+  /// extend it.
+  pub fn decode<T: Serializable>(&self) -> IoResult<T> {
+    let mut iter = self.data.iter().map(|n| *n);
+    let ret: T = try!(Serializable::deserialize(iter.by_ref()));
+    let leftover = iter.count();
+    if leftover > 0 && self.command.as_slice() != "version" {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "payload has trailing bytes",
+        detail: Some(format!("{:} bytes left over after decoding {:s}", leftover, self.command.as_slice()))
+      });
+    }
+    Ok(ret)
+  }
+
+  /// Decodes the payload as `T`, as `decode` does, but also checks that
+  /// `T` is the type the network header gave. The payload is decoded before
+  /// the type can be checked, so a payload which does not decode as `T`
+  /// gives a decoding error rather than a type mismatch.
+  pub fn decode_typed<T: Message>(&self) -> IoResult<T> {
+    let ret: T = try!(self.decode());
+    if ret.command() != self.command {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "wrong message type",
+        detail: Some(format!("expected {:s} message, got {:s}", ret.command(), self.command.as_slice()))
+      });
+    }
+    Ok(ret)
+  }
+
+  /// Serializes a message, for passing around before it is sent
+  pub fn from_typed(message: &Message) -> MessageData {
+    MessageData {
+      data: message.serialize(),
+      command: message.command()
+    }
+  }
+}
+
 /// Format an IP address in the 16-byte bitcoin protocol serialization
 fn ipaddr_to_bitcoin_addr(ipaddr: &ip::IpAddr) -> [u8, ..16] {
   match *ipaddr {
@@ -66,7 +122,22 @@ pub struct Socket {
   /// Nonce to identify our `version` messages
   pub version_nonce: u64,
   /// Network magic
-  pub magic: u32
+  pub magic: u32,
+  /// Protocol version spoken on the connection: the lower of ours and the
+  /// peer's, once its `version` message has arrived. Shared by all clones
+  /// of the socket, like `prefers_headers`.
+  negotiated_version: Arc<AtomicUint>,
+  /// Whether the peer has sent `sendheaders`, asking for new blocks to be
+  /// announced with `headers` rather than `inv`. Shared by all clones of
+  /// the socket, since the listener sees the message but the application
+  /// does the announcing.
+  prefers_headers: Arc<AtomicBool>,
+  /// Round-trip time of the last ping measured on this socket, in
+  /// nanoseconds. Shared by all clones of the socket, like
+  /// `prefers_headers`, since the listener measures its own pings.
+  last_ping_latency: Arc<Mutex<Option<u64>>>,
+  /// Recent protocol events, shared by all clones of the socket
+  trace: Arc<Mutex<ProtocolTrace>>
 }
 
 impl Socket {
@@ -79,12 +150,80 @@ impl Socket {
       services: 0,
       version_nonce: rng.gen(),
       user_agent: String::from_str(constants::USER_AGENT),
-      magic: magic
+      magic: magic,
+      negotiated_version: Arc::new(AtomicUint::new(constants::PROTOCOL_VERSION as uint)),
+      prefers_headers: Arc::new(AtomicBool::new(false)),
+      last_ping_latency: Arc::new(Mutex::new(None)),
+      trace: Arc::new(Mutex::new(ProtocolTrace::new(DEFAULT_TRACE_EVENTS, DEFAULT_TRACE_BYTES)))
+    }
+  }
+
+  /// Returns the round-trip time of the last ping answered on this
+  /// socket, by `measure_ping` or by the listener, in nanoseconds
+  pub fn last_ping_latency(&self) -> Option<u64> {
+    *self.last_ping_latency.lock()
+  }
+
+  /// Records the round-trip time of a ping the peer has answered
+  pub fn record_ping_latency(&self, latency_ns: u64) {
+    *self.last_ping_latency.lock() = Some(latency_ns);
+  }
+
+  /// Records a protocol event in the socket's trace
+  pub fn trace_event(&self, event: TraceEvent) {
+    let mut trace = self.trace.lock();
+    trace.push(precise_time_ns(), event);
+  }
+
+  /// Returns the socket's recent protocol events, one per line
+  pub fn trace_dump(&self) -> String {
+    // Copy the events out so the lock is not held while formatting
+    let entries = self.trace.lock().entries();
+    format_trace(entries.as_slice())
+  }
+
+  /// Protocol version spoken on the connection
+  pub fn negotiated_version(&self) -> u32 {
+    self.negotiated_version.load(SeqCst) as u32
+  }
+
+  /// Records the protocol version the peer gave in its `version` message,
+  /// lowering the negotiated version if the peer's is older than ours
+  pub fn set_peer_version(&self, version: u32) {
+    if version < self.negotiated_version() {
+      self.negotiated_version.store(version as uint, SeqCst);
+    }
+  }
+
+  /// Whether the peer wants new blocks announced with `headers`
+  pub fn prefers_headers(&self) -> bool {
+    self.prefers_headers.load(SeqCst)
+  }
+
+  /// Records that the peer has sent `sendheaders`
+  pub fn set_prefers_headers(&self, prefers_headers: bool) {
+    self.prefers_headers.store(prefers_headers, SeqCst);
+  }
+
+  /// Returns the message with which to announce a new block to the peer
+  pub fn block_announcement(&self, header: &BlockHeader) -> Box<Message> {
+    if self.prefers_headers() {
+      box HeadersMessage(vec![LoneBlockHeader { header: *header, tx_count: VarU8(0) }]) as Box<Message>
+    } else {
+      box InventoryMessage(vec![Inventory { inv_type: InvBlock, hash: header.hash() }]) as Box<Message>
     }
   }
 
   /// Connect to the peer
   pub fn connect(&mut self, host: &str, port: u16) -> IoResult<()> {
+    // Onion services can only be reached through a Tor proxy
+    if is_onion_v3(host) {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "cannot connect to onion address directly",
+        detail: Some(format!("{:s} must be reached through a SOCKS5 proxy", host))
+      });
+    }
     match tcp::TcpStream::connect(host, port) {
       Ok(s)  => {
         self.stream = Some(s);
@@ -94,6 +233,65 @@ impl Socket {
     }
   }
 
+  /// Connect to the peer through a SOCKS5 proxy, such as Tor. The hostname
+  /// is passed to the proxy unresolved, so no local DNS lookup is done and
+  /// `.onion` hostnames can be used.
+  pub fn connect_via_socks5(&mut self, proxy_host: &str, proxy_port: u16,
+                            host: &str, port: u16) -> IoResult<()> {
+    if host.len() > 255 {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "hostname too long for SOCKS5",
+        detail: Some(format!("{:s} is longer than 255 bytes", host))
+      });
+    }
+    let mut stream = try!(tcp::TcpStream::connect(proxy_host, proxy_port));
+
+    // Greeting: version 5, offering one method, "no authentication"
+    try!(stream.write([5u8, 1, 0]));
+    let greeting = try!(stream.read_exact(2));
+    if greeting.as_slice() != [5u8, 0].as_slice() {
+      return Err(IoError {
+        kind: ConnectionFailed,
+        desc: "SOCKS5 proxy refused connection",
+        detail: Some(format!("proxy wants authentication method {:}", *greeting.get(1)))
+      });
+    }
+
+    // Request: version 5, CONNECT, reserved, DOMAINNAME, then the name and port
+    let mut request = vec![5u8, 1, 0, 3, host.len() as u8];
+    request.push_all(host.as_bytes());
+    request.push_all([(port / 0x100) as u8, (port % 0x100) as u8]);
+    try!(stream.write(request.as_slice()));
+
+    // Reply: version, status, reserved, address type, then the bound address
+    let reply = try!(stream.read_exact(4));
+    if *reply.get(1) != 0 {
+      return Err(IoError {
+        kind: ConnectionFailed,
+        desc: "SOCKS5 proxy failed to connect",
+        detail: Some(format!("proxy returned error code {:}", *reply.get(1)))
+      });
+    }
+    let addr_len = match *reply.get(3) {
+      1 => 4,
+      3 => try!(stream.read_u8()) as uint,
+      4 => 16,
+      n => {
+        return Err(IoError {
+          kind: OtherIoError,
+          desc: "bad SOCKS5 reply",
+          detail: Some(format!("unknown address type {:}", n))
+        });
+      }
+    };
+    // We have no use for the bound address or port, so just read them out
+    try!(stream.read_exact(addr_len + 2));
+
+    self.stream = Some(stream);
+    Ok(())
+  }
+
   /// Peer address
   pub fn receiver_address(&mut self) -> IoResult<Address> {
     match self.stream {
@@ -158,53 +356,420 @@ impl Socket {
 
   /// Send a general message across the line
   pub fn send_message(&mut self, message: &Message) -> IoResult<()> {
-    if self.stream.is_none() {
+    let version = self.negotiated_version();
+    if !message.is_supported_by_version(version) {
+      Err(IoError {
+        kind: InvalidInput,
+        desc: "peer protocol version too low",
+        detail: Some(format!("{:s} is not supported by protocol version {:u}",
+                             message.command(), version))
+      })
+    }
+    else if self.stream.is_none() {
       Err(standard_error(NotConnected))
     }
     else {
       let payload = message.serialize();
+      let payload_len = payload.len();
 
       let mut wire_message = self.magic.serialize();
       wire_message.extend(CommandString(message.command()).serialize().move_iter());
       wire_message.extend(CheckedData(payload).serialize().move_iter());
 
-      let stream = self.stream.get_mut_ref();
-      match stream.write(wire_message.as_slice()) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e)
+      let result = {
+        let stream = self.stream.get_mut_ref();
+        stream.write(wire_message.as_slice())
+      };
+      if result.is_ok() {
+        self.trace_event(Sent(message.command(), payload_len));
       }
+      result
+    }
+  }
+
+  /// Shuts the connection down in both directions. This affects every
+  /// clone of the socket, since they share the underlying stream.
+  pub fn close(&mut self) -> IoResult<()> {
+    match self.stream {
+      Some(ref mut s) => {
+        try!(s.close_read());
+        s.close_write()
+      }
+      None => Err(standard_error(NotConnected))
     }
   }
 
   /// Receive the next message from the peer, decoding the network header
   /// and verifying its correctness. Returns the undecoded payload.
   pub fn receive_message(&mut self) -> IoResult<MessageData> {
-    match self.stream {
+    self.receive_message_from(None)
+  }
+
+  /// Receives the next message, as `receive_message` does, but gives a
+  /// `TimedOut` error if none has started to arrive within `timeout_ms`
+  /// milliseconds. A peer which then takes more than MESSAGE_TIMEOUT_MS
+  /// over the rest of the message gets a `ConnectionAborted` error, since
+  /// the stream is no longer at a message boundary.
+  pub fn receive_message_timeout(&mut self, timeout_ms: u64) -> IoResult<MessageData> {
+    let first_byte = match self.stream {
+      None => { return Err(standard_error(NotConnected)); }
+      Some(ref mut s) => {
+        s.set_read_timeout(Some(timeout_ms));
+        let ret = s.read_byte();
+        s.set_read_timeout(if ret.is_ok() { Some(MESSAGE_TIMEOUT_MS) } else { None });
+        ret
+      }
+    };
+    let result = self.receive_message_from(Some(try!(first_byte)));
+    self.stream.get_mut_ref().set_read_timeout(None);
+    match result {
+      Err(ref e) if e.kind == TimedOut => Err(IoError {
+        kind: ConnectionAborted,
+        desc: "peer stalled partway through a message",
+        detail: Some(format!("no data for {:u} ms", MESSAGE_TIMEOUT_MS))
+      }),
+      result => result
+    }
+  }
+
+  /// Receives a message whose first byte, if given, has already been read
+  fn receive_message_from(&mut self, first_byte: Option<u8>) -> IoResult<MessageData> {
+    let result = match self.stream {
       None => Err(standard_error(NotConnected)),
       Some(ref mut s) => {
         let mut read_err = None;
         let ret = {
-          let mut iter = s.bytes().filter_map(|res| match res { Ok(ch) => Some(ch), Err(e) => { read_err = Some(e); None } });
-          let magic: u32 = try!(prepend_err("magic", Serializable::deserialize(iter.by_ref())));
-          // Check magic before decoding further
-          if magic != self.magic {
-            return Err(IoError {
-              kind: OtherIoError,
-              desc: "bad magic",
-              detail: Some(format!("magic {:x} did not match network magic {:x}", magic, self.magic)),
-            });
-          }
-          let CommandString(command): CommandString = try!(prepend_err("command", Serializable::deserialize(iter.by_ref())));
-          let CheckedData(payload): CheckedData = try!(prepend_err("payload", Serializable::deserialize(iter.by_ref())));
-          MessageData { command: command, data: payload }
+          // Stop at the first read error (e.g. a timeout) rather than retrying
+          let iter = s.bytes().take_while(|res| match *res {
+              Ok(_) => true,
+              Err(ref e) => { read_err = Some(e.clone()); false }
+            }).map(|res| res.unwrap());
+          decode_message(first_byte.move_iter().chain(iter), self.magic)
         };
+        // A read error is the real cause of any decoding error, so report it instead
         match read_err {
           Some(e) => Err(e),
-          _ => Ok(ret)
+          _ => ret
         }
       }
+    };
+    match result {
+      Ok(ref msg) => { self.trace_event(Received(msg.command.clone(), msg.data.len())); }
+      Err(ref e) if e.desc == "oversized payload" => { self.trace_event(Misbehaved("oversized payload")); }
+      Err(_) => {}
     }
+    result
   }
 }
 
+/// Decode a message from the wire, checking its magic, payload size and
+/// checksum
+pub fn decode_message<I: Iterator<u8>>(mut iter: I, network_magic: u32) -> IoResult<MessageData> {
+  let magic: u32 = try!(prepend_err("magic", Serializable::deserialize(iter.by_ref())));
+  // Check magic before decoding further
+  if magic != network_magic {
+    return Err(IoError {
+      kind: OtherIoError,
+      desc: "bad magic",
+      detail: Some(format!("magic {:x} did not match network magic {:x}", magic, network_magic)),
+    });
+  }
+  let CommandString(command): CommandString = try!(prepend_err("command", Serializable::deserialize(iter.by_ref())));
+  // Check the payload length against the command's limit before reading it.
+  // The payload is left unread, so the stream is no longer at a message
+  // boundary and the connection cannot be used further.
+  let length: u32 = try!(prepend_err("payload", Serializable::deserialize(iter.by_ref())));
+  let max_length = constants::max_payload_size(command.as_slice());
+  if length > max_length {
+    return Err(IoError {
+      kind: ConnectionAborted,
+      desc: "oversized payload",
+      detail: Some(format!("{:} payload of {:} bytes exceeds limit of {:}", command, length, max_length)),
+    });
+  }
+  // Put the length back in front for CheckedData to read
+  let CheckedData(payload): CheckedData = try!(prepend_err("payload",
+      Serializable::deserialize(length.serialize().move_iter().chain(iter))));
+  Ok(MessageData { command: command, data: payload })
+}
+
+/// Read messages until a `pong` with the given nonce arrives
+fn wait_for_pong(socket: &mut Socket, nonce: u64) -> IoResult<()> {
+  loop {
+    let msg = try!(socket.receive_message());
+    if msg.command.as_slice() == "pong" {
+      let pong: PongMessage = try!(prepend_err("pong", msg.decode_typed()));
+      if pong.nonce == nonce {
+        return Ok(());
+      }
+    }
+  }
+}
+
+/// Sends a `ping` with a fresh nonce and waits up to ten seconds for the
+/// matching `pong`, returning the round-trip time in nanoseconds. This reads
+/// from the socket directly, discarding any other messages which arrive in
+/// the meantime, so it must not be used while a listener is running on the
+/// socket. The listener measures its own keepalive pings instead, and any
+/// clone of the socket can read the result from `last_ping_latency`.
+pub fn measure_ping(socket: &mut Socket) -> IoResult<u64> {
+  let nonce: u64 = task_rng().gen();
+  let start = precise_time_ns();
+  try!(socket.send_message(&PingMessage { nonce: nonce }));
+
+  // send_message has checked that we have a stream
+  socket.stream.get_mut_ref().set_read_timeout(Some(PING_TIMEOUT_MS));
+  let result = wait_for_pong(socket, nonce);
+  socket.stream.get_mut_ref().set_read_timeout(None);
+  try!(result);
+
+  let latency = precise_time_ns() - start;
+  socket.record_ping_latency(latency);
+  Ok(latency)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+  use std::io::{Acceptor, Listener};
+  use std::io::net::tcp::TcpListener;
+
+  use blockdata::constants::genesis_block;
+  use std::io::{IoError, IoResult, ConnectionAborted, TimedOut};
+  use network::constants;
+  use network::message_network::{VersionMessage, VersionAckMessage, MempoolMessage};
+  use network::message_network::{PingMessage, PongMessage};
+  use network::serialize::{Serializable, CommandString, CheckedData};
+  use network::socket::{Socket, MessageData, decode_message, measure_ping};
+  use util::misc::hex_bytes;
+
+  fn raw_message(command: &str, payload: Vec<u8>) -> Vec<u8> {
+    let mut ret = constants::MAGIC_BITCOIN.serialize();
+    ret.extend(CommandString(String::from_str(command)).serialize().move_iter());
+    ret.extend(CheckedData(payload).serialize().move_iter());
+    ret
+  }
+
+  fn decode_raw(raw: Vec<u8>) -> IoResult<MessageData> {
+    decode_message(raw.move_iter(), constants::MAGIC_BITCOIN)
+  }
+
+  fn decode_err(raw: Vec<u8>) -> IoError {
+    match decode_raw(raw) {
+      Ok(msg) => fail!("decoded {:} message which should have been rejected", msg.command),
+      Err(e) => e
+    }
+  }
+
+  #[test]
+  fn decode_message_size_limit_test() {
+    // Payloads at the limit are fine
+    let msg = decode_raw(raw_message("ping", vec![0u8, ..8])).unwrap();
+    assert_eq!(msg.command, String::from_str("ping"));
+    assert_eq!(msg.data.len(), 8);
+    assert!(decode_raw(raw_message("verack", vec![])).is_ok());
+    assert!(decode_raw(raw_message("headers", Vec::from_elem(162003, 0u8))).is_ok());
+
+    // One byte more is not
+    let err = decode_err(raw_message("ping", vec![0u8, ..9]));
+    assert!(err.kind == ConnectionAborted);
+    assert_eq!(err.desc, "oversized payload");
+    assert_eq!(err.detail, Some(String::from_str("ping payload of 9 bytes exceeds limit of 8")));
+    assert_eq!(decode_err(raw_message("verack", vec![0])).desc, "oversized payload");
+    assert_eq!(decode_err(raw_message("headers", Vec::from_elem(162004, 0u8))).desc,
+               "oversized payload");
+
+    // Unknown commands get the default limit
+    let limit = constants::DEFAULT_MAX_PAYLOAD_SIZE as uint;
+    assert!(decode_raw(raw_message("nonsense", Vec::from_elem(limit, 0u8))).is_ok());
+    assert_eq!(decode_err(raw_message("nonsense", Vec::from_elem(limit + 1, 0u8))).desc,
+               "oversized payload");
+
+    // The length is checked before any of the payload is read
+    let mut raw = constants::MAGIC_BITCOIN.serialize();
+    raw.extend(CommandString(String::from_str("block")).serialize().move_iter());
+    raw.extend(0xFFFFFFFFu32.serialize().move_iter());
+    assert_eq!(decode_err(raw).desc, "oversized payload");
+  }
+
+  #[test]
+  fn send_message_version_test() {
+    let mut sock = Socket::new(constants::MAGIC_BITCOIN);
+    assert_eq!(sock.negotiated_version(), constants::PROTOCOL_VERSION);
+    sock.set_peer_version(60001);
+    assert_eq!(sock.negotiated_version(), 60001);
+    // A newer peer does not raise it again
+    sock.set_peer_version(70002);
+    assert_eq!(sock.negotiated_version(), 60001);
+
+    // `mempool` needs 60002, so is refused before the socket is touched
+    let err = sock.send_message(&MempoolMessage::new()).unwrap_err();
+    assert_eq!(err.desc, "peer protocol version too low");
+    assert_eq!(err.detail, Some(String::from_str("mempool is not supported by protocol version 60001")));
+
+    // Messages the peer understands get as far as noticing there is no connection
+    let err = sock.send_message(&PongMessage { nonce: 1 }).unwrap_err();
+    assert!(err.desc != "peer protocol version too low");
+    let err = sock.send_message(&VersionAckMessage::new()).unwrap_err();
+    assert!(err.desc != "peer protocol version too low");
+
+    let mut sock = Socket::new(constants::MAGIC_BITCOIN);
+    sock.set_peer_version(60002);
+    let err = sock.send_message(&MempoolMessage::new()).unwrap_err();
+    assert!(err.desc != "peer protocol version too low");
+
+    // The version is shared with clones, such as the listener's
+    let mut app_sock = Socket::new(constants::MAGIC_BITCOIN);
+    let listener_sock = app_sock.clone();
+    listener_sock.set_peer_version(60001);
+    assert_eq!(app_sock.negotiated_version(), 60001);
+    let err = app_sock.send_message(&MempoolMessage::new()).unwrap_err();
+    assert_eq!(err.desc, "peer protocol version too low");
+  }
+
+  #[test]
+  fn message_data_decode_test() {
+    let mut data = PingMessage { nonce: 1234 }.serialize();
+    let ping = MessageData { command: String::from_str("ping"), data: data.clone() };
+    let decode: IoResult<PingMessage> = ping.decode();
+    assert_eq!(decode.unwrap().nonce, 1234);
+
+    // Trailing garbage is rejected, except after a version message
+    data.push_all([0xde, 0xad]);
+    let ping = MessageData { command: String::from_str("ping"), data: data };
+    let decode: IoResult<PingMessage> = ping.decode();
+    assert_eq!(decode.unwrap_err().detail, Some(String::from_str("2 bytes left over after decoding ping")));
+
+    let mut data = hex_bytes("721101000100000000000000e6e0845300000000010000000000000000000000000000000000ffff0000000000000100000000000000fd87d87eeb4364f22cf54dca59412db7208d47d920cffce83ee8102f5361746f7368693a302e392e39392f2c9f040001").unwrap();
+    data.push_all([0xde, 0xad]);
+    let version = MessageData { command: String::from_str("version"), data: data };
+    let decode: IoResult<VersionMessage> = version.decode();
+    assert!(decode.is_ok());
+  }
+
+  #[test]
+  fn message_data_typed_test() {
+    let ping = MessageData::from_typed(&PingMessage { nonce: 1234 });
+    assert_eq!(ping.command, String::from_str("ping"));
+    assert_eq!(ping.data, PingMessage { nonce: 1234 }.serialize());
+    let decode: IoResult<PingMessage> = ping.decode_typed();
+    assert_eq!(decode.unwrap().nonce, 1234);
+
+    // A pong has the same payload as a ping, but is not one
+    let decode: IoResult<PongMessage> = ping.decode_typed();
+    let err = decode.err().unwrap();
+    assert_eq!(err.desc, "wrong message type");
+    assert_eq!(err.detail, Some(String::from_str("expected pong message, got ping")));
+    // Whereas an untyped decode does not look at the command
+    let decode: IoResult<PongMessage> = ping.decode();
+    assert_eq!(decode.unwrap().nonce, 1234);
+
+    // Payloads without any fields work too
+    let mempool = MessageData::from_typed(&MempoolMessage::new());
+    assert_eq!(mempool.command, String::from_str("mempool"));
+    assert_eq!(mempool.data.len(), 0);
+    let decode: IoResult<MempoolMessage> = mempool.decode_typed();
+    assert!(decode.is_ok());
+  }
+
+  #[test]
+  fn block_announcement_test() {
+    let header = genesis_block().header;
+    let sock = Socket::new(constants::MAGIC_BITCOIN);
+
+    let inv = sock.block_announcement(&header);
+    assert_eq!(inv.command(), String::from_str("inv"));
+    // One inventory of type 2 (block) with the header's hash
+    let mut expected = vec![1u8, 2, 0, 0, 0];
+    expected.extend(header.hash().serialize().move_iter());
+    assert_eq!(inv.serialize(), expected);
+
+    sock.set_prefers_headers(true);
+    let headers = sock.block_announcement(&header);
+    assert_eq!(headers.command(), String::from_str("headers"));
+    // One header with a zero transaction count
+    let mut expected = vec![1u8];
+    expected.extend(header.serialize().move_iter());
+    expected.push(0);
+    assert_eq!(headers.serialize(), expected);
+  }
+
+  #[test]
+  fn prefers_headers_shared_test() {
+    let header = genesis_block().header;
+    let sock = Socket::new(constants::MAGIC_BITCOIN);
+    // The listener works on a clone of the application's socket
+    let listener_sock = sock.clone();
+    assert!(!sock.prefers_headers());
+
+    listener_sock.set_prefers_headers(true);
+    assert!(sock.prefers_headers());
+    assert_eq!(sock.block_announcement(&header).command(), String::from_str("headers"));
+
+    // Clones made afterwards share it too
+    let later_sock = sock.clone();
+    sock.set_prefers_headers(false);
+    assert!(!later_sock.prefers_headers());
+    assert!(!listener_sock.prefers_headers());
+  }
+
+  #[test]
+  fn receive_message_timeout_test() {
+    let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+    let port = listener.socket_name().unwrap().port;
+    let acceptor = listener.listen();
+    let (done_tx, done_rx) = channel();
+
+    // Fake peer which sends one ping, then stays silent until we are done
+    spawn(proc() {
+      let mut acceptor = acceptor;
+      let mut peer = Socket::new(constants::MAGIC_BITCOIN);
+      peer.stream = Some(acceptor.accept().unwrap());
+      peer.send_message(&PingMessage { nonce: 1 }).unwrap();
+      done_rx.recv();
+    });
+
+    let mut sock = Socket::new(constants::MAGIC_BITCOIN);
+    assert!(sock.receive_message_timeout(10).is_err());
+    sock.connect("127.0.0.1", port).unwrap();
+    let msg = sock.receive_message_timeout(10000).unwrap();
+    assert_eq!(msg.command, String::from_str("ping"));
+    // Nothing else is coming, so we hear about it rather than blocking
+    let err = sock.receive_message_timeout(10).err().unwrap();
+    assert!(err.kind == TimedOut);
+    done_tx.send(());
+  }
+
+  #[test]
+  fn measure_ping_test() {
+    let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+    let port = listener.socket_name().unwrap().port;
+    let acceptor = listener.listen();
+
+    // Fake peer which answers a single ping
+    spawn(proc() {
+      let mut acceptor = acceptor;
+      let mut peer = Socket::new(constants::MAGIC_BITCOIN);
+      peer.stream = Some(acceptor.accept().unwrap());
+      let msg = peer.receive_message().unwrap();
+      assert_eq!(msg.command, String::from_str("ping"));
+      let ping: PingMessage = Serializable::deserialize(msg.data.iter().map(|n| *n)).unwrap();
+      // Send a stale pong first, which should be ignored
+      peer.send_message(&PongMessage { nonce: ping.nonce + 1 }).unwrap();
+      peer.send_message(&PongMessage { nonce: ping.nonce }).unwrap();
+    });
+
+    let mut sock = Socket::new(constants::MAGIC_BITCOIN);
+    assert!(measure_ping(&mut sock).is_err());
+    assert!(sock.last_ping_latency().is_none());
+
+    sock.connect("127.0.0.1", port).unwrap();
+    let latency = measure_ping(&mut sock);
+    assert!(latency.is_ok());
+    let real_latency = latency.unwrap();
+    assert_eq!(sock.last_ping_latency(), Some(real_latency));
+    // A loopback round trip should be nowhere near the timeout
+    assert!(real_latency < 1000000000);
+  }
+}
 
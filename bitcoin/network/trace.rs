@@ -0,0 +1,203 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Protocol traces
+//!
+//! A bounded record of the last few protocol events on a connection, to
+//! ask for when a peer and this library disagree about the protocol. It
+//! keeps much less than a capture of the raw bytes, so it can be left on
+//! for every connection.
+//!
+
+use collections::Deque;
+use collections::ringbuf::RingBuf;
+
+/// Number of events a trace keeps by default
+pub static DEFAULT_TRACE_EVENTS: uint = 256;
+/// Bytes of text (commands and error descriptions) a trace keeps by default
+pub static DEFAULT_TRACE_BYTES: uint = 16384;
+
+/// Something which happened on a connection
+#[deriving(PartialEq, Clone, Show)]
+pub enum TraceEvent {
+  /// We sent a message with this command and payload size
+  Sent(String, uint),
+  /// We received a message with this command and payload size
+  Received(String, uint),
+  /// The handshake reached a new state
+  Handshake(&'static str),
+  /// The peer did something the protocol does not allow
+  Misbehaved(&'static str),
+  /// Receiving a message failed with this error
+  ReceiveError(String),
+  /// We gave up on the peer for this reason
+  Disconnected(&'static str)
+}
+
+impl TraceEvent {
+  /// Bytes of text held by the event, which count against the trace's limit
+  fn text_len(&self) -> uint {
+    match *self {
+      Sent(ref command, _) | Received(ref command, _) => command.len(),
+      Handshake(state) => state.len(),
+      Misbehaved(reason) => reason.len(),
+      ReceiveError(ref error) => error.len(),
+      Disconnected(reason) => reason.len()
+    }
+  }
+}
+
+/// An event and when it happened
+#[deriving(PartialEq, Clone, Show)]
+pub struct TraceEntry {
+  /// Time of the event, from `time::precise_time_ns`
+  pub time_ns: u64,
+  /// The event
+  pub event: TraceEvent
+}
+
+/// The most recent events on a connection. Once either the event or the
+/// byte limit is exceeded, the oldest events are dropped.
+pub struct ProtocolTrace {
+  entries: RingBuf<TraceEntry>,
+  max_events: uint,
+  max_bytes: uint,
+  bytes: uint
+}
+
+impl ProtocolTrace {
+  /// Constructs an empty trace with the given limits
+  pub fn new(max_events: uint, max_bytes: uint) -> ProtocolTrace {
+    ProtocolTrace {
+      entries: RingBuf::new(),
+      max_events: max_events,
+      max_bytes: max_bytes,
+      bytes: 0
+    }
+  }
+
+  /// Records an event, dropping the oldest ones if the trace is full
+  pub fn push(&mut self, time_ns: u64, event: TraceEvent) {
+    self.bytes += event.text_len();
+    self.entries.push_back(TraceEntry { time_ns: time_ns, event: event });
+    while self.entries.len() > self.max_events || self.bytes > self.max_bytes {
+      match self.entries.pop_front() {
+        Some(old) => { self.bytes -= old.event.text_len(); }
+        None => { break; }
+      }
+    }
+  }
+
+  /// Returns the number of events held
+  pub fn len(&self) -> uint {
+    self.entries.len()
+  }
+
+  /// Returns a copy of the events held, oldest first. Callers sharing the
+  /// trace behind a lock should take this and release the lock before
+  /// formatting it.
+  pub fn entries(&self) -> Vec<TraceEntry> {
+    self.entries.iter().map(|entry| entry.clone()).collect()
+  }
+}
+
+/// Formats events one per line, with times in nanoseconds since the first
+pub fn format_trace(entries: &[TraceEntry]) -> String {
+  let start = match entries.head() {
+    Some(entry) => entry.time_ns,
+    None => 0
+  };
+  let mut ret = String::new();
+  for entry in entries.iter() {
+    let line = match entry.event {
+      Sent(ref command, size) => format!("sent {:s}, {:u} bytes", command.as_slice(), size),
+      Received(ref command, size) => format!("received {:s}, {:u} bytes", command.as_slice(), size),
+      Handshake(state) => format!("handshake {:s}", state),
+      Misbehaved(reason) => format!("misbehaved: {:s}", reason),
+      ReceiveError(ref error) => format!("receive error: {:s}", error.as_slice()),
+      Disconnected(reason) => format!("disconnected: {:s}", reason)
+    };
+    ret.push_str(format!("+{:u} {:s}\n", entry.time_ns - start, line.as_slice()).as_slice());
+  }
+  ret
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use network::trace::{ProtocolTrace, Sent, Received, Handshake, Misbehaved, ReceiveError, Disconnected};
+  use network::trace::format_trace;
+
+  fn commands(trace: &ProtocolTrace) -> Vec<String> {
+    trace.entries().move_iter().map(|entry| match entry.event {
+      Sent(command, _) | Received(command, _) => command,
+      _ => String::from_str("?")
+    }).collect()
+  }
+
+  #[test]
+  fn trace_event_limit_test() {
+    let mut trace = ProtocolTrace::new(3, 1000);
+    trace.push(1, Sent(String::from_str("version"), 100));
+    trace.push(2, Received(String::from_str("version"), 102));
+    trace.push(3, Received(String::from_str("verack"), 0));
+    assert_eq!(trace.len(), 3);
+
+    // The oldest events go first
+    trace.push(4, Sent(String::from_str("ping"), 8));
+    trace.push(5, Received(String::from_str("pong"), 8));
+    assert_eq!(trace.len(), 3);
+    assert_eq!(commands(&trace), vec![String::from_str("verack"), String::from_str("ping"),
+                                      String::from_str("pong")]);
+    assert_eq!(trace.entries().get(0).time_ns, 3);
+  }
+
+  #[test]
+  fn trace_byte_limit_test() {
+    // 12 bytes holds "inv", "getdata" but not another "inv" as well
+    let mut trace = ProtocolTrace::new(100, 12);
+    trace.push(1, Received(String::from_str("inv"), 37));
+    trace.push(2, Sent(String::from_str("getdata"), 37));
+    assert_eq!(trace.len(), 2);
+    trace.push(3, Received(String::from_str("inv"), 37));
+    assert_eq!(commands(&trace), vec![String::from_str("getdata"), String::from_str("inv")]);
+
+    // An event bigger than the whole limit empties the trace
+    trace.push(4, ReceiveError(String::from_str("this error is far too long")));
+    assert_eq!(trace.len(), 0);
+    trace.push(5, Received(String::from_str("inv"), 37));
+    assert_eq!(trace.len(), 1);
+  }
+
+  #[test]
+  fn format_trace_test() {
+    let mut trace = ProtocolTrace::new(10, 1000);
+    trace.push(1000, Sent(String::from_str("version"), 102));
+    trace.push(1500, Handshake("version sent"));
+    trace.push(2000, Received(String::from_str("verack"), 0));
+    trace.push(2500, Misbehaved("second verack"));
+    trace.push(9000, ReceiveError(String::from_str("end of file")));
+    trace.push(9001, Disconnected("peer stalled partway through a message"));
+    assert_eq!(format_trace(trace.entries().as_slice()),
+               String::from_str("+0 sent version, 102 bytes\n\
+                                 +500 handshake version sent\n\
+                                 +1000 received verack, 0 bytes\n\
+                                 +1500 misbehaved: second verack\n\
+                                 +8000 receive error: end of file\n\
+                                 +8001 disconnected: peer stalled partway through a message\n"));
+    assert_eq!(format_trace(&[]), String::new());
+  }
+}
+
@@ -0,0 +1,157 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Regtest chains
+//!
+//! Integration tests need a blockchain they can control. This module
+//! provides one on regtest parameters, whose proof-of-work is easy enough
+//! that blocks can be mined on demand.
+//!
+
+use blockdata::block::Block;
+use blockdata::constants::{COIN_VALUE, regtest_genesis_block};
+use blockdata::template::{BlockTemplate, build_candidate_block, coinbase_from_template};
+use network::serialize::Serializable;
+use util::address::Address;
+use util::hash::Sha256dHash;
+
+/// Compact target of every regtest block
+pub static REGTEST_BITS: u32 = 0x207fffff;
+/// Number of blocks between halvings of the regtest subsidy
+pub static REGTEST_HALVING_INTERVAL: u32 = 150;
+
+/// A regtest blockchain which grows as blocks are mined on it
+pub struct RegtestChain {
+  blocks: Vec<Block>
+}
+
+impl RegtestChain {
+  /// Constructs a chain holding only the regtest genesis block
+  pub fn new() -> RegtestChain {
+    RegtestChain { blocks: vec![regtest_genesis_block()] }
+  }
+
+  /// Returns the block subsidy at `height`
+  pub fn subsidy(height: u32) -> u64 {
+    let halvings = height / REGTEST_HALVING_INTERVAL;
+    if halvings >= 64 { 0 } else { (50 * COIN_VALUE) >> halvings as uint }
+  }
+
+  /// Mines a block on the tip whose coinbase pays the subsidy to
+  /// `reward_address`, and returns it
+  pub fn mine_block<'a>(&'a mut self, reward_address: &Address) -> &'a Block {
+    let height = self.height() + 1;
+    let template = BlockTemplate {
+      version: 2,
+      prev_blockhash: self.tip_hash(),
+      transactions: vec![],
+      coinbase_value: RegtestChain::subsidy(height),
+      bits: REGTEST_BITS,
+      // A second after the tip, so times always increase
+      time: self.blocks.last().unwrap().header.time + 1,
+      height: height
+    };
+    let coinbase = coinbase_from_template(&template, &reward_address.to_script_pubkey(), 0);
+    let mut block = build_candidate_block(&template, coinbase, 0);
+    // About half of all hashes meet the regtest target
+    block.header.solve_nonce_with_timestamp(0xFFFFFFFF).unwrap();
+    self.blocks.push(block);
+    self.blocks.last().unwrap()
+  }
+
+  /// Mines `count` blocks in a row, and returns them
+  pub fn mine_blocks<'a>(&'a mut self, count: u32, reward_address: &Address) -> Vec<&'a Block> {
+    for _ in range(0, count) {
+      self.mine_block(reward_address);
+    }
+    let start = self.blocks.len() - count as uint;
+    self.blocks.slice_from(start).iter().collect()
+  }
+
+  /// Returns the hash of the most recent block
+  pub fn tip_hash(&self) -> Sha256dHash {
+    self.blocks.last().unwrap().header.hash()
+  }
+
+  /// Returns the height of the most recent block; the genesis block is
+  /// at height zero
+  pub fn height(&self) -> u32 {
+    (self.blocks.len() - 1) as u32
+  }
+
+  /// Returns every block in the chain, starting with the genesis block
+  pub fn all_blocks<'a>(&'a self) -> &'a [Block] {
+    self.blocks.as_slice()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use blockdata::constants::COIN_VALUE;
+  use network::constants::Bitcoin;
+  use network::serialize::Serializable;
+  use testing::RegtestChain;
+  use util::address::{Address, PubkeyHash};
+  use util::misc::hex_bytes;
+
+  fn reward_address() -> Address {
+    let mut hash = [0u8, ..20];
+    hash.copy_from(hex_bytes("0389035a9225b3839e2bbf32d826a1e222031fd8").unwrap().as_slice());
+    Address { network: Bitcoin, payload: PubkeyHash(hash) }
+  }
+
+  #[test]
+  fn regtest_chain_test() {
+    let mut chain = RegtestChain::new();
+    assert_eq!(chain.height(), 0);
+    assert_eq!(chain.all_blocks().len(), 1);
+    assert_eq!(format!("{:x}", chain.tip_hash()),
+               String::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"));
+
+    let genesis_hash = chain.tip_hash();
+    let first_hash = chain.mine_block(&reward_address()).header.hash();
+    assert_eq!(chain.height(), 1);
+    assert!(chain.tip_hash() == first_hash);
+    assert!(chain.all_blocks()[1].header.prev_blockhash == genesis_hash);
+
+    assert_eq!(chain.mine_blocks(4, &reward_address()).len(), 4);
+    assert_eq!(chain.height(), 5);
+
+    let blocks = chain.all_blocks();
+    for (height, block) in blocks.iter().enumerate().skip(1) {
+      assert!(block.header.prev_blockhash == blocks[height - 1].header.hash());
+      assert!(block.header.time > blocks[height - 1].header.time);
+      let target = block.header.target();
+      assert_eq!(block.validate(&target, height as u32).len(), 0);
+
+      let output = block.txdata.get(0).output.get(0);
+      assert_eq!(output.value, 50 * COIN_VALUE);
+      assert_eq!(output.script_pubkey, reward_address().to_script_pubkey());
+    }
+    // Coinbases start with their height, so no two are the same
+    assert!(blocks[1].txdata.get(0).hash() != blocks[2].txdata.get(0).hash());
+  }
+
+  #[test]
+  fn subsidy_test() {
+    assert_eq!(RegtestChain::subsidy(1), 50 * COIN_VALUE);
+    assert_eq!(RegtestChain::subsidy(149), 50 * COIN_VALUE);
+    assert_eq!(RegtestChain::subsidy(150), 25 * COIN_VALUE);
+    assert_eq!(RegtestChain::subsidy(300), 1250000000);
+    assert_eq!(RegtestChain::subsidy(150 * 64), 0);
+  }
+}
+
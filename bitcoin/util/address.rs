@@ -0,0 +1,253 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Addresses
+//!
+//! Addresses are the human-readable encodings of the output scripts which
+//! wallets pay to: base58check for pay-to-pubkey-hash and pay-to-script-hash
+//! outputs, and bech32 (BIP 173) or bech32m (BIP 350) for witness outputs.
+//!
+
+use std::fmt;
+
+use blockdata::opcodes;
+use blockdata::script::Script;
+use blockdata::script::{P2PKH_TEMPLATE, P2SH_TEMPLATE, P2WPKH_TEMPLATE, P2WSH_TEMPLATE, P2TR_TEMPLATE};
+use blockdata::script::{P2PK_COMPRESSED_TEMPLATE, P2PK_UNCOMPRESSED_TEMPLATE};
+use network::constants::{Network, Bitcoin, BitcoinTestnet};
+use util::hash::hash160;
+use util::misc::{base58check_encode, bech32_encode, bytes_to_u5};
+
+/// What an address pays to
+pub enum Payload {
+  /// The hash160 of a public key
+  PubkeyHash([u8, ..20]),
+  /// The hash160 of a redeem script
+  ScriptHash([u8, ..20]),
+  /// A witness version and program
+  WitnessProgram(u8, Vec<u8>)
+}
+
+impl PartialEq for Payload {
+  fn eq(&self, other: &Payload) -> bool {
+    match (self, other) {
+      (&PubkeyHash(ref a), &PubkeyHash(ref b)) => a.as_slice() == b.as_slice(),
+      (&ScriptHash(ref a), &ScriptHash(ref b)) => a.as_slice() == b.as_slice(),
+      (&WitnessProgram(v1, ref p1), &WitnessProgram(v2, ref p2)) => v1 == v2 && p1 == p2,
+      _ => false
+    }
+  }
+}
+
+impl Clone for Payload {
+  fn clone(&self) -> Payload {
+    match *self {
+      PubkeyHash(hash) => PubkeyHash(hash),
+      ScriptHash(hash) => ScriptHash(hash),
+      WitnessProgram(version, ref program) => WitnessProgram(version, program.clone())
+    }
+  }
+}
+
+/// An address on a particular network
+#[deriving(PartialEq, Clone)]
+pub struct Address {
+  /// The network the address is for
+  pub network: Network,
+  /// What the address pays to
+  pub payload: Payload
+}
+
+impl Address {
+  /// Returns the address which `script` pays to, if any; see `script_to_address`
+  pub fn from_script(script: &Script, network: Network) -> Option<Address> {
+    script_to_address(script, network)
+  }
+
+  /// Returns the output script which pays to this address
+  pub fn to_script_pubkey(&self) -> Script {
+    let mut ret = Script::new();
+    match self.payload {
+      PubkeyHash(ref hash) => {
+        ret.push_opcode(opcodes::DUP);
+        ret.push_opcode(opcodes::HASH160);
+        ret.push_slice(hash.as_slice());
+        ret.push_opcode(opcodes::EQUALVERIFY);
+        ret.push_opcode(opcodes::CHECKSIG);
+      }
+      ScriptHash(ref hash) => {
+        ret.push_opcode(opcodes::HASH160);
+        ret.push_slice(hash.as_slice());
+        ret.push_opcode(opcodes::EQUAL);
+      }
+      WitnessProgram(version, ref program) => {
+        // Versions 1-16 are OP_1 to OP_16
+        ret.push_int(version as int);
+        ret.push_slice(program.as_slice());
+      }
+    }
+    ret
+  }
+}
+
+/// Copies a 20-byte hash out of a script
+fn hash_from_slice(data: &[u8]) -> [u8, ..20] {
+  let mut ret = [0u8, ..20];
+  ret.copy_from(data);
+  ret
+}
+
+/// Returns the address which `script` pays to, if it is one of the standard
+/// templates: pay-to-pubkey (as the address of the key's hash), P2PKH,
+/// P2SH, P2WPKH, P2WSH or P2TR.
+pub fn script_to_address(script: &Script, network: Network) -> Option<Address> {
+  let raw = script.as_slice();
+  let payload = if script.matches_template(&P2PKH_TEMPLATE) {
+    PubkeyHash(hash_from_slice(raw.slice(3, 23)))
+  } else if script.matches_template(&P2SH_TEMPLATE) {
+    ScriptHash(hash_from_slice(raw.slice(2, 22)))
+  } else if script.matches_template(&P2WPKH_TEMPLATE) || script.matches_template(&P2WSH_TEMPLATE) {
+    WitnessProgram(0, Vec::from_slice(raw.slice_from(2)))
+  } else if script.matches_template(&P2TR_TEMPLATE) {
+    WitnessProgram(1, Vec::from_slice(raw.slice_from(2)))
+  } else if script.matches_template(&P2PK_COMPRESSED_TEMPLATE) && (raw[1] == 0x02 || raw[1] == 0x03) {
+    PubkeyHash(hash160(raw.slice(1, 34)))
+  } else if script.matches_template(&P2PK_UNCOMPRESSED_TEMPLATE) && raw[1] == 0x04 {
+    PubkeyHash(hash160(raw.slice(1, 66)))
+  } else {
+    return None;
+  };
+  Some(Address { network: network, payload: payload })
+}
+
+impl fmt::Show for Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let encoded = match self.payload {
+      PubkeyHash(ref hash) | ScriptHash(ref hash) => {
+        let version = match (&self.payload, self.network) {
+          (&PubkeyHash(_), Bitcoin) => 0x00,
+          (&PubkeyHash(_), BitcoinTestnet) => 0x6f,
+          (_, Bitcoin) => 0x05,
+          (_, BitcoinTestnet) => 0xc4
+        };
+        let mut data = vec![version];
+        data.push_all(hash.as_slice());
+        base58check_encode(data.as_slice())
+      }
+      WitnessProgram(version, ref program) => {
+        let hrp = match self.network {
+          Bitcoin => "bc",
+          BitcoinTestnet => "tb"
+        };
+        let mut data = vec![version];
+        data.push_all(bytes_to_u5(program.as_slice()).as_slice());
+        bech32_encode(hrp, data.as_slice(), version > 0)
+      }
+    };
+    write!(f, "{:s}", encoded.as_slice())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use blockdata::script::Script;
+  use network::constants::{Bitcoin, BitcoinTestnet};
+  use util::address::{Address, PubkeyHash, script_to_address};
+  use util::misc::hex_bytes;
+
+  fn script(hex: &str) -> Script {
+    Script::from_vec(hex_bytes(hex).unwrap())
+  }
+
+  fn check(script_hex: &str, mainnet: &str, testnet: &str) {
+    let address = script_to_address(&script(script_hex), Bitcoin).unwrap();
+    assert_eq!(format!("{:}", address), String::from_str(mainnet));
+    let address = script_to_address(&script(script_hex), BitcoinTestnet).unwrap();
+    assert_eq!(format!("{:}", address), String::from_str(testnet));
+  }
+
+  #[test]
+  fn script_to_address_test() {
+    // Pay-to-pubkey, uncompressed (the genesis output) and compressed
+    check("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+          "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", "mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt");
+    check("210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac",
+          "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r");
+    // P2PKH and P2SH
+    check("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac",
+          "1KhAQw5BU3i4G8BDGpw8YqUKRLuW3mQdh", "mfqeTU23zVUxqNbnvqoJxU3oBQwcTvNCRs");
+    check("a9140389035a9225b3839e2bbf32d826a1e222031fd887",
+          "321i5xRWjNN69RpcLNVXZBCQTwdd6WpSNG", "2MsZv9hMYLpsSMDTA1W7QB8BfgHqnvbAqtf");
+    // P2WPKH and P2WSH, from BIP 173
+    check("0014751e76e8199196d454941c45d1b3a323f1433bd6",
+          "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx");
+    check("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+          "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+          "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7");
+    // P2TR, from BIP 350
+    check("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+          "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+          "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq47zagq");
+
+    // Pay-to-pubkey gives the same address as P2PKH of the key's hash
+    let p2pk = script_to_address(&script("210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac"), Bitcoin);
+    let p2pkh = script_to_address(&script("76a914751e76e8199196d454941c45d1b3a323f1433bd688ac"), Bitcoin);
+    assert!(p2pk == p2pkh);
+    match p2pk.unwrap() {
+      Address { payload: PubkeyHash(_), .. } => {}
+      _ => { fail!("pay-to-pubkey should give a pubkey hash address"); }
+    }
+  }
+
+  #[test]
+  fn to_script_pubkey_test() {
+    let scripts = [
+      "76a9140389035a9225b3839e2bbf32d826a1e222031fd888ac",
+      "a9140389035a9225b3839e2bbf32d826a1e222031fd887",
+      "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+      "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+      "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+    ];
+    for hex in scripts.iter() {
+      for &network in [Bitcoin, BitcoinTestnet].iter() {
+        let address = Address::from_script(&script(*hex), network).unwrap();
+        assert_eq!(address.to_script_pubkey().as_slice(), script(*hex).as_slice());
+        assert!(Address::from_script(&address.to_script_pubkey(), network) == Some(address.clone()));
+      }
+    }
+
+    // Pay-to-pubkey outputs come back as P2PKH
+    let address = Address::from_script(&script("210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac"), Bitcoin).unwrap();
+    assert_eq!(address.to_script_pubkey().as_slice(),
+               script("76a914751e76e8199196d454941c45d1b3a323f1433bd688ac").as_slice());
+  }
+
+  #[test]
+  fn nonstandard_script_test() {
+    // Empty, OP_RETURN and bare multisig scripts have no address
+    assert!(script_to_address(&Script::new(), Bitcoin).is_none());
+    assert!(script_to_address(&script("6a0401020304"), Bitcoin).is_none());
+    assert!(script_to_address(&script("512102000000000000000000000000000000000000000000000000000000000000000051ae"), Bitcoin).is_none());
+    // Near misses on the templates
+    assert!(script_to_address(&script("76a9140389035a9225b3839e2bbf32d826a1e222031fd888ad"), Bitcoin).is_none());
+    assert!(script_to_address(&script("a9130389035a9225b3839e2bbf32d826a1e222031f8787"), Bitcoin).is_none());
+    assert!(script_to_address(&script("0015751e76e8199196d454941c45d1b3a323f1433bd6"), Bitcoin).is_none());
+    assert!(script_to_address(&script("52201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"), Bitcoin).is_none());
+    // A "public key" of the wrong form
+    assert!(script_to_address(&script("210579be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac"), Bitcoin).is_none());
+  }
+}
+
@@ -0,0 +1,199 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Bloom Filters
+//!
+//! A rolling bloom filter for remembering recently seen objects, such as
+//! inventory we have already requested, in bounded memory. Like bitcoind's
+//! CRollingBloomFilter it keeps three generations of entries and forgets
+//! the oldest one as each new one fills up.
+//!
+
+use std::cmp::{min, max};
+use std::rand::task_rng;
+use rand::Rng;
+
+/// Rotate a 32-bit word left by 0 < n < 32 bits
+fn rotate_left(word: u32, n: uint) -> u32 {
+  (word << n) | (word >> (32 - n))
+}
+
+/// The 32-bit MurmurHash3 function, which bitcoind uses for bloom filters
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+  static C1: u32 = 0xcc9e2d51;
+  static C2: u32 = 0x1b873593;
+  let mut h1 = seed;
+  for block in data.chunks(4) {
+    let mut k1 = 0u32;
+    for (i, ch) in block.iter().enumerate() {
+      k1 |= (*ch as u32) << (8 * i);
+    }
+    k1 *= C1;
+    k1 = rotate_left(k1, 15);
+    k1 *= C2;
+    h1 ^= k1;
+    // Only full blocks are mixed into the state; the tail is not
+    if block.len() == 4 {
+      h1 = rotate_left(h1, 13);
+      h1 = h1 * 5 + 0xe6546b64;
+    }
+  }
+  h1 ^= data.len() as u32;
+  h1 ^= h1 >> 16;
+  h1 *= 0x85ebca6b;
+  h1 ^= h1 >> 13;
+  h1 *= 0xc2b2ae35;
+  h1 ^= h1 >> 16;
+  h1
+}
+
+/// A bloom filter which remembers at least the most recent `elements`
+/// insertions, and at most half as many again. False positives occur at
+/// roughly the rate given at construction; false negatives among the
+/// recent insertions never do.
+pub struct RollingBloomFilter {
+  entries_per_generation: uint,
+  entries_this_generation: uint,
+  /// Current generation, which is 1, 2 or 3
+  generation: u8,
+  hash_funcs: uint,
+  tweak: u32,
+  /// Pairs of words, which together hold a two-bit generation number for
+  /// each of 64 positions. Zero means the position is unset.
+  data: Vec<u64>
+}
+
+impl RollingBloomFilter {
+  /// Constructs a new filter which remembers at least `elements` entries
+  /// with the given false-positive rate
+  pub fn new(elements: uint, fp_rate: f64) -> RollingBloomFilter {
+    let log_fp_rate = fp_rate.ln();
+    let hash_funcs = max(1, min((log_fp_rate / (0.5f64).ln()).round() as uint, 50));
+    let entries_per_generation = (elements + 1) / 2;
+    let max_elements = entries_per_generation * 3;
+    let filter_bits = (-1.0 * hash_funcs as f64 * max_elements as f64 /
+                       (1.0 - (log_fp_rate / hash_funcs as f64).exp()).ln()).ceil() as uint;
+    RollingBloomFilter {
+      entries_per_generation: entries_per_generation,
+      entries_this_generation: 0,
+      generation: 1,
+      hash_funcs: hash_funcs,
+      tweak: task_rng().gen(),
+      data: Vec::from_elem(((filter_bits + 63) / 64) * 2, 0u64)
+    }
+  }
+
+  /// Returns the bit within a word, and the index of a word in the pair,
+  /// used by the `n`th hash function for `key`
+  fn position(&self, n: uint, key: &[u8]) -> (uint, uint) {
+    let hash = murmur3((n as u32) * 0xFBA4C795 + self.tweak, key);
+    let index = ((hash as u64 * self.data.len() as u64) >> 32) as uint;
+    ((hash & 63) as uint, index & !1)
+  }
+
+  /// Adds an entry to the filter, forgetting the oldest generation of
+  /// entries if the current one is full
+  pub fn insert(&mut self, key: &[u8]) {
+    if self.entries_this_generation == self.entries_per_generation {
+      self.entries_this_generation = 0;
+      self.generation += 1;
+      if self.generation == 4 {
+        self.generation = 1;
+      }
+      // Clear every position which is marked with the new generation
+      let mask1 = if self.generation & 1 == 1 { !0u64 } else { 0 };
+      let mask2 = if self.generation >> 1 == 1 { !0u64 } else { 0 };
+      for i in range(0, self.data.len() / 2) {
+        let p1 = *self.data.get(2 * i);
+        let p2 = *self.data.get(2 * i + 1);
+        let mask = (p1 ^ mask1) | (p2 ^ mask2);
+        *self.data.get_mut(2 * i) = p1 & mask;
+        *self.data.get_mut(2 * i + 1) = p2 & mask;
+      }
+    }
+    self.entries_this_generation += 1;
+
+    let gen1 = (self.generation & 1) as u64;
+    let gen2 = (self.generation >> 1) as u64;
+    for n in range(0, self.hash_funcs) {
+      let (bit, index) = self.position(n, key);
+      {
+        let w1 = self.data.get_mut(index);
+        *w1 = (*w1 & !(1 << bit)) | (gen1 << bit);
+      }
+      let w2 = self.data.get_mut(index + 1);
+      *w2 = (*w2 & !(1 << bit)) | (gen2 << bit);
+    }
+  }
+
+  /// Checks whether an entry may have been inserted recently
+  pub fn contains(&self, key: &[u8]) -> bool {
+    for n in range(0, self.hash_funcs) {
+      let (bit, index) = self.position(n, key);
+      if (*self.data.get(index) | *self.data.get(index + 1)) & (1 << bit) == 0 {
+        return false;
+      }
+    }
+    true
+  }
+
+  /// Forgets all entries
+  pub fn reset(&mut self) {
+    self.tweak = task_rng().gen();
+    self.entries_this_generation = 0;
+    self.generation = 1;
+    for word in self.data.mut_iter() {
+      *word = 0;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use network::serialize::Serializable;
+  use util::bloom::{RollingBloomFilter, murmur3};
+
+  #[test]
+  fn murmur3_test() {
+    assert_eq!(murmur3(0, &[]), 0);
+    assert_eq!(murmur3(1, &[]), 0x514e28b7);
+    assert_eq!(murmur3(0, b"hello"), 0x248bfa47);
+  }
+
+  #[test]
+  fn rolling_bloom_test() {
+    let mut filter = RollingBloomFilter::new(100, 0.001);
+    for i in range(0u32, 1000) {
+      filter.insert(i.serialize().as_slice());
+    }
+    // The most recent insertions are always remembered...
+    for i in range(900u32, 1000) {
+      assert!(filter.contains(i.serialize().as_slice()));
+    }
+    // ...and old ones are forgotten, up to false positives
+    let mut remembered = 0u;
+    for i in range(0u32, 500) {
+      if filter.contains(i.serialize().as_slice()) {
+        remembered += 1;
+      }
+    }
+    assert!(remembered < 10);
+
+    filter.reset();
+    assert!(!filter.contains(999u32.serialize().as_slice()));
+  }
+}
+
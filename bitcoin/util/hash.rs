@@ -91,6 +91,8 @@ impl Serializable for Sha256dHash {
     data.iter().map(|n| *n).collect()
   }
 
+  fn serialized_len(&self) -> uint { 32 }
+
   fn deserialize<I: Iterator<u8>>(iter: I) -> IoResult<Sha256dHash> {
     let Sha256dHash(mut ret) = zero_hash();
     let mut fixediter = iter.enumerate().fixed_take(32);
@@ -121,6 +123,195 @@ impl LowerHex for Sha256dHash {
   }
 }
 
+static KECCAK_ROUND_CONSTANTS: [u64, ..24] = [
+  0x0000000000000001, 0x0000000000008082, 0x800000000000808A, 0x8000000080008000,
+  0x000000000000808B, 0x0000000080000001, 0x8000000080008081, 0x8000000000008009,
+  0x000000000000008A, 0x0000000000000088, 0x0000000080008009, 0x000000008000000A,
+  0x000000008000808B, 0x800000000000008B, 0x8000000000008089, 0x8000000000008003,
+  0x8000000000008002, 0x8000000000000080, 0x000000000000800A, 0x800000008000000A,
+  0x8000000080008081, 0x8000000000008080, 0x0000000080000001, 0x8000000080008008
+];
+static KECCAK_ROTATIONS: [uint, ..24] = [
+  1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44
+];
+static KECCAK_LANES: [uint, ..24] = [
+  10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1
+];
+
+/// Rotate a 64-bit word left by 0 < n < 64 bits
+fn rotate_left(word: u64, n: uint) -> u64 {
+  (word << n) | (word >> (64 - n))
+}
+
+/// The Keccak-f[1600] permutation underlying SHA3
+fn keccak_f(state: &mut [u64, ..25]) {
+  for round in range(0u, 24) {
+    // Theta
+    let mut parity = [0u64, ..5];
+    for x in range(0u, 5) {
+      parity[x] = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+    }
+    for x in range(0u, 5) {
+      let d = parity[(x + 4) % 5] ^ rotate_left(parity[(x + 1) % 5], 1);
+      for y in range(0u, 5) {
+        state[5 * y + x] ^= d;
+      }
+    }
+    // Rho and pi
+    let mut last = state[1];
+    for i in range(0u, 24) {
+      let lane = KECCAK_LANES[i];
+      let tmp = state[lane];
+      state[lane] = rotate_left(last, KECCAK_ROTATIONS[i]);
+      last = tmp;
+    }
+    // Chi
+    for y in range(0u, 5) {
+      let mut row = [0u64, ..5];
+      for x in range(0u, 5) {
+        row[x] = state[5 * y + x];
+      }
+      for x in range(0u, 5) {
+        state[5 * y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+      }
+    }
+    // Iota
+    state[0] ^= KECCAK_ROUND_CONSTANTS[round];
+  }
+}
+
+/// Computes the SHA3-256 hash of some data. Bitcoin itself has no use for
+/// this, but Tor uses it to checksum v3 onion addresses.
+pub fn sha3_256(data: &[u8]) -> [u8, ..32] {
+  static RATE: uint = 136;
+  let mut state = [0u64, ..25];
+  // Pad with the SHA3 domain separator and a final 1 bit
+  let mut padded = Vec::from_slice(data);
+  padded.push(0x06);
+  while padded.len() % RATE != 0 {
+    padded.push(0);
+  }
+  let last = padded.len() - 1;
+  *padded.get_mut(last) |= 0x80;
+  // Absorb
+  for block in padded.as_slice().chunks(RATE) {
+    for (i, ch) in block.iter().enumerate() {
+      state[i / 8] ^= (*ch as u64) << (8 * (i % 8));
+    }
+    keccak_f(&mut state);
+  }
+  // Squeeze (our output is shorter than the rate, so one go is enough)
+  let mut ret = [0u8, ..32];
+  for i in range(0u, 32) {
+    ret[i] = (state[i / 8] >> (8 * (i % 8))) as u8;
+  }
+  ret
+}
+
+static RIPEMD_WORDS_LEFT: [uint, ..80] = [
+  0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+  7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9, 5, 2, 14, 11, 8,
+  3, 10, 14, 4, 9, 15, 8, 1, 2, 7, 0, 6, 13, 11, 5, 12,
+  1, 9, 11, 10, 0, 8, 12, 4, 13, 3, 7, 15, 14, 5, 6, 2,
+  4, 0, 5, 9, 7, 12, 2, 10, 14, 1, 3, 8, 11, 6, 15, 13
+];
+static RIPEMD_WORDS_RIGHT: [uint, ..80] = [
+  5, 14, 7, 0, 9, 2, 11, 4, 13, 6, 15, 8, 1, 10, 3, 12,
+  6, 11, 3, 7, 0, 13, 5, 10, 14, 15, 8, 12, 4, 9, 1, 2,
+  15, 5, 1, 3, 7, 14, 6, 9, 11, 8, 12, 2, 10, 0, 4, 13,
+  8, 6, 4, 1, 3, 11, 15, 0, 5, 12, 2, 13, 9, 7, 10, 14,
+  12, 15, 10, 4, 1, 5, 8, 7, 6, 2, 13, 14, 0, 3, 9, 11
+];
+static RIPEMD_ROTATIONS_LEFT: [uint, ..80] = [
+  11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8,
+  7, 6, 8, 13, 11, 9, 7, 15, 7, 12, 15, 9, 11, 7, 13, 12,
+  11, 13, 6, 7, 14, 9, 13, 15, 14, 8, 13, 6, 5, 12, 7, 5,
+  11, 12, 14, 15, 14, 15, 9, 8, 9, 14, 5, 6, 8, 6, 5, 12,
+  9, 15, 5, 11, 6, 8, 13, 12, 5, 12, 13, 14, 11, 8, 5, 6
+];
+static RIPEMD_ROTATIONS_RIGHT: [uint, ..80] = [
+  8, 9, 9, 11, 13, 15, 15, 5, 7, 7, 8, 11, 14, 14, 12, 6,
+  9, 13, 15, 7, 12, 8, 9, 11, 7, 7, 12, 7, 6, 15, 13, 11,
+  9, 7, 15, 11, 8, 6, 6, 14, 12, 13, 5, 14, 13, 13, 7, 5,
+  15, 5, 8, 11, 14, 14, 6, 14, 6, 9, 12, 9, 12, 5, 15, 8,
+  8, 5, 12, 9, 12, 5, 14, 6, 8, 13, 6, 5, 15, 13, 11, 11
+];
+static RIPEMD_CONSTANTS_LEFT: [u32, ..5] = [0x00000000, 0x5A827999, 0x6ED9EBA1, 0x8F1BBCDC, 0xA953FD4E];
+static RIPEMD_CONSTANTS_RIGHT: [u32, ..5] = [0x50A28BE6, 0x5C4DD124, 0x6D703EF3, 0x7A6D76E9, 0x00000000];
+
+/// Rotate a 32-bit word left by 0 < n < 32 bits
+fn rotate_left_32(word: u32, n: uint) -> u32 {
+  (word << n) | (word >> (32 - n))
+}
+
+/// The nonlinear function used in the given round of RIPEMD-160
+fn ripemd_f(round: uint, x: u32, y: u32, z: u32) -> u32 {
+  match round / 16 {
+    0 => x ^ y ^ z,
+    1 => (x & y) | (!x & z),
+    2 => (x | !y) ^ z,
+    3 => (x & z) | (y & !z),
+    _ => x ^ (y | !z)
+  }
+}
+
+/// Computes the RIPEMD-160 hash of some data
+pub fn ripemd160(data: &[u8]) -> [u8, ..20] {
+  let mut state = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+  // Pad with a 1 bit, then zeroes, then the little-endian length in bits
+  let mut padded = Vec::from_slice(data);
+  padded.push(0x80);
+  while padded.len() % 64 != 56 {
+    padded.push(0);
+  }
+  let n_bits = 8 * data.len() as u64;
+  for i in range(0u, 8) {
+    padded.push((n_bits >> (8 * i)) as u8);
+  }
+
+  for block in padded.as_slice().chunks(64) {
+    let mut words = [0u32, ..16];
+    for (i, ch) in block.iter().enumerate() {
+      words[i / 4] |= (*ch as u32) << (8 * (i % 4));
+    }
+    // The two lines of the compression function run side by side
+    let mut left = state;
+    let mut right = state;
+    for j in range(0u, 80) {
+      let t = rotate_left_32(left[0] + ripemd_f(j, left[1], left[2], left[3]) +
+                             words[RIPEMD_WORDS_LEFT[j]] + RIPEMD_CONSTANTS_LEFT[j / 16],
+                             RIPEMD_ROTATIONS_LEFT[j]) + left[4];
+      left = [left[4], t, left[1], rotate_left_32(left[2], 10), left[3]];
+      let t = rotate_left_32(right[0] + ripemd_f(79 - j, right[1], right[2], right[3]) +
+                             words[RIPEMD_WORDS_RIGHT[j]] + RIPEMD_CONSTANTS_RIGHT[j / 16],
+                             RIPEMD_ROTATIONS_RIGHT[j]) + right[4];
+      right = [right[4], t, right[1], rotate_left_32(right[2], 10), right[3]];
+    }
+    let t = state[1] + left[2] + right[3];
+    state[1] = state[2] + left[3] + right[4];
+    state[2] = state[3] + left[4] + right[0];
+    state[3] = state[4] + left[0] + right[1];
+    state[4] = state[0] + left[1] + right[2];
+    state[0] = t;
+  }
+
+  let mut ret = [0u8, ..20];
+  for i in range(0u, 20) {
+    ret[i] = (state[i / 4] >> (8 * (i % 4))) as u8;
+  }
+  ret
+}
+
+/// Computes RIPEMD160(SHA256(x)), which is how Bitcoin hashes public keys
+/// and scripts for addresses
+pub fn hash160(data: &[u8]) -> [u8, ..20] {
+  let mut sha = [0u8, ..32];
+  let mut sha2 = sha2::Sha256::new();
+  sha2.input(data);
+  sha2.result(sha.as_mut_slice());
+  ripemd160(sha.as_slice())
+}
+
 //TODO: this should be an impl and the function have first parameter self.
 //See https://github.com/rust-lang/rust/issues/15060 for why this isn't so.
 //impl<T: Serializable> Vec<T> {
@@ -155,7 +346,7 @@ mod tests {
   use std::prelude::*;
   use collections::bitv::from_bytes;
 
-  use util::hash::Sha256dHash;
+  use util::hash::{Sha256dHash, sha3_256, ripemd160, hash160};
   use util::misc::hex_bytes;
 
   #[test]
@@ -166,6 +357,43 @@ mod tests {
                hex_bytes("d7bd34bfe44a18d2aa755a344fe3e6b06ed0473772e6dfce16ac71ba0b0a241c").unwrap().as_slice());
   }
 
+  #[test]
+  fn test_sha3_256() {
+    assert_eq!(sha3_256(&[]).as_slice(),
+               hex_bytes("a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a").unwrap().as_slice());
+    assert_eq!(sha3_256(b"abc").as_slice(),
+               hex_bytes("3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532").unwrap().as_slice());
+    // Longer than one block
+    assert_eq!(sha3_256(&[0x61u8, ..200]).as_slice(),
+               hex_bytes("cce34485baf2bf2aca99b94833892a4f52896d3d153f7b840cc4f9fe695f1387").unwrap().as_slice());
+  }
+
+  #[test]
+  fn test_ripemd160() {
+    assert_eq!(ripemd160(&[]).as_slice(),
+               hex_bytes("9c1185a5c5e9fc54612808977ee8f548b2258d31").unwrap().as_slice());
+    assert_eq!(ripemd160(b"abc").as_slice(),
+               hex_bytes("8eb208f7e05d987a9b044a8e98c6b087f15a0bfc").unwrap().as_slice());
+    // Lengths either side of where padding spills into a second block
+    let data: Vec<u8> = range(0u8, 64).collect();
+    assert_eq!(ripemd160(data.slice_to(55)).as_slice(),
+               hex_bytes("3c86963b3ff646a65ae42996e9664c747cc7e5e6").unwrap().as_slice());
+    assert_eq!(ripemd160(data.slice_to(56)).as_slice(),
+               hex_bytes("ebdd79cfd4fd9949ef8089673d2620427f487cfb").unwrap().as_slice());
+    assert_eq!(ripemd160(data.as_slice()).as_slice(),
+               hex_bytes("2581f5e9f957b44b0fa24d31996de47409dd1e0f").unwrap().as_slice());
+    assert_eq!(ripemd160(&[0x61u8, ..200]).as_slice(),
+               hex_bytes("2a5b424394c0fce2665d4e0b077e998d2d62160a").unwrap().as_slice());
+  }
+
+  #[test]
+  fn test_hash160() {
+    // The genesis block's public key
+    let pubkey = hex_bytes("04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f").unwrap();
+    assert_eq!(hash160(pubkey.as_slice()).as_slice(),
+               hex_bytes("62e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap().as_slice());
+  }
+
   #[test]
   fn test_hash_to_bitvset() {
     assert_eq!(Sha256dHash::from_data(&[]).as_bitv(),
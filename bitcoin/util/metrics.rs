@@ -0,0 +1,185 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Metrics
+//!
+//! A registry of named counters and gauges, so that a long-running node
+//! can report on its health. Handles are cheap to clone and may be used
+//! from any task; updating one is a single atomic operation.
+//!
+
+use std::io::{IoResult, IoError, InvalidInput};
+use std::sync::{Arc, Mutex};
+use std::sync::atomics::{AtomicUint, SeqCst};
+
+/// A count which only goes up, such as blocks connected
+#[deriving(Clone)]
+pub struct Counter {
+  value: Arc<AtomicUint>
+}
+
+impl Counter {
+  /// Adds `n` to the counter
+  pub fn inc(&self, n: uint) {
+    self.value.fetch_add(n, SeqCst);
+  }
+
+  /// Returns the current count
+  pub fn get(&self) -> uint {
+    self.value.load(SeqCst)
+  }
+}
+
+/// A level which may go up or down, such as peers connected
+#[deriving(Clone)]
+pub struct Gauge {
+  value: Arc<AtomicUint>
+}
+
+impl Gauge {
+  /// Sets the gauge to `n`
+  pub fn set(&self, n: uint) {
+    self.value.store(n, SeqCst);
+  }
+
+  /// Returns the current level
+  pub fn get(&self) -> uint {
+    self.value.load(SeqCst)
+  }
+}
+
+/// A set of named metrics. Clones share the same metrics.
+#[deriving(Clone)]
+pub struct Registry {
+  metrics: Arc<Mutex<Vec<(String, Arc<AtomicUint>)>>>
+}
+
+impl Registry {
+  /// Constructs an empty registry
+  pub fn new() -> Registry {
+    Registry { metrics: Arc::new(Mutex::new(vec![])) }
+  }
+
+  /// Adds a metric under a new name, which must be made of lowercase
+  /// letters, digits and underscores
+  fn register(&self, name: &str) -> IoResult<Arc<AtomicUint>> {
+    if name.len() == 0 || !name.chars().all(|c| (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') || c == '_') {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "bad metric name",
+        detail: Some(format!("metric name `{:s}`", name))
+      });
+    }
+    let mut metrics = self.metrics.lock();
+    if metrics.iter().any(|&(ref existing, _)| existing.as_slice() == name) {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "duplicate metric name",
+        detail: Some(format!("metric `{:s}` is already registered", name))
+      });
+    }
+    let value = Arc::new(AtomicUint::new(0));
+    metrics.push((String::from_str(name), value.clone()));
+    Ok(value)
+  }
+
+  /// Registers a new counter, starting at zero
+  pub fn register_counter(&self, name: &str) -> IoResult<Counter> {
+    let value = try!(self.register(name));
+    Ok(Counter { value: value })
+  }
+
+  /// Registers a new gauge, starting at zero
+  pub fn register_gauge(&self, name: &str) -> IoResult<Gauge> {
+    let value = try!(self.register(name));
+    Ok(Gauge { value: value })
+  }
+
+  /// Returns the current value of every metric, sorted by name
+  pub fn dump(&self) -> Vec<(String, uint)> {
+    let metrics = self.metrics.lock();
+    let mut ret: Vec<(String, uint)> = metrics.iter().map(|&(ref name, ref value)| (name.clone(), value.load(SeqCst))).collect();
+    ret.sort();
+    ret
+  }
+
+  /// Returns the current value of every metric as a flat JSON object.
+  /// Metric names never need escaping.
+  pub fn dump_json(&self) -> String {
+    let entries: Vec<String> = self.dump().iter().map(|&(ref name, value)| format!("\"{:s}\": {:u}", name.as_slice(), value)).collect();
+    let mut ret = String::from_str("{");
+    ret.push_str(entries.connect(", ").as_slice());
+    ret.push_char('}');
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use util::metrics::Registry;
+
+  #[test]
+  fn registry_test() {
+    let registry = Registry::new();
+    let blocks = registry.register_counter("blocks_connected").unwrap();
+    let peers = registry.register_gauge("peers_connected").unwrap();
+    assert_eq!(registry.dump(), vec![(String::from_str("blocks_connected"), 0),
+                                     (String::from_str("peers_connected"), 0)]);
+
+    blocks.inc(1);
+    blocks.inc(2);
+    peers.set(8);
+    peers.set(5);
+    assert_eq!(blocks.get(), 3);
+    assert_eq!(peers.get(), 5);
+    assert_eq!(registry.dump_json(), String::from_str("{\"blocks_connected\": 3, \"peers_connected\": 5}"));
+
+    // Names must be unique and tidy
+    assert_eq!(registry.register_counter("blocks_connected").err().unwrap().desc, "duplicate metric name");
+    assert_eq!(registry.register_gauge("blocks_connected").err().unwrap().desc, "duplicate metric name");
+    assert_eq!(registry.register_counter("").err().unwrap().desc, "bad metric name");
+    assert_eq!(registry.register_counter("bad\"name").err().unwrap().desc, "bad metric name");
+    assert_eq!(Registry::new().dump_json(), String::from_str("{}"));
+  }
+
+  #[test]
+  fn registry_threads_test() {
+    let registry = Registry::new();
+    let shared = registry.register_counter("bytes_relayed").unwrap();
+
+    let (tx, rx) = channel();
+    for n in range(0u, 2) {
+      let registry = registry.clone();
+      let shared = shared.clone();
+      let tx = tx.clone();
+      spawn(proc() {
+        let own = registry.register_counter(format!("thread_{:u}", n).as_slice()).unwrap();
+        for _ in range(0u, 1000) {
+          own.inc(1);
+          shared.inc(2);
+        }
+        tx.send(());
+      });
+    }
+    rx.recv();
+    rx.recv();
+
+    assert_eq!(registry.dump(), vec![(String::from_str("bytes_relayed"), 4000),
+                                     (String::from_str("thread_0"), 1000),
+                                     (String::from_str("thread_1"), 1000)]);
+  }
+}
+
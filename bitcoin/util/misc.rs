@@ -18,8 +18,13 @@
 
 use std::io::{IoError, IoResult, InvalidInput};
 
+use util::hash::Sha256dHash;
 use util::iter::Pairable;
 
+static BASE32_CHARS: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+static BASE58_CHARS: &'static [u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+static BECH32_CHARS: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
 /// Convert a hexadecimal-encoded string to its corresponding bytes
 pub fn hex_bytes(s: &str) -> IoResult<Vec<u8>> {
   let mut v = vec![];
@@ -54,6 +59,149 @@ pub fn hex_bytes(s: &str) -> IoResult<Vec<u8>> {
   }
 }
 
+/// Convert a byte string to lowercase, unpadded RFC 4648 base32
+pub fn base32_encode(data: &[u8]) -> String {
+  let mut ret = vec![];
+  let mut buffer = 0u32;
+  let mut n_bits = 0u;
+  for ch in data.iter() {
+    buffer = (buffer << 8) | *ch as u32;
+    n_bits += 8;
+    while n_bits >= 5 {
+      n_bits -= 5;
+      ret.push(BASE32_CHARS[((buffer >> n_bits) & 0x1F) as uint]);
+    }
+  }
+  // Pad out the final character with zero bits
+  if n_bits > 0 {
+    ret.push(BASE32_CHARS[((buffer << (5 - n_bits)) & 0x1F) as uint]);
+  }
+  FromIterator::from_iter(ret.move_iter().map(|u| u as char))
+}
+
+/// Convert an unpadded RFC 4648 base32 string, of either case, to its
+/// corresponding bytes
+pub fn base32_decode(s: &str) -> IoResult<Vec<u8>> {
+  let mut ret = vec![];
+  let mut buffer = 0u32;
+  let mut n_bits = 0u;
+  for ch in s.chars() {
+    let value = if ch >= 'a' && ch <= 'z' { ch as u32 - 'a' as u32 }
+                else if ch >= 'A' && ch <= 'Z' { ch as u32 - 'A' as u32 }
+                else if ch >= '2' && ch <= '7' { ch as u32 - '2' as u32 + 26 }
+                else {
+                  return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "invalid base32 character",
+                    detail: Some(format!("expected base32, got {:}", ch))
+                  });
+                };
+    buffer = (buffer << 5) | value;
+    n_bits += 5;
+    if n_bits >= 8 {
+      n_bits -= 8;
+      ret.push((buffer >> n_bits) as u8);
+    }
+  }
+  // Anything left over should be zero padding bits from a partial character
+  if n_bits >= 5 || buffer & ((1 << n_bits) - 1) != 0 {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "base32 string has trailing bits",
+      detail: None
+    });
+  }
+  Ok(ret)
+}
+
+/// Convert a byte string to base58, keeping leading zero bytes as `1`s
+pub fn base58_encode(data: &[u8]) -> String {
+  // Base 58 digits of the data, least significant first
+  let mut digits: Vec<u8> = vec![];
+  for ch in data.iter() {
+    let mut carry = *ch as uint;
+    for digit in digits.mut_iter() {
+      carry += *digit as uint * 256;
+      *digit = (carry % 58) as u8;
+      carry /= 58;
+    }
+    while carry > 0 {
+      digits.push((carry % 58) as u8);
+      carry /= 58;
+    }
+  }
+  let mut ret: Vec<u8> = data.iter().take_while(|n| **n == 0).map(|_| BASE58_CHARS[0]).collect();
+  ret.extend(digits.iter().rev().map(|n| BASE58_CHARS[*n as uint]));
+  FromIterator::from_iter(ret.move_iter().map(|u| u as char))
+}
+
+/// Convert a byte string to base58 with a four-byte double-SHA256
+/// checksum appended, as addresses and private keys are written
+pub fn base58check_encode(data: &[u8]) -> String {
+  let mut with_checksum = Vec::from_slice(data);
+  with_checksum.push_all(Sha256dHash::from_data(data).as_slice().slice_to(4));
+  base58_encode(with_checksum.as_slice())
+}
+
+/// Split a byte string into 5-bit groups, most significant first, zero
+/// padding the last one
+pub fn bytes_to_u5(data: &[u8]) -> Vec<u8> {
+  let mut ret = vec![];
+  let mut buffer = 0u32;
+  let mut n_bits = 0u;
+  for ch in data.iter() {
+    buffer = (buffer << 8) | *ch as u32;
+    n_bits += 8;
+    while n_bits >= 5 {
+      n_bits -= 5;
+      ret.push(((buffer >> n_bits) & 0x1F) as u8);
+    }
+  }
+  if n_bits > 0 {
+    ret.push(((buffer << (5 - n_bits)) & 0x1F) as u8);
+  }
+  ret
+}
+
+/// The BCH checksum underlying bech32
+fn bech32_polymod(values: &[u8]) -> u32 {
+  static GENERATORS: [u32, ..5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+  let mut chk = 1u32;
+  for value in values.iter() {
+    let top = chk >> 25;
+    chk = ((chk & 0x1ffffff) << 5) ^ *value as u32;
+    for i in range(0u, 5) {
+      if (top >> i) & 1 == 1 {
+        chk ^= GENERATORS[i];
+      }
+    }
+  }
+  chk
+}
+
+/// Encode 5-bit groups as a bech32 string with the given human-readable
+/// part. `bech32m` selects the BIP 350 checksum used for witness versions
+/// above zero, rather than the original BIP 173 one.
+pub fn bech32_encode(hrp: &str, data: &[u8], bech32m: bool) -> String {
+  let mut values = vec![];
+  values.extend(hrp.bytes().map(|ch| ch >> 5));
+  values.push(0);
+  values.extend(hrp.bytes().map(|ch| ch & 0x1F));
+  values.push_all(data);
+  values.push_all([0u8, ..6]);
+  let checksum = bech32_polymod(values.as_slice()) ^ if bech32m { 0x2bc830a3 } else { 1 };
+
+  let mut ret = String::from_str(hrp);
+  ret.push_char('1');
+  for value in data.iter() {
+    ret.push_char(BECH32_CHARS[*value as uint] as char);
+  }
+  for i in range(0u, 6) {
+    ret.push_char(BECH32_CHARS[((checksum >> (5 * (5 - i))) & 0x1F) as uint] as char);
+  }
+  ret
+}
+
 /// Prepend the detail of an IoResult's error with some text to get poor man's backtracing
 pub fn prepend_err<T>(s: &str, res: IoResult<T>) -> IoResult<T> {
   res.map_err(|err| {
@@ -77,7 +225,8 @@ pub fn consume_err<T>(s: &str, res: IoResult<T>) {
 mod tests {
   use std::prelude::*;
 
-  use util::misc::hex_bytes;
+  use util::misc::{hex_bytes, base32_encode, base32_decode};
+  use util::misc::{base58_encode, base58check_encode, bytes_to_u5, bech32_encode};
 
   #[test]
   fn test_hex_bytes() {
@@ -86,5 +235,43 @@ mod tests {
     assert!(hex_bytes("aBcDeF").is_ok());
     assert!(hex_bytes("aBcD4eFL").is_err());
   }
-}
 
+  #[test]
+  fn test_base32() {
+    assert_eq!(base32_encode(&[]), String::new());
+    assert_eq!(base32_encode(b"f"), String::from_str("my"));
+    assert_eq!(base32_encode(b"foobar"), String::from_str("mzxw6ytboi"));
+    assert_eq!(base32_decode("mzxw6ytboi").unwrap().as_slice(), b"foobar");
+    assert_eq!(base32_decode("MZXW6YTBOI").unwrap().as_slice(), b"foobar");
+    assert_eq!(base32_decode("my").unwrap().as_slice(), b"f");
+    // Nonzero padding bits
+    assert!(base32_decode("mz").is_err());
+    // Dangling character
+    assert!(base32_decode("mzx").is_err());
+    assert!(base32_decode("mzxw1").is_err());
+  }
+
+  #[test]
+  fn test_base58() {
+    assert_eq!(base58_encode(&[]), String::new());
+    assert_eq!(base58_encode([0u8, 0, 1, 2, 255]), String::from_str("11LiA"));
+    assert_eq!(base58_encode([0u8, ..25]), String::from_char(25, '1'));
+    // The genesis block's address
+    let payload = hex_bytes("0062e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap();
+    assert_eq!(base58check_encode(payload.as_slice()), String::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+  }
+
+  #[test]
+  fn test_bech32() {
+    assert_eq!(bytes_to_u5([0xFFu8]), vec![0x1F, 0x1C]);
+    assert_eq!(bytes_to_u5([0u8, 0xFF, 0, 0, 0]), vec![0, 3, 0x1F, 0x10, 0, 0, 0, 0]);
+    // The P2WPKH example from BIP 173
+    let mut data = vec![0u8];
+    data.push_all(bytes_to_u5(hex_bytes("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap().as_slice()).as_slice());
+    assert_eq!(bech32_encode("bc", data.as_slice(), false),
+               String::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"));
+    // Valid checksums from the BIP 173 and BIP 350 test vectors
+    assert_eq!(bech32_encode("a", &[], false), String::from_str("a12uel5l"));
+    assert_eq!(bech32_encode("a", &[], true), String::from_str("a1lqfn3a"));
+  }
+}
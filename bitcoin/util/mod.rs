@@ -16,9 +16,15 @@
 //!
 //! Functions needed by all parts of the Bitcoin library
 
+pub mod address;
+pub mod bloom;
 pub mod hash;
 pub mod iter;
+pub mod metrics;
 pub mod misc;
 pub mod patricia_tree;
+pub mod qr;
+pub mod selftest;
+pub mod timer;
 pub mod uint256;
 
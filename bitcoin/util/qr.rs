@@ -0,0 +1,651 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # QR Codes
+//!
+//! A small QR code encoder, enough to show an address or payment URI on a
+//! terminal so that it can be scanned rather than copied by hand. Only
+//! byte mode at the lowest error correction level (L) is supported, in
+//! versions 1 to 10, which hold up to 271 bytes.
+//!
+
+use std::cmp;
+use std::io::{IoResult, IoError, InvalidInput};
+use std::num::abs;
+
+/// The largest version supported
+static MAX_VERSION: uint = 10;
+
+/// Blocks at error correction level L for versions 1 to 10: the number of
+/// error correction codewords per block, then the number of blocks and the
+/// data codewords in each block, for each of the two groups of blocks
+static BLOCKS: [(uint, uint, uint, uint, uint), ..10] = [
+  (7, 1, 19, 0, 0),
+  (10, 1, 34, 0, 0),
+  (15, 1, 55, 0, 0),
+  (20, 1, 80, 0, 0),
+  (26, 1, 108, 0, 0),
+  (18, 2, 68, 0, 0),
+  (20, 2, 78, 0, 0),
+  (24, 2, 97, 0, 0),
+  (30, 2, 116, 0, 0),
+  (18, 2, 68, 2, 69)
+];
+
+/// A QR code, as a square of dark and light modules
+pub struct QrCode {
+  version: uint,
+  mask: uint,
+  size: uint,
+  modules: Vec<bool>
+}
+
+/// Number of data codewords a code of the given version holds
+fn data_codewords(version: uint) -> uint {
+  let (_, blocks1, data1, blocks2, data2) = BLOCKS[version - 1];
+  blocks1 * data1 + blocks2 * data2
+}
+
+/// Width of the byte count in the data
+fn count_bits(version: uint) -> uint {
+  if version < 10 { 8 } else { 16 }
+}
+
+/// The most bytes a code of the given version can hold
+fn capacity(version: uint) -> uint {
+  (data_codewords(version) * 8 - 4 - count_bits(version)) / 8
+}
+
+/// Multiplies in GF(256), modulo the polynomial QR codes use
+fn gf_mul(x: u8, y: u8) -> u8 {
+  let mut z = 0u;
+  for i in range(0u, 8).rev() {
+    z = (z << 1) ^ ((z >> 7) * 0x11D);
+    z ^= ((y as uint >> i) & 1) * x as uint;
+  }
+  z as u8
+}
+
+/// Coefficients of the Reed-Solomon generator polynomial of the given
+/// degree, highest first
+fn rs_generator(degree: uint) -> Vec<u8> {
+  let mut generator = vec![1u8];
+  let mut root = 1u8;
+  for _ in range(0, degree) {
+    // Multiply by (x - root)
+    let mut next = Vec::from_elem(generator.len() + 1, 0u8);
+    for (j, &coeff) in generator.iter().enumerate() {
+      *next.get_mut(j) ^= coeff;
+      *next.get_mut(j + 1) ^= gf_mul(coeff, root);
+    }
+    generator = next;
+    root = gf_mul(root, 2);
+  }
+  generator
+}
+
+/// Computes the error correction codewords for a block of data
+fn rs_remainder(data: &[u8], degree: uint) -> Vec<u8> {
+  let generator = rs_generator(degree);
+  let mut remainder = Vec::from_elem(degree, 0u8);
+  for &byte in data.iter() {
+    let factor = byte ^ *remainder.get(0);
+    remainder.remove(0);
+    remainder.push(0);
+    for j in range(0, degree) {
+      *remainder.get_mut(j) ^= gf_mul(*generator.get(j + 1), factor);
+    }
+  }
+  remainder
+}
+
+/// Appends the low `n` bits of `value` to `bits`, highest first
+fn push_bits(bits: &mut Vec<bool>, value: uint, n: uint) {
+  for i in range(0, n).rev() {
+    bits.push((value >> i) & 1 == 1);
+  }
+}
+
+/// Encodes the data in byte mode and adds error correction, giving the
+/// codewords in the order they are placed in the code
+fn codewords(data: &[u8], version: uint) -> Vec<u8> {
+  let n_data = data_codewords(version);
+  let mut bits = vec![];
+  // Byte mode, the byte count, then the bytes
+  push_bits(&mut bits, 4, 4);
+  push_bits(&mut bits, data.len(), count_bits(version));
+  for &byte in data.iter() {
+    push_bits(&mut bits, byte as uint, 8);
+  }
+  // A terminator if there is room, then zeros to a byte boundary
+  let terminator = cmp::min(4, n_data * 8 - bits.len());
+  push_bits(&mut bits, 0, terminator);
+  while bits.len() % 8 != 0 {
+    bits.push(false);
+  }
+  let mut bytes: Vec<u8> = bits.as_slice().chunks(8).map(|byte| {
+      byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8)
+    }).collect();
+  // Then alternating pad bytes to fill the code
+  let mut pad = 0xEC;
+  while bytes.len() < n_data {
+    bytes.push(pad);
+    pad ^= 0xEC ^ 0x11;
+  }
+
+  // Split into blocks, each with its own error correction
+  let (ec_len, blocks1, data1, blocks2, data2) = BLOCKS[version - 1];
+  let mut blocks = vec![];
+  let mut pos = 0;
+  for i in range(0, blocks1 + blocks2) {
+    let len = if i < blocks1 { data1 } else { data2 };
+    blocks.push(bytes.slice(pos, pos + len));
+    pos += len;
+  }
+  let ecs: Vec<Vec<u8>> = blocks.iter().map(|block| rs_remainder(*block, ec_len)).collect();
+
+  // Interleave the blocks: the first codeword of each, then the second,
+  // and so on, then the same for the error correction
+  let mut ret = vec![];
+  for i in range(0, cmp::max(data1, data2)) {
+    for block in blocks.iter() {
+      if i < block.len() {
+        ret.push((*block)[i]);
+      }
+    }
+  }
+  for i in range(0, ec_len) {
+    for ec in ecs.iter() {
+      ret.push(*ec.get(i));
+    }
+  }
+  ret
+}
+
+/// Whether mask pattern `mask` flips the module at column `x`, row `y`
+fn mask_bit(mask: uint, x: uint, y: uint) -> bool {
+  match mask {
+    0 => (y + x) % 2 == 0,
+    1 => y % 2 == 0,
+    2 => x % 3 == 0,
+    3 => (y + x) % 3 == 0,
+    4 => (y / 2 + x / 3) % 2 == 0,
+    5 => (y * x) % 2 + (y * x) % 3 == 0,
+    6 => ((y * x) % 2 + (y * x) % 3) % 2 == 0,
+    _ => ((y + x) % 2 + (y * x) % 3) % 2 == 0
+  }
+}
+
+/// A code being drawn, which remembers which modules are part of the
+/// fixed patterns rather than data
+struct Grid {
+  size: uint,
+  modules: Vec<bool>,
+  function: Vec<bool>
+}
+
+impl Grid {
+  /// An empty grid with the fixed patterns of the given version drawn
+  fn new(version: uint) -> Grid {
+    let size = 17 + 4 * version;
+    let mut grid = Grid {
+      size: size,
+      modules: Vec::from_elem(size * size, false),
+      function: Vec::from_elem(size * size, false)
+    };
+    grid.draw_function_patterns(version);
+    grid
+  }
+
+  fn get(&self, x: uint, y: uint) -> bool {
+    *self.modules.get(y * self.size + x)
+  }
+
+  fn set_function(&mut self, x: uint, y: uint, dark: bool) {
+    *self.modules.get_mut(y * self.size + x) = dark;
+    *self.function.get_mut(y * self.size + x) = true;
+  }
+
+  fn draw_function_patterns(&mut self, version: uint) {
+    let size = self.size;
+    // Timing patterns
+    for i in range(0, size) {
+      self.set_function(6, i, i % 2 == 0);
+      self.set_function(i, 6, i % 2 == 0);
+    }
+
+    // Finder patterns in three corners, with their separators
+    for &(cx, cy) in [(3u, 3u), (size - 4, 3), (3, size - 4)].iter() {
+      for dy in range(-4i, 5) {
+        for dx in range(-4i, 5) {
+          let (x, y) = (cx as int + dx, cy as int + dy);
+          if x >= 0 && x < size as int && y >= 0 && y < size as int {
+            let dist = cmp::max(abs(dx), abs(dy));
+            self.set_function(x as uint, y as uint, dist != 2 && dist != 4);
+          }
+        }
+      }
+    }
+
+    // Alignment patterns, everywhere on the grid of positions except where
+    // they would overlap a finder
+    let positions = alignment_positions(version);
+    let n = positions.len();
+    for i in range(0, n) {
+      for j in range(0, n) {
+        if (i == 0 && j == 0) || (i == 0 && j == n - 1) || (i == n - 1 && j == 0) {
+          continue;
+        }
+        for dy in range(-2i, 3) {
+          for dx in range(-2i, 3) {
+            let x = (*positions.get(i) as int + dx) as uint;
+            let y = (*positions.get(j) as int + dy) as uint;
+            self.set_function(x, y, cmp::max(abs(dx), abs(dy)) != 1);
+          }
+        }
+      }
+    }
+
+    // Reserve the format bits, which are drawn once the mask is chosen
+    self.draw_format(0);
+
+    // Version information, for versions 7 and up
+    if version >= 7 {
+      let mut rem = version;
+      for _ in range(0u, 12) {
+        rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+      }
+      let bits = (version << 12) | rem;
+      for i in range(0u, 18) {
+        let dark = (bits >> i) & 1 == 1;
+        let (a, b) = (size - 11 + i % 3, i / 3);
+        self.set_function(a, b, dark);
+        self.set_function(b, a, dark);
+      }
+    }
+  }
+
+  /// Draws both copies of the format information for level L and the given
+  /// mask, and the dark module beside them
+  fn draw_format(&mut self, mask: uint) {
+    let size = self.size;
+    // Level L is 01
+    let data = (1 << 3) | mask;
+    let mut rem = data;
+    for _ in range(0u, 10) {
+      rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    let bits = ((data << 10) | rem) ^ 0x5412;
+    let bit = |i: uint| (bits >> i) & 1 == 1;
+
+    // Around the top left finder
+    for i in range(0u, 6) {
+      self.set_function(8, i, bit(i));
+    }
+    self.set_function(8, 7, bit(6));
+    self.set_function(8, 8, bit(7));
+    self.set_function(7, 8, bit(8));
+    for i in range(9u, 15) {
+      self.set_function(14 - i, 8, bit(i));
+    }
+    // Split between the other two
+    for i in range(0u, 8) {
+      self.set_function(size - 1 - i, 8, bit(i));
+    }
+    for i in range(8u, 15) {
+      self.set_function(8, size - 15 + i, bit(i));
+    }
+    self.set_function(8, size - 8, true);
+  }
+
+  /// Places the codewords in the modules not taken by fixed patterns,
+  /// going up and down two columns at a time from the bottom right
+  fn draw_codewords(&mut self, codewords: &[u8]) {
+    let size = self.size;
+    let mut i = 0;
+    let mut right = size - 1;
+    loop {
+      // Skip the vertical timing pattern
+      if right == 6 {
+        right = 5;
+      }
+      let upward = (right + 1) & 2 == 0;
+      for vert in range(0, size) {
+        for j in range(0u, 2) {
+          let x = right - j;
+          let y = if upward { size - 1 - vert } else { vert };
+          if !*self.function.get(y * size + x) && i < codewords.len() * 8 {
+            *self.modules.get_mut(y * size + x) = (codewords[i / 8] >> (7 - i % 8)) & 1 == 1;
+            i += 1;
+          }
+        }
+      }
+      if right < 2 {
+        break;
+      }
+      right -= 2;
+    }
+  }
+
+  /// Flips the data modules which the mask selects
+  fn apply_mask(&mut self, mask: uint) {
+    for y in range(0, self.size) {
+      for x in range(0, self.size) {
+        let index = y * self.size + x;
+        if !*self.function.get(index) && mask_bit(mask, x, y) {
+          let flipped = !*self.modules.get(index);
+          *self.modules.get_mut(index) = flipped;
+        }
+      }
+    }
+  }
+
+  /// Scores how hard the code would be to scan, by the four rules of the
+  /// standard; the mask with the lowest score is used
+  fn penalty(&self) -> uint {
+    let size = self.size;
+    let mut score = 0;
+
+    // Every row and every column
+    let mut lines = vec![];
+    for y in range(0, size) {
+      lines.push(range(0, size).map(|x| self.get(x, y)).collect::<Vec<bool>>());
+    }
+    for x in range(0, size) {
+      lines.push(range(0, size).map(|y| self.get(x, y)).collect::<Vec<bool>>());
+    }
+
+    // Runs of five or more modules of one colour
+    for line in lines.iter() {
+      let mut run = 1;
+      for k in range(1, size + 1) {
+        if k < size && *line.get(k) == *line.get(k - 1) {
+          run += 1;
+        } else {
+          if run >= 5 {
+            score += 3 + run - 5;
+          }
+          run = 1;
+        }
+      }
+    }
+
+    // Two by two blocks of one colour
+    for y in range(0, size - 1) {
+      for x in range(0, size - 1) {
+        let dark = self.get(x, y);
+        if self.get(x + 1, y) == dark && self.get(x, y + 1) == dark && self.get(x + 1, y + 1) == dark {
+          score += 3;
+        }
+      }
+    }
+
+    // Patterns which look like a finder, with four light modules to one side
+    let finder = [true, false, true, true, true, false, true, false, false, false, false];
+    for line in lines.iter() {
+      for k in range(0, size - 10) {
+        let window = line.slice(k, k + 11);
+        if range(0u, 11).all(|n| window[n] == finder[n]) ||
+           range(0u, 11).all(|n| window[n] == finder[10 - n]) {
+          score += 40;
+        }
+      }
+    }
+
+    // Imbalance of dark and light, in steps of 5%
+    let total = size * size;
+    let dark = self.modules.iter().filter(|&dark| *dark).count();
+    let imbalance = if dark * 20 > total * 10 { dark * 20 - total * 10 } else { total * 10 - dark * 20 };
+    score += ((imbalance + total - 1) / total - 1) * 10;
+    score
+  }
+}
+
+/// The rows and columns of alignment pattern centres for a version
+fn alignment_positions(version: uint) -> Vec<uint> {
+  if version == 1 {
+    return vec![];
+  }
+  let n = version / 7 + 2;
+  let step = (version * 8 + n * 3 + 5) / (n * 4 - 4) * 2;
+  let size = 17 + 4 * version;
+  let mut ret = vec![6u];
+  for i in range(0, n - 1).rev() {
+    ret.push(size - 7 - i * step);
+  }
+  ret
+}
+
+impl QrCode {
+  /// Encodes the data in the smallest version which holds it, with the
+  /// mask which scores best
+  pub fn encode(data: &[u8]) -> IoResult<QrCode> {
+    let version = match range(1, MAX_VERSION + 1).find(|&v| data.len() <= capacity(v)) {
+      Some(v) => v,
+      None => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "data too long for QR code",
+          detail: Some(format!("{:u} bytes, more than the {:u} a version {:u} code holds",
+                               data.len(), capacity(MAX_VERSION), MAX_VERSION))
+        });
+      }
+    };
+    let codewords = codewords(data, version);
+
+    let mut best: Option<(uint, uint, Grid)> = None;
+    for mask in range(0u, 8) {
+      let mut grid = Grid::new(version);
+      grid.draw_codewords(codewords.as_slice());
+      grid.apply_mask(mask);
+      grid.draw_format(mask);
+      let penalty = grid.penalty();
+      let better = match best {
+        Some((ref best_penalty, _, _)) => penalty < *best_penalty,
+        None => true
+      };
+      if better {
+        best = Some((penalty, mask, grid));
+      }
+    }
+
+    let (_, mask, grid) = best.unwrap();
+    Ok(QrCode {
+      version: version,
+      mask: mask,
+      size: grid.size,
+      modules: grid.modules
+    })
+  }
+
+  /// The version of the code, from 1 to 10
+  pub fn version(&self) -> uint {
+    self.version
+  }
+
+  /// The mask pattern chosen, from 0 to 7
+  pub fn mask(&self) -> uint {
+    self.mask
+  }
+
+  /// The number of modules along each side
+  pub fn size(&self) -> uint {
+    self.size
+  }
+
+  /// Whether the module at column `x`, row `y` is dark
+  pub fn is_dark(&self, x: uint, y: uint) -> bool {
+    *self.modules.get(y * self.size + x)
+  }
+
+  /// Renders the code for a terminal, two characters per module so that it
+  /// comes out roughly square, inside the four-module light border that
+  /// scanners need. Dark modules are `#`, so it scans the right way round
+  /// as dark text on a light background.
+  pub fn to_ascii(&self) -> String {
+    let border = 4;
+    let width = self.size + 2 * border;
+    let mut ret = String::new();
+    for y in range(0, width) {
+      for x in range(0, width) {
+        let dark = x >= border && x < border + self.size && y >= border && y < border + self.size &&
+                   self.is_dark(x - border, y - border);
+        ret.push_str(if dark { "##" } else { "  " });
+      }
+      ret.push_char('\n');
+    }
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use util::qr::{QrCode, rs_remainder, capacity};
+
+  // Rows of a code as `#` for dark and `.` for light
+  fn rows(code: &QrCode) -> Vec<String> {
+    range(0, code.size()).map(|y| {
+      range(0, code.size()).map(|x| if code.is_dark(x, y) { '#' } else { '.' }).collect::<String>()
+    }).collect()
+  }
+
+  fn check_matrix(code: &QrCode, expected: &[&str]) {
+    let actual = rows(code);
+    assert_eq!(actual.len(), expected.len());
+    for (row, exp) in actual.iter().zip(expected.iter()) {
+      assert_eq!(row.as_slice(), *exp);
+    }
+  }
+
+  #[test]
+  fn rs_remainder_test() {
+    // The HELLO WORLD example at level M, version 1
+    let data = [32u8, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+    assert_eq!(rs_remainder(data, 10), vec![196u8, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+  }
+
+  #[test]
+  fn capacity_test() {
+    let capacities: Vec<uint> = range(1u, 11).map(|v| capacity(v)).collect();
+    assert_eq!(capacities, vec![17u, 32, 53, 78, 106, 134, 154, 192, 230, 271]);
+
+    // The smallest version which fits is used
+    assert_eq!(QrCode::encode(&[]).unwrap().version(), 1);
+    assert_eq!(QrCode::encode([0u8, ..17]).unwrap().version(), 1);
+    assert_eq!(QrCode::encode([0u8, ..18]).unwrap().version(), 2);
+    let code = QrCode::encode(Vec::from_elem(271, 0u8).as_slice()).unwrap();
+    assert_eq!(code.version(), 10);
+    assert_eq!(code.size(), 57);
+
+    let err = QrCode::encode(Vec::from_elem(272, 0u8).as_slice()).err().unwrap();
+    assert_eq!(err.desc, "data too long for QR code");
+    assert_eq!(err.detail, Some(String::from_str("272 bytes, more than the 271 a version 10 code holds")));
+  }
+
+  #[test]
+  fn format_and_version_bits_test() {
+    let code = QrCode::encode(b"bitcoin").unwrap();
+    assert_eq!(code.mask(), 3);
+    // Level L with mask 3 is 111100010011101, read from bit 14 down along
+    // row 8 to the left of the timing pattern and then up column 8
+    let row: String = [0u, 1, 2, 3, 4, 5, 7, 8].iter().map(|&x| if code.is_dark(x, 8) { '1' } else { '0' }).collect();
+    let column: String = [7u, 5, 4, 3, 2, 1, 0].iter().map(|&y| if code.is_dark(8, y) { '1' } else { '0' }).collect();
+    assert_eq!(row.append(column.as_slice()), String::from_str("111100010011101"));
+
+    // Version 7 carries its version, 000111110010010100, above the bottom
+    // left finder, lowest bit first
+    let code = QrCode::encode(Vec::from_elem(140, 0u8).as_slice()).unwrap();
+    assert_eq!(code.version(), 7);
+    let size = code.size();
+    let bits: String = range(0u, 18).rev().map(|i| if code.is_dark(i / 3, size - 11 + i % 3) { '1' } else { '0' }).collect();
+    assert_eq!(bits, String::from_str("000111110010010100"));
+  }
+
+  #[test]
+  fn encode_test() {
+    let code = QrCode::encode(b"bitcoin").unwrap();
+    assert_eq!(code.version(), 1);
+    check_matrix(&code, [
+      "#######.#.###.#######",
+      "#.....#...##..#.....#",
+      "#.###.#.##.#..#.###.#",
+      "#.###.#.##..#.#.###.#",
+      "#.###.#.#..#..#.###.#",
+      "#.....#..####.#.....#",
+      "#######.#.#.#.#######",
+      "...........##........",
+      "####..#.######..###.#",
+      "#####....#.#####....#",
+      "..#####.####.......##",
+      "#.#..#.#...#..#..#.##",
+      ".#.#.######.#...##...",
+      "........####..#.#....",
+      "#######..####..##....",
+      "#.....#..##......####",
+      "#.###.#..#..###.#####",
+      "#.###.#.####..#....#.",
+      "#.###.#.###.#.#..#...",
+      "#.....#.#....#.##...#",
+      "#######.###..#.#..#.."]);
+
+    let code = QrCode::encode(b"bitcoin:1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+    assert_eq!(code.version(), 3);
+    assert_eq!(code.mask(), 7);
+    check_matrix(&code, [
+      "#######..#..#.#....##.#######",
+      "#.....#.#..##.##....#.#.....#",
+      "#.###.#.#####..#.##...#.###.#",
+      "#.###.#..###..#####...#.###.#",
+      "#.###.#.#.....##..#...#.###.#",
+      "#.....#.##.#.###..###.#.....#",
+      "#######.#.#.#.#.#.#.#.#######",
+      "........##..#.#..#.##........",
+      "##.#..##..#..##..#....###.##.",
+      "#.###...#.##.##.#...#....#.##",
+      "..#.#.#..##.....#..#.......#.",
+      ".##..........####.##.#..#.##.",
+      "##....#.....##.##...#.##...##",
+      "..#..#.#..###....####....##..",
+      "##.#.##.###.###.###.#.#.#####",
+      "...##.....#.##.###..#..#.....",
+      "#..#..#....#.#.#...###.#.#..#",
+      ".###...##.#...###.##.##..#.##",
+      "#.#..#####...#.......########",
+      "...###..#.##....##....##.#.#.",
+      "#..#..###..####.....#####.#.#",
+      "........######.###..#...#.###",
+      "#######.#.#####.##.##.#.##.#.",
+      "#.....#...####.##...#...#####",
+      "#.###.#..#.#..#.....######...",
+      "#.###.#.#..#.#..####..##..##.",
+      "#.###.#..###.##.##.#.##.#.#.#",
+      "#.....#.####...###..##...#.#.",
+      "#######.###.##.#...##...#..#."]);
+  }
+
+  #[test]
+  fn to_ascii_test() {
+    let code = QrCode::encode(b"bitcoin").unwrap();
+    let ascii = code.to_ascii();
+    let lines: Vec<&str> = ascii.as_slice().lines().collect();
+    // Four modules of border all round, two characters per module
+    assert_eq!(lines.len(), 29);
+    assert!(lines.iter().all(|line| line.len() == 58));
+    assert_eq!(*lines.get(0), String::from_char(58, ' ').as_slice());
+    assert_eq!(lines.get(4).slice(8, 8 + 14), "##############");
+    assert_eq!(lines.get(4).slice(22, 24), "  ");
+  }
+}
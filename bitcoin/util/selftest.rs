@@ -0,0 +1,119 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Self-test
+//!
+//! Known-answer checks of the hashing, encoding and serialization code,
+//! for a program to run before it trusts this library with anything
+//! that matters. A failure means the build is broken, for example
+//! miscompiled or linked against a bad version of a dependency.
+//!
+
+use std::io::{IoResult, IoError, OtherIoError};
+
+use blockdata::constants::{genesis_block, regtest_genesis_block};
+use network::serialize::{Serializable, VarInt, u64_to_varint, varint_to_u64};
+use util::hash::Sha256dHash;
+use util::misc::{base58check_encode, hex_bytes};
+
+/// A named check which returns whether it passed
+type Vector = (&'static str, fn() -> bool);
+
+/// Double-SHA256 of fixed inputs
+fn check_sha256d() -> bool {
+  Sha256dHash::from_data(&[]).as_slice() ==
+    hex_bytes("5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456").unwrap().as_slice() &&
+  Sha256dHash::from_data(b"abc").as_slice() ==
+    hex_bytes("4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358").unwrap().as_slice()
+}
+
+/// Base58check of the all-zero hash, and of the genesis block's address
+fn check_base58check() -> bool {
+  let payload = hex_bytes("0062e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap();
+  base58check_encode([0u8, ..21]) == String::from_str("1111111111111111111114oLvT2") &&
+  base58check_encode(payload.as_slice()) == String::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")
+}
+
+/// The mainnet and regtest genesis block hashes, which cover header
+/// serialization
+fn check_genesis_hash() -> bool {
+  let mainnet: Vec<u8> = genesis_block().header.hash().serialize().iter().rev().map(|n| *n).collect();
+  let regtest: Vec<u8> = regtest_genesis_block().header.hash().serialize().iter().rev().map(|n| *n).collect();
+  mainnet == hex_bytes("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap() &&
+  regtest == hex_bytes("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap()
+}
+
+/// Varints either side of each change in width survive a round trip
+fn check_varint() -> bool {
+  let cases = [(0xFCu64, 1u), (0xFD, 3), (0xFFFF, 3), (0x10000, 5),
+               (0xFFFFFFFF, 5), (0x100000000, 9)];
+  cases.iter().all(|&(n, len)| {
+    let data = u64_to_varint(n).serialize();
+    let decoded: IoResult<VarInt> = Serializable::deserialize(data.iter().map(|n| *n));
+    data.len() == len && match decoded {
+      Ok(v) => varint_to_u64(v) == n,
+      Err(_) => false
+    }
+  })
+}
+
+/// The checks `selftest` runs
+fn vectors() -> Vec<Vector> {
+  vec![("sha256d", check_sha256d as fn() -> bool),
+       ("base58check", check_base58check as fn() -> bool),
+       ("genesis block hashes", check_genesis_hash as fn() -> bool),
+       ("varint boundaries", check_varint as fn() -> bool)]
+}
+
+/// Runs each check in turn, stopping at the first failure
+fn run_vectors(vectors: &[Vector]) -> IoResult<()> {
+  for &(name, check) in vectors.iter() {
+    if !check() {
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "self-test failed",
+        detail: Some(format!("known-answer vector `{:s}` failed", name))
+      });
+    }
+  }
+  Ok(())
+}
+
+/// Runs the known-answer checks, returning an error which names the
+/// first one to fail
+pub fn selftest() -> IoResult<()> {
+  run_vectors(vectors().as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use util::selftest::{selftest, run_vectors, vectors};
+
+  fn bad_vector() -> bool { false }
+
+  #[test]
+  fn selftest_test() {
+    assert!(selftest().is_ok());
+
+    // A failing vector is named, and stops the run
+    let mut bad = vectors();
+    bad.insert(1, ("injected", bad_vector as fn() -> bool));
+    let err = run_vectors(bad.as_slice()).unwrap_err();
+    assert_eq!(err.desc, "self-test failed");
+    assert_eq!(err.detail, Some(String::from_str("known-answer vector `injected` failed")));
+  }
+}
+
@@ -0,0 +1,187 @@
+// Rust Bitcoin Library
+// Written in 2014 by
+//   Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Timers
+//!
+//! A queue of deadlines shared by the network code, so that pings,
+//! handshake timeouts and the like need not each have a sleeping task.
+//! The queue never reads the clock itself: callers pass the current time
+//! in, so tests can drive it with whatever times they like.
+//!
+
+use collections::treemap::{TreeMap, TreeSet};
+
+/// Identifies a scheduled timer, so that it can be cancelled
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct TimerHandle(uint);
+
+/// A scheduled event
+struct Timer<E> {
+  deadline_ns: u64,
+  period_ns: Option<u64>,
+  event: E
+}
+
+/// Events waiting for their deadlines. Timers due at the same time fire
+/// in the order they were scheduled.
+pub struct TimerQueue<E> {
+  /// (deadline, handle) of every timer, soonest first
+  queue: TreeSet<(u64, uint)>,
+  timers: TreeMap<uint, Timer<E>>,
+  next_handle: uint
+}
+
+impl<E: Clone> TimerQueue<E> {
+  /// Constructs an empty queue
+  pub fn new() -> TimerQueue<E> {
+    TimerQueue {
+      queue: TreeSet::new(),
+      timers: TreeMap::new(),
+      next_handle: 0
+    }
+  }
+
+  fn insert(&mut self, deadline_ns: u64, period_ns: Option<u64>, event: E) -> TimerHandle {
+    let handle = self.next_handle;
+    self.next_handle += 1;
+    self.queue.insert((deadline_ns, handle));
+    self.timers.insert(handle, Timer { deadline_ns: deadline_ns, period_ns: period_ns, event: event });
+    TimerHandle(handle)
+  }
+
+  /// Schedules `event` to fire once, `after_ns` nanoseconds after `now_ns`
+  pub fn schedule(&mut self, now_ns: u64, after_ns: u64, event: E) -> TimerHandle {
+    self.insert(now_ns + after_ns, None, event)
+  }
+
+  /// Schedules `event` to fire every `period_ns` nanoseconds, starting
+  /// one period after `now_ns`, until it is cancelled
+  pub fn schedule_periodic(&mut self, now_ns: u64, period_ns: u64, event: E) -> TimerHandle {
+    assert!(period_ns > 0);
+    self.insert(now_ns + period_ns, Some(period_ns), event)
+  }
+
+  /// Cancels a timer. Returns false if it had already fired or been cancelled.
+  pub fn cancel(&mut self, handle: TimerHandle) -> bool {
+    let TimerHandle(handle) = handle;
+    match self.timers.pop(&handle) {
+      Some(timer) => {
+        self.queue.remove(&(timer.deadline_ns, handle));
+        true
+      }
+      None => false
+    }
+  }
+
+  /// Returns the soonest deadline, if any timer is scheduled
+  pub fn next_deadline(&self) -> Option<u64> {
+    self.queue.iter().next().map(|&(deadline, _)| deadline)
+  }
+
+  /// Returns the number of scheduled timers
+  pub fn len(&self) -> uint {
+    self.timers.len()
+  }
+
+  /// Removes every timer due by `now_ns` and returns their events in the
+  /// order their deadlines fell. Periodic timers are put back for their
+  /// next deadline, which counts from the one just passed so that they
+  /// do not drift; one which is several periods behind fires only once,
+  /// and skips the periods it missed.
+  pub fn poll(&mut self, now_ns: u64) -> Vec<E> {
+    let mut ret = vec![];
+    loop {
+      let (deadline, handle) = match self.queue.iter().next() {
+        Some(&(deadline, handle)) if deadline <= now_ns => (deadline, handle),
+        _ => { break; }
+      };
+      self.queue.remove(&(deadline, handle));
+
+      let period = match self.timers.find(&handle) {
+        Some(timer) => {
+          ret.push(timer.event.clone());
+          timer.period_ns
+        }
+        None => None
+      };
+      match period {
+        Some(period) => {
+          // The first deadline after now which is a whole number of
+          // periods on from this one
+          let next = deadline + period * ((now_ns - deadline) / period + 1);
+          self.queue.insert((next, handle));
+          self.timers.find_mut(&handle).unwrap().deadline_ns = next;
+        }
+        None => { self.timers.remove(&handle); }
+      }
+    }
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::prelude::*;
+
+  use util::timer::TimerQueue;
+
+  #[test]
+  fn timer_queue_test() {
+    let mut timers = TimerQueue::new();
+    timers.schedule(0, 300, "handshake");
+    let ping = timers.schedule(0, 100, "ping");
+    timers.schedule(50, 100, "reconnect");
+    timers.schedule(0, 150, "stale tip");
+    assert_eq!(timers.len(), 4);
+    assert_eq!(timers.next_deadline(), Some(100));
+
+    // Cancelling takes the timer out, once
+    assert!(timers.cancel(ping));
+    assert!(!timers.cancel(ping));
+    assert_eq!(timers.next_deadline(), Some(150));
+
+    // Nothing is due before its deadline
+    assert_eq!(timers.poll(149), vec![]);
+    // Timers due together fire in the order they were scheduled
+    assert_eq!(timers.poll(150), vec!["reconnect", "stale tip"]);
+    assert_eq!(timers.poll(150), vec![]);
+    // Fired timers cannot be cancelled
+    let late = timers.schedule(150, 10, "late");
+    assert_eq!(timers.poll(1000), vec!["late", "handshake"]);
+    assert!(!timers.cancel(late));
+    assert_eq!(timers.len(), 0);
+    assert_eq!(timers.next_deadline(), None);
+  }
+
+  #[test]
+  fn periodic_timer_test() {
+    let mut timers = TimerQueue::new();
+    let ping = timers.schedule_periodic(0, 100, "ping");
+    timers.schedule(0, 250, "once");
+
+    assert_eq!(timers.poll(99), vec![]);
+    assert_eq!(timers.poll(100), vec!["ping"]);
+    assert_eq!(timers.next_deadline(), Some(200));
+    // Periods missed while not polling fire only once, keeping the phase
+    assert_eq!(timers.poll(420), vec!["ping", "once"]);
+    assert_eq!(timers.next_deadline(), Some(500));
+    assert_eq!(timers.poll(500), vec!["ping"]);
+    assert_eq!(timers.next_deadline(), Some(600));
+
+    assert!(timers.cancel(ping));
+    assert_eq!(timers.poll(10000), vec![]);
+    assert_eq!(timers.len(), 0);
+  }
+}
+
@@ -12,35 +12,73 @@
  * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
  */
 
-use std::io::{IoError, IoResult, IoUnavailable};
+use std::io::{IoError, IoResult, IoUnavailable, ConnectionAborted};
 use std::comm::Select;
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::blockchain::Blockchain;
 use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::address::PeerAddr;
 use bitcoin::network::serialize::Serializable;
 use bitcoin::network::listener::{Listener, ListenerChannels};
 use bitcoin::network::socket::Socket;
-use bitcoin::network::message_blockdata::{GetDataMessage, GetHeadersMessage};
+use bitcoin::network::message_blockdata::{GetDataMessage, GetHeadersMessage, Inventory};
+use bitcoin::util::bloom::RollingBloomFilter;
 use bitcoin::util::misc::consume_err;
 use bitcoin::util::hash::zero_hash;
 
 use user_data;
 
 pub struct Bitcoind {
-  peer_address: String,
-  peer_port: u16,
+  peer: PeerAddr,
   blockchain: Blockchain,
   channels: Option<ListenerChannels>,
   sock: Option<Socket>,
-  last_best_tip: Option<BlockHeader>
+  last_best_tip: Option<BlockHeader>,
+  inventory: InventoryTracker
+}
+
+/// What we know about the peer's inventory, so that we do not ask it for
+/// the same thing twice
+struct InventoryTracker {
+  /// Inventory we have recently sent `getdata` for
+  requested: RollingBloomFilter,
+  /// Inventory the peer has told us it does not have
+  notfound: RollingBloomFilter
+}
+
+impl InventoryTracker {
+  fn new() -> InventoryTracker {
+    InventoryTracker {
+      requested: RollingBloomFilter::new(50000, 0.000001),
+      notfound: RollingBloomFilter::new(50000, 0.000001)
+    }
+  }
+
+  /// Picks out the inventory in an `inv` which is worth asking for, and
+  /// marks it as requested
+  fn to_request(&mut self, data: Vec<Inventory>) -> Vec<Inventory> {
+    let ret: Vec<Inventory> = data.move_iter().filter(|inv| !self.requested.contains(inv.hash.as_slice()) &&
+                                                            !self.notfound.contains(inv.hash.as_slice())).collect();
+    for inv in ret.iter() {
+      self.requested.insert(inv.hash.as_slice());
+    }
+    ret
+  }
+
+  /// Marks inventory from a `notfound`, so that it is not asked for again
+  /// after the peer has said it does not have it
+  fn mark_notfound(&mut self, data: &[Inventory]) {
+    for inv in data.iter() {
+      self.notfound.insert(inv.hash.as_slice());
+    }
+  }
 }
 
 impl Bitcoind {
-  pub fn new(peer_address: &str, peer_port: u16, blockchain_path: &Path) -> Bitcoind {
+  pub fn new(peer: PeerAddr, blockchain_path: &Path) -> Bitcoind {
     Bitcoind {
-      peer_address: String::from_str(peer_address),
-      peer_port: peer_port,
+      peer: peer,
       // Load blockchain from disk
       blockchain: match Serializable::deserialize_file(blockchain_path) {
         Ok(blockchain) => {
@@ -54,7 +92,8 @@ println!("Read blockchain, best tip {:x}", blockchain.best_tip().hash());
       },
       channels: None,
       sock: None,
-      last_best_tip: None
+      last_best_tip: None,
+      inventory: InventoryTracker::new()
     }
   }
 
@@ -91,12 +130,18 @@ println!("Read blockchain, best tip {:x}", blockchain.best_tip().hash());
     let mut header_h = sel.handle(header_ref);
     let inv_ref = &self.channels.get_ref().inv_rx;
     let mut inv_h = sel.handle(inv_ref);
+    let notfound_ref = &self.channels.get_ref().notfound_rx;
+    let mut notfound_h = sel.handle(notfound_ref);
+    let disconnect_ref = &self.channels.get_ref().disconnect_rx;
+    let mut disconnect_h = sel.handle(disconnect_ref);
     unsafe {
       block_h.add();
       header_h.add();
       inv_h.add();
+      notfound_h.add();
+      disconnect_h.add();
     }
-    // This loop never returns
+    // This loop only returns once the listener has given up on the peer
     loop {
       let id = sel.wait();
       if id == block_h.id() {
@@ -132,10 +177,28 @@ println!("Read blockchain, best tip {:x}", blockchain.best_tip().hash());
         }
       } else if id == inv_h.id() {
         let data = inv_h.recv();
-        let sendmsg = GetDataMessage(data);
-        // Send
-        consume_err("Warning: failed to send getdata in response to inv",
-          self.sock.get_mut_ref().send_message(&sendmsg));
+        // Only ask for things we have not already asked for
+        let new_data = self.inventory.to_request(data);
+        if !new_data.is_empty() {
+          let sendmsg = GetDataMessage(new_data);
+          // Send
+          consume_err("Warning: failed to send getdata in response to inv",
+            self.sock.get_mut_ref().send_message(&sendmsg));
+        }
+      } else if id == notfound_h.id() {
+        // We only have the one peer, so there is nobody else to ask
+        let data = notfound_h.recv();
+        for inv in data.iter() {
+          println!("Peer does not have {:} {:x}", inv.inv_type, inv.hash);
+        }
+        self.inventory.mark_notfound(data.as_slice());
+      } else if id == disconnect_h.id() {
+        let reason = disconnect_h.recv();
+        return Err(IoError {
+          kind: ConnectionAborted,
+          desc: "disconnected from peer",
+          detail: Some(String::from_str(reason))
+        });
       }
     }
   }
@@ -143,28 +206,54 @@ println!("Read blockchain, best tip {:x}", blockchain.best_tip().hash());
 
 impl Listener for Bitcoind {
   fn peer<'a>(&'a self) -> &'a str {
-    self.peer_address.as_slice()
+    self.peer.host.as_slice()
   }
 
   fn port(&self) -> u16 {
-    self.peer_port
+    self.peer.port
   }
 }
 
 #[cfg(test)]
 mod tests {
+  use bitcoin::network::address::PeerAddr;
   use bitcoin::network::listener::Listener;
+  use bitcoin::network::message_blockdata::{Inventory, InvBlock};
+  use bitcoin::util::hash::Sha256dHash;
 
-  use bitcoind::Bitcoind;
+  use bitcoind::{Bitcoind, InventoryTracker};
 
   #[test]
   fn test_bitcoind() {
-    let bitcoind = Bitcoind::new("localhost", 1000);
+    let path = Path::new("/nonexistent/blockchain");
+    let bitcoind = Bitcoind::new(PeerAddr::parse("localhost:1000", 8333).unwrap(), &path);
     assert_eq!(bitcoind.peer(), "localhost");
     assert_eq!(bitcoind.port(), 1000);
 
-    let mut bitcoind = Bitcoind::new("127.0.0.1", 0);
+    let mut bitcoind = Bitcoind::new(PeerAddr::parse("127.0.0.1:0", 8333).unwrap(), &path);
     assert!(bitcoind.listen().is_err());
   }
-}
 
+  fn block_inv(data: &[u8]) -> Inventory {
+    Inventory { inv_type: InvBlock, hash: Sha256dHash::from_data(data) }
+  }
+
+  #[test]
+  fn test_inventory_tracker() {
+    let mut tracker = InventoryTracker::new();
+    let first = tracker.to_request(vec![block_inv(b"a"), block_inv(b"b")]);
+    assert_eq!(first.len(), 2);
+    // Nothing is asked for twice
+    let second = tracker.to_request(vec![block_inv(b"a"), block_inv(b"c")]);
+    assert_eq!(second.len(), 1);
+    assert!(second.get(0).hash == block_inv(b"c").hash);
+
+    // Once the peer says it does not have something, it is not asked for
+    // again, even after the requests have been forgotten
+    tracker.mark_notfound([block_inv(b"a")]);
+    tracker.requested.reset();
+    let third = tracker.to_request(vec![block_inv(b"a"), block_inv(b"b")]);
+    assert_eq!(third.len(), 1);
+    assert!(third.get(0).hash == block_inv(b"b").hash);
+  }
+}
@@ -47,6 +47,9 @@ extern crate bitcoin;
 
 use std::io::timer;
 
+use bitcoin::network::address::PeerAddr;
+use bitcoin::network::constants::DEFAULT_PORT;
+
 use bitcoind::Bitcoind;
 use user_data::blockchain_path;
 
@@ -59,7 +62,8 @@ fn main()
   println!("Starting the Wizards' Wallet");
 
   // Connect to bitcoind
-  let mut bitcoind = Bitcoind::new("127.0.0.1", 8333, &blockchain_path());
+  let peer = PeerAddr::parse("127.0.0.1", DEFAULT_PORT).unwrap();
+  let mut bitcoind = Bitcoind::new(peer, &blockchain_path());
   // Loop until we get a successful connection
   loop {
     match bitcoind.listen() {